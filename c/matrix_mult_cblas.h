@@ -0,0 +1,40 @@
+/*
+ * gemm entry points exported by libmatrix_mult (make -C c lib).
+ * Same prototypes as the reference CBLAS and Fortran BLAS, with a matrix_mult_ prefix
+ * so they can be linked together with any BLAS. Built with
+ * --no-default-features --features cblas-alias, the library also exports them
+ * under the standard names (cblas_sgemm, ...) instead of linking OpenBLAS.
+ */
+#ifndef MATRIX_MULT_CBLAS_H
+#define MATRIX_MULT_CBLAS_H
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+enum CBLAS_ORDER { CblasRowMajor = 101, CblasColMajor = 102 };
+enum CBLAS_TRANSPOSE { CblasNoTrans = 111, CblasTrans = 112, CblasConjTrans = 113 };
+
+void matrix_mult_cblas_sgemm(const enum CBLAS_ORDER Order, const enum CBLAS_TRANSPOSE TransA,
+                 const enum CBLAS_TRANSPOSE TransB, const int M, const int N, const int K,
+                 const float alpha, const float *A, const int lda, const float *B,
+                 const int ldb, const float beta, float *C, const int ldc);
+
+void matrix_mult_cblas_dgemm(const enum CBLAS_ORDER Order, const enum CBLAS_TRANSPOSE TransA,
+                 const enum CBLAS_TRANSPOSE TransB, const int M, const int N, const int K,
+                 const double alpha, const double *A, const int lda, const double *B,
+                 const int ldb, const double beta, double *C, const int ldc);
+
+void matrix_mult_sgemm_(const char *transa, const char *transb, const int *m, const int *n, const int *k,
+            const float *alpha, const float *a, const int *lda, const float *b, const int *ldb,
+            const float *beta, float *c, const int *ldc);
+
+void matrix_mult_dgemm_(const char *transa, const char *transb, const int *m, const int *n, const int *k,
+            const double *alpha, const double *a, const int *lda, const double *b,
+            const int *ldb, const double *beta, double *c, const int *ldc);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
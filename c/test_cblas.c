@@ -0,0 +1,138 @@
+/*
+ * Checks the exported gemm against a naive triple loop:
+ * every order / transpose combination, padded leading dimensions,
+ * alpha and beta (including beta = 0 over a C full of NaN).
+ */
+#include <math.h>
+#include <stdio.h>
+#include <stdlib.h>
+
+#include "matrix_mult_cblas.h"
+
+static int failures = 0;
+
+/* index of element (i, j) of a matrix stored with leading dimension ld */
+static size_t at(int row_major, int i, int j, int ld) {
+    return row_major ? (size_t)i * ld + j : (size_t)j * ld + i;
+}
+
+static double value(unsigned seed, size_t i) {
+    return (double)((seed * 2654435761u + i * 40503u) % 17) - 8.0;
+}
+
+static void reference(int row_major, int ta, int tb, int m, int n, int k, double alpha,
+                      const double *a, int lda, const double *b, int ldb, double beta,
+                      double *c, int ldc) {
+    for (int i = 0; i < m; i++) {
+        for (int j = 0; j < n; j++) {
+            double s = 0.0;
+            for (int p = 0; p < k; p++) {
+                double x = ta ? a[at(row_major, p, i, lda)] : a[at(row_major, i, p, lda)];
+                double y = tb ? b[at(row_major, j, p, ldb)] : b[at(row_major, p, j, ldb)];
+                s += x * y;
+            }
+            double *cij = &c[at(row_major, i, j, ldc)];
+            *cij = alpha * s + (beta == 0.0 ? 0.0 : beta * *cij);
+        }
+    }
+}
+
+static void check(const char *name, int row_major, int ta, int tb, int m, int n, int k,
+                  double alpha, double beta) {
+    int pad = 3;
+    int ar = ta ? k : m, ac = ta ? m : k;
+    int br = tb ? n : k, bc = tb ? k : n;
+    int lda = (row_major ? ac : ar) + pad;
+    int ldb = (row_major ? bc : br) + pad;
+    int ldc = (row_major ? n : m) + pad;
+    size_t asize = (size_t)lda * (row_major ? ar : ac) + 1;
+    size_t bsize = (size_t)ldb * (row_major ? br : bc) + 1;
+    size_t csize = (size_t)ldc * (row_major ? m : n) + 1;
+    double *a = malloc(asize * sizeof(double));
+    double *b = malloc(bsize * sizeof(double));
+    double *c = malloc(csize * sizeof(double));
+    double *expected = malloc(csize * sizeof(double));
+    float *af = malloc(asize * sizeof(float));
+    float *bf = malloc(bsize * sizeof(float));
+    float *cf = malloc(csize * sizeof(float));
+    for (size_t i = 0; i < asize; i++) af[i] = (float)(a[i] = value(1, i));
+    for (size_t i = 0; i < bsize; i++) bf[i] = (float)(b[i] = value(2, i));
+    for (size_t i = 0; i < csize; i++) {
+        c[i] = beta == 0.0 ? NAN : value(3, i);
+        cf[i] = (float)c[i];
+        expected[i] = c[i];
+    }
+    reference(row_major, ta, tb, m, n, k, alpha, a, lda, b, ldb, beta, expected, ldc);
+
+    enum CBLAS_ORDER order = row_major ? CblasRowMajor : CblasColMajor;
+    enum CBLAS_TRANSPOSE ca = ta ? CblasTrans : CblasNoTrans;
+    enum CBLAS_TRANSPOSE cb = tb ? CblasTrans : CblasNoTrans;
+    char fa = ta ? 'T' : 'N', fb = tb ? 'T' : 'N';
+    float alphaf = (float)alpha, betaf = (float)beta;
+    if (name[0] == 'c' && name[6] == 'd') {
+        matrix_mult_cblas_dgemm(order, ca, cb, m, n, k, alpha, a, lda, b, ldb, beta, c, ldc);
+    } else if (name[0] == 'c') {
+        matrix_mult_cblas_sgemm(order, ca, cb, m, n, k, alphaf, af, lda, bf, ldb, betaf, cf, ldc);
+    } else if (name[0] == 'd') {
+        matrix_mult_dgemm_(&fa, &fb, &m, &n, &k, &alpha, a, &lda, b, &ldb, &beta, c, &ldc);
+    } else {
+        matrix_mult_sgemm_(&fa, &fb, &m, &n, &k, &alphaf, af, &lda, bf, &ldb, &betaf, cf, &ldc);
+    }
+    int single = name[0] == 's' || name[6] == 's';
+
+    /* small integers: the products are exact in both precisions */
+    for (int i = 0; i < m; i++) {
+        for (int j = 0; j < n; j++) {
+            size_t idx = at(row_major, i, j, ldc);
+            double got = single ? cf[idx] : c[idx];
+            if (got != expected[idx]) {
+                printf("FAIL %s %s ta=%d tb=%d m=%d n=%d k=%d alpha=%g beta=%g: "
+                       "c[%d][%d] = %g, expected %g\n",
+                       name, row_major ? "row" : "col", ta, tb, m, n, k, alpha, beta, i, j,
+                       got, expected[idx]);
+                failures++;
+                goto done;
+            }
+        }
+    }
+    /* the padding of C is never written */
+    for (int i = 0; i < (row_major ? m : n); i++) {
+        for (int j = row_major ? n : m; j < ldc; j++) {
+            size_t idx = (size_t)i * ldc + j;
+            double got = single ? cf[idx] : c[idx];
+            if (!(got == value(3, idx) || (isnan(got) && beta == 0.0))) {
+                printf("FAIL %s: padding of C overwritten\n", name);
+                failures++;
+                goto done;
+            }
+        }
+    }
+done:
+    free(a); free(b); free(c); free(expected);
+    free(af); free(bf); free(cf);
+}
+
+int main(void) {
+    const char *names[] = {"cblas_sgemm", "cblas_dgemm", "sgemm_", "dgemm_"};
+    const int sizes[][3] = {{1, 1, 1}, {7, 5, 3}, {64, 64, 64}, {131, 67, 97}, {300, 200, 150},
+                            {5, 0, 3}, {4, 6, 0}};
+    const double scalars[][2] = {{1.0, 0.0}, {2.0, 1.0}, {-1.0, 0.5}, {0.0, 3.0}};
+    int checks = 0;
+    for (int f = 0; f < 4; f++) {
+        for (int s = 0; s < (int)(sizeof(sizes) / sizeof(sizes[0])); s++) {
+            for (int o = 0; o < 2; o++) {
+                /* the Fortran interface is column major only */
+                if (names[f][0] != 'c' && o == 0) continue;
+                for (int t = 0; t < 4; t++) {
+                    for (int x = 0; x < 4; x++) {
+                        check(names[f], o == 0, t & 1, t >> 1, sizes[s][0], sizes[s][1],
+                              sizes[s][2], scalars[x][0], scalars[x][1]);
+                        checks++;
+                    }
+                }
+            }
+        }
+    }
+    printf("%d checks, %d failures\n", checks, failures);
+    return failures != 0;
+}
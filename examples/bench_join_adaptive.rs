@@ -13,23 +13,23 @@ use matrix_mult::vectorisation;
 use matrix_mult::matrix_adaptive;
 use rayon_adaptive::prelude::*;
 use rayon_adaptive::Policy;
+use matrix_mult::report::{self, BenchResult};
+use matrix_mult::stats::Summary;
 const ITERS: usize = 5;
 
-fn average(numbers: [f64; ITERS as usize]) -> f64 {
-    numbers.iter().sum::<f64>() / numbers.len() as f64
-}
-
 fn main() -> std::io::Result<()> {
     let input_size: Vec<usize> = (1..41).map(|i| i * 250).collect();
-    do_benchmark("openblas_join_adaptive.data", |a,b,mut c|  linalg::general_mat_mul(1.0, &a, &b, 1.0, &mut c),input_size);
+    do_benchmark("openblas_join_adaptive.data", "blas", |a,b,mut c|  linalg::general_mat_mul(1.0, &a, &b, 1.0, &mut c),input_size)?;
     Ok(())
 }   
 
-fn do_benchmark<F>(filename: &str,resolution : F, input_size: Vec<usize>) -> std::io::Result<()> 
+fn do_benchmark<F>(filename: &str, kernel: &str, resolution : F, input_size: Vec<usize>) -> std::io::Result<()> 
 where
 F: Fn(ArrayView<f32, Ix2>, ArrayView<f32, Ix2>, ArrayViewMut<f32,Ix2>) + Copy + Sync
 {
     let mut file = File::create(filename)?;  
+    let threads = rayon::current_num_threads();
+    let mut results = Vec::new();
     for (_j, size) in enumerate(input_size) {
         let mut time= [0f64; ITERS as usize];
         println!("SIZE : {:?}", size);
@@ -81,7 +81,9 @@ F: Fn(ArrayView<f32, Ix2>, ArrayView<f32, Ix2>, ArrayViewMut<f32,Ix2>) + Copy +
             );
             time[i] = (end_time - start_time) as f64;
         }
-        file.write_all(format!("{}\t{}\n", size, average(time)).as_bytes())?;
+        results.push(BenchResult::new(kernel, "join + adaptive", (size, size, size), threads, &time));
+        file.write_all(format!("{}\t{}\n", size, Summary::new(&time).mean).as_bytes())?;
     }
-    Ok(())
+    let csv = format!("{}.csv", filename.trim_end_matches(".data"));
+    report::write_csv(File::create(csv)?, &results)
 }
\ No newline at end of file
@@ -12,27 +12,27 @@ use matrix_mult::faster_vec;
 use std::ops::AddAssign;
 use matrix_mult::vectorisation;
 use matrix_mult::vectorisation_packed_simd;
+use matrix_mult::report::{self, BenchResult};
+use matrix_mult::stats::Summary;
 const ITERS: usize = 5;
 
-fn average(numbers: [f64; ITERS as usize]) -> f64 {
-    numbers.iter().sum::<f64>() / numbers.len() as f64
-}
-
 fn main() -> std::io::Result<()> {
     let input_size: Vec<usize> = (1..41).map(|i| i * 250).collect();
-    do_benchmark_seq("openblas_native_8threads_verif", |a,b,mut c|  linalg::general_mat_mul(1.0, &a, &b, 1.0, &mut c),input_size.clone());
-    //do_benchmark_seq("matrix_mult_seq_faster_native", |a,b,mut c| faster_vec::mult_faster_from_ndarray(a,b,&mut c),input_size.clone());
-    //benchmark_seq_blocks("matrix_mult_seq_faster_600_native",|a,b,mut c| faster_vec::mult_faster_from_ndarray(a,b,&mut c),600,input_size.clone());
-    //do_benchmark_seq("matrix_mult_seq_simd_native", |a,b,mut c| vectorisation_packed_simd::mult_faster_from_ndarray(a,b,&mut c),input_size.clone());
-    //benchmark_seq_blocks("matrix_mult_seq_simd_600_native", |a,b,mut c| vectorisation_packed_simd::mult_faster_from_ndarray(a,b,&mut c),600,input_size.clone());
+    do_benchmark_seq("openblas_native_8threads_verif", "blas", |a,b,mut c|  linalg::general_mat_mul(1.0, &a, &b, 1.0, &mut c),input_size.clone());
+    //do_benchmark_seq("matrix_mult_seq_faster_native", "faster", |a,b,mut c| faster_vec::mult_faster_from_ndarray(a,b,&mut c),input_size.clone());
+    //benchmark_seq_blocks("matrix_mult_seq_faster_600_native", "faster", |a,b,mut c| faster_vec::mult_faster_from_ndarray(a,b,&mut c),600,input_size.clone());
+    //do_benchmark_seq("matrix_mult_seq_simd_native", "simd", |a,b,mut c| vectorisation_packed_simd::mult_faster_from_ndarray(a,b,&mut c),input_size.clone());
+    //benchmark_seq_blocks("matrix_mult_seq_simd_600_native", "simd", |a,b,mut c| vectorisation_packed_simd::mult_faster_from_ndarray(a,b,&mut c),600,input_size.clone());
     Ok(())
 }   
 
-fn benchmark_seq_blocks<F>(filename: &str,resolution : F,blocksize:usize, input_size: Vec<usize>) -> std::io::Result<()> 
+fn benchmark_seq_blocks<F>(filename: &str, kernel: &str, resolution : F,blocksize:usize, input_size: Vec<usize>) -> std::io::Result<()> 
 where
 F: Fn(ArrayView<f32, Ix2>, ArrayView<f32, Ix2>, ArrayViewMut<f32,Ix2>) + Copy
 {
     let mut file = File::create(filename)?;
+    let mut results = Vec::new();
+    let policy = format!("blocks {}", blocksize);
 
     for (_j, size) in enumerate(input_size) {
         println!("SIZE : {:?}", size);
@@ -62,16 +62,18 @@ F: Fn(ArrayView<f32, Ix2>, ArrayView<f32, Ix2>, ArrayViewMut<f32,Ix2>) + Copy
             );
             timeblas[i] = (end_time - start_time) as f64;
         }
-        file.write_all(format!("{}\t{}\n", size, average(timeblas),).as_bytes())?;
+        results.push(BenchResult::new(kernel, &policy, (size, size, size), 1, &timeblas));
+        file.write_all(format!("{}\t{}\n", size, Summary::new(&timeblas).mean).as_bytes())?;
     }
-    Ok(())
+    report::write_csv(File::create(format!("{}.csv", filename))?, &results)
 }
 
-fn do_benchmark_seq<F>(filename: &str,resolution : F, input_size: Vec<usize>) -> std::io::Result<()> 
+fn do_benchmark_seq<F>(filename: &str, kernel: &str, resolution : F, input_size: Vec<usize>) -> std::io::Result<()> 
 where
 F: Fn(ArrayView<f32, Ix2>, ArrayView<f32, Ix2>, ArrayViewMut<f32,Ix2>) + Copy
 {
         let mut file = File::create(filename)?;
+        let mut results = Vec::new();
       
         for (_j, size) in enumerate(input_size) {
         println!("SIZE : {:?}", size);
@@ -101,7 +103,8 @@ F: Fn(ArrayView<f32, Ix2>, ArrayView<f32, Ix2>, ArrayViewMut<f32,Ix2>) + Copy
             );
             time[i] = (end_time - start_time) as f64;
         }
-        file.write_all(format!("{}\t{}\n", size, average(time)).as_bytes())?;
+        results.push(BenchResult::new(kernel, "sequential", (size, size, size), 1, &time));
+        file.write_all(format!("{}\t{}\n", size, Summary::new(&time).mean).as_bytes())?;
     }
-    Ok(())
+    report::write_csv(File::create(format!("{}.csv", filename))?, &results)
 }
\ No newline at end of file
@@ -0,0 +1,47 @@
+use matrix_mult::block_grid::{self, BLOCK_ORDERS};
+use matrix_mult::naive_sequential;
+use matrix_mult::vectorisation_packed_simd;
+use ndarray::Array;
+use std::time::Instant;
+
+// Blocks loaded by each traversal order of mult_blocks_ordered according to the LRU
+// model, then the measured time of each order on a large matrix.
+fn main() {
+    let size = 2048;
+    let block = 64;
+    let grid = size / block;
+    println!("{}x{} matrices, {}x{} blocks", size, size, block, block);
+    print!("cache (blocks)");
+    for order in BLOCK_ORDERS.iter() {
+        print!("\t{:?}", order);
+    }
+    println!();
+    for &capacity in &[3 * grid / 2, 4 * grid, 8 * grid, 3 * grid * grid] {
+        print!("{}", capacity);
+        for &order in BLOCK_ORDERS.iter() {
+            let products = block_grid::block_products(grid, grid, grid, order);
+            print!("\t{}", block_grid::lru_misses(&products, capacity));
+        }
+        println!();
+    }
+    let a = Array::from_shape_fn((size, size), |(i, j)| ((i * 7 + j) % 5) as f32);
+    let b = Array::from_shape_fn((size, size), |(i, j)| ((i + 3 * j) % 4) as f32);
+    print!("time (s)");
+    for &order in BLOCK_ORDERS.iter() {
+        let mut c = Array::zeros((size, size));
+        let start = Instant::now();
+        let (ablocks, bblocks, cblocks) = naive_sequential::cut_in_blocks_with(
+            a.view(),
+            b.view(),
+            c.view_mut(),
+            block,
+            block,
+            block,
+        );
+        naive_sequential::mult_blocks_ordered(ablocks, bblocks, cblocks, order, |a, b, mut c| {
+            vectorisation_packed_simd::mult_faster_from_ndarray(a, b, &mut c)
+        });
+        print!("\t{:.3}", start.elapsed().as_secs_f64());
+    }
+    println!();
+}
@@ -0,0 +1,15 @@
+use matrix_mult::summation;
+
+// Relative error of a f32 scalar product against a f64 reference
+// for each summation mode, k from 2^10 to 2^24.
+fn main() {
+    println!("k\tnaive\tkahan\tpairwise");
+    for power in (10..25).step_by(2) {
+        let k = 1 << power;
+        let errors = summation::dot_error(k, power as u64);
+        println!(
+            "{}\t{:e}\t{:e}\t{:e}",
+            k, errors[0].1, errors[1].1, errors[2].1
+        );
+    }
+}
@@ -0,0 +1,391 @@
+//! Matrices cut in grids of blocks, for the blocked multiplications of naive_sequential.
+//! The grids of A, B and C are built from the same row, column and k boundaries,
+//! so block (i, k) of A, (k, j) of B and (i, j) of C always have matching shapes.
+
+use ndarray::{ArrayView, ArrayViewMut, Axis, Ix2};
+use std::collections::{BTreeMap, HashMap};
+use std::ops::{Index, IndexMut};
+
+///
+/// Cut `len` into blocks of at most `block` (at least 1), in power of two friendly places:
+/// a range is halved at half of its next power of two until it fits.
+/// Returns the boundaries: 0, the ends of the blocks, len.
+///
+pub fn boundaries(len: usize, block: usize) -> Vec<usize> {
+    fn rec(start: usize, len: usize, block: usize, bounds: &mut Vec<usize>) {
+        if len <= block {
+            bounds.push(start + len);
+        } else {
+            let half = len.next_power_of_two() / 2;
+            rec(start, half, block, bounds);
+            rec(start + half, len - half, block, bounds);
+        }
+    }
+    let mut bounds = vec![0];
+    if len > 0 {
+        rec(0, len, block.max(1), &mut bounds);
+    }
+    bounds
+}
+
+/// Order in which the positions of a grid are visited.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BlockOrder {
+    RowMajor,
+    ColumnMajor,
+    /// Row-major, every other row from right to left: consecutive blocks are neighbours.
+    Serpentine,
+    /// Z-order: the quadrants recursively, bits of i and j interleaved.
+    Morton,
+    /// Hilbert curve: the quadrants recursively, consecutive blocks are neighbours.
+    Hilbert,
+}
+
+pub const BLOCK_ORDERS: [BlockOrder; 5] = [
+    BlockOrder::RowMajor,
+    BlockOrder::ColumnMajor,
+    BlockOrder::Serpentine,
+    BlockOrder::Morton,
+    BlockOrder::Hilbert,
+];
+
+/// Position of the d-th point of the Morton curve.
+fn morton(d: usize) -> (usize, usize) {
+    let (mut i, mut j) = (0, 0);
+    let mut bit = 0;
+    while d >> (2 * bit) != 0 {
+        j |= ((d >> (2 * bit)) & 1) << bit;
+        i |= ((d >> (2 * bit + 1)) & 1) << bit;
+        bit += 1;
+    }
+    (i, j)
+}
+
+/// Position of the d-th point of the Hilbert curve filling a side x side square.
+fn hilbert(side: usize, d: usize) -> (usize, usize) {
+    let (mut x, mut y) = (0, 0);
+    let mut t = d;
+    let mut s = 1;
+    while s < side {
+        let rx = 1 & (t / 2);
+        let ry = 1 & (t ^ rx);
+        if ry == 0 {
+            if rx == 1 {
+                x = s - 1 - x;
+                y = s - 1 - y;
+            }
+            std::mem::swap(&mut x, &mut y);
+        }
+        x += s * rx;
+        y += s * ry;
+        t /= 4;
+        s *= 2;
+    }
+    (y, x)
+}
+
+/// The (row, col) positions of a rows x cols grid in the given order.
+pub fn positions(rows: usize, cols: usize, order: BlockOrder) -> Vec<(usize, usize)> {
+    match order {
+        BlockOrder::RowMajor => (0..rows)
+            .flat_map(|i| (0..cols).map(move |j| (i, j)))
+            .collect(),
+        BlockOrder::ColumnMajor => (0..cols)
+            .flat_map(|j| (0..rows).map(move |i| (i, j)))
+            .collect(),
+        BlockOrder::Serpentine => (0..rows)
+            .flat_map(|i| {
+                (0..cols).map(move |j| if i % 2 == 1 { (i, cols - 1 - j) } else { (i, j) })
+            })
+            .collect(),
+        // squares of the smaller side, one after the other along the longer side, each one
+        // filled by the curve over its enclosing power of two square without the points
+        // outside: linear in the number of blocks, even for skinny grids
+        BlockOrder::Morton | BlockOrder::Hilbert => {
+            let tile = rows.min(cols).max(1);
+            let side = tile.next_power_of_two();
+            let tall = rows > cols;
+            let length = if tall { rows } else { cols };
+            (0..length)
+                .step_by(tile)
+                .flat_map(move |start| {
+                    (0..side * side)
+                        .map(move |d| {
+                            if order == BlockOrder::Morton {
+                                morton(d)
+                            } else {
+                                hilbert(side, d)
+                            }
+                        })
+                        .filter(move |&(u, v)| u < tile && v < tile)
+                        // the Hilbert curve ends on the side of the next square
+                        .map(move |(u, v)| if tall { (start + v, u) } else { (u, start + v) })
+                })
+                .filter(|&(i, j)| i < rows && j < cols)
+                .collect()
+        }
+    }
+}
+
+///
+/// The (i, j, k) block products of a blocked multiplication with a rows x cols grid of
+/// output blocks and depth blocks along k: the output blocks in the given order, for each
+/// one all the k, back and forth so consecutive output blocks share the blocks of a k.
+///
+pub fn block_products(
+    rows: usize,
+    cols: usize,
+    depth: usize,
+    order: BlockOrder,
+) -> Vec<(usize, usize, usize)> {
+    positions(rows, cols, order)
+        .into_iter()
+        .enumerate()
+        .flat_map(|(step, (i, j))| {
+            (0..depth).map(move |l| {
+                let k = if step % 2 == 1 { depth - 1 - l } else { l };
+                (i, j, k)
+            })
+        })
+        .collect()
+}
+
+///
+/// Cache-miss model: number of blocks loaded by an LRU cache holding `capacity` blocks
+/// when each product (i, j, k) reads block (i, k) of A, (k, j) of B and (i, j) of C.
+///
+pub fn lru_misses(products: &[(usize, usize, usize)], capacity: usize) -> usize {
+    let mut last_use: HashMap<(u8, usize, usize), usize> = HashMap::new();
+    let mut by_age: BTreeMap<usize, (u8, usize, usize)> = BTreeMap::new();
+    let mut misses = 0;
+    let mut time = 0;
+    for &(i, j, k) in products {
+        for &block in &[(0, i, k), (1, k, j), (2, i, j)] {
+            match last_use.insert(block, time) {
+                Some(previous) => {
+                    by_age.remove(&previous);
+                }
+                None => {
+                    misses += 1;
+                    if last_use.len() > capacity {
+                        let oldest = *by_age.keys().next().unwrap();
+                        let evicted = by_age.remove(&oldest).unwrap();
+                        last_use.remove(&evicted);
+                    }
+                }
+            }
+            by_age.insert(time, block);
+            time += 1;
+        }
+    }
+    misses
+}
+
+///
+/// The blocks of a matrix, indexed by (block row, block column).
+/// Row r of blocks covers the matrix rows row_bounds()[r]..row_bounds()[r + 1].
+///
+#[derive(Debug)]
+pub struct BlockGrid<V> {
+    blocks: Vec<V>,
+    row_bounds: Vec<usize>,
+    col_bounds: Vec<usize>,
+}
+
+impl<V> BlockGrid<V> {
+    /// Cut a view along the given boundaries with `split_at`.
+    fn cut<F: Fn(V, Axis, usize) -> (V, V)>(
+        view: V,
+        row_bounds: Vec<usize>,
+        col_bounds: Vec<usize>,
+        split_at: F,
+    ) -> Self {
+        let mut blocks = Vec::with_capacity((row_bounds.len() - 1) * (col_bounds.len() - 1));
+        let mut rest = view;
+        for rows in row_bounds.windows(2) {
+            let (mut strip, remaining) = split_at(rest, Axis(0), rows[1] - rows[0]);
+            rest = remaining;
+            for cols in col_bounds.windows(2) {
+                let (block, remaining) = split_at(strip, Axis(1), cols[1] - cols[0]);
+                blocks.push(block);
+                strip = remaining;
+            }
+        }
+        BlockGrid {
+            blocks,
+            row_bounds,
+            col_bounds,
+        }
+    }
+
+    /// Number of block rows and block columns.
+    pub fn shape(&self) -> (usize, usize) {
+        (self.row_bounds.len() - 1, self.col_bounds.len() - 1)
+    }
+
+    /// Number of matrix rows and columns of a block.
+    pub fn block_shape(&self, (i, j): (usize, usize)) -> (usize, usize) {
+        (
+            self.row_bounds[i + 1] - self.row_bounds[i],
+            self.col_bounds[j + 1] - self.col_bounds[j],
+        )
+    }
+
+    pub fn row_bounds(&self) -> &[usize] {
+        &self.row_bounds
+    }
+
+    pub fn col_bounds(&self) -> &[usize] {
+        &self.col_bounds
+    }
+
+    pub fn get(&self, (i, j): (usize, usize)) -> Option<&V> {
+        let (rows, cols) = self.shape();
+        if i < rows && j < cols {
+            self.blocks.get(i * cols + j)
+        } else {
+            None
+        }
+    }
+
+    pub fn get_mut(&mut self, (i, j): (usize, usize)) -> Option<&mut V> {
+        let (rows, cols) = self.shape();
+        if i < rows && j < cols {
+            self.blocks.get_mut(i * cols + j)
+        } else {
+            None
+        }
+    }
+
+    /// The blocks with their position, in the given order.
+    pub fn iter(&self, order: BlockOrder) -> impl Iterator<Item = ((usize, usize), &V)> {
+        let (rows, cols) = self.shape();
+        positions(rows, cols, order)
+            .into_iter()
+            .map(move |position| (position, &self[position]))
+    }
+
+    /// The blocks in row-major order.
+    pub fn into_blocks(self) -> Vec<V> {
+        self.blocks
+    }
+}
+
+impl<'a, A> BlockGrid<ArrayView<'a, A, Ix2>> {
+    pub fn from_view(
+        view: ArrayView<'a, A, Ix2>,
+        row_bounds: Vec<usize>,
+        col_bounds: Vec<usize>,
+    ) -> Self {
+        assert_eq!(row_bounds.last(), Some(&view.rows()));
+        assert_eq!(col_bounds.last(), Some(&view.cols()));
+        BlockGrid::cut(view, row_bounds, col_bounds, |v, axis, index| {
+            v.split_at(axis, index)
+        })
+    }
+}
+
+impl<'a, A> BlockGrid<ArrayViewMut<'a, A, Ix2>> {
+    pub fn from_view_mut(
+        view: ArrayViewMut<'a, A, Ix2>,
+        row_bounds: Vec<usize>,
+        col_bounds: Vec<usize>,
+    ) -> Self {
+        assert_eq!(row_bounds.last(), Some(&view.rows()));
+        assert_eq!(col_bounds.last(), Some(&view.cols()));
+        BlockGrid::cut(view, row_bounds, col_bounds, |v, axis, index| {
+            v.split_at(axis, index)
+        })
+    }
+}
+
+impl<V> Index<(usize, usize)> for BlockGrid<V> {
+    type Output = V;
+    fn index(&self, position: (usize, usize)) -> &V {
+        self.get(position).expect("block out of the grid")
+    }
+}
+
+impl<V> IndexMut<(usize, usize)> for BlockGrid<V> {
+    fn index_mut(&mut self, position: (usize, usize)) -> &mut V {
+        self.get_mut(position).expect("block out of the grid")
+    }
+}
+
+#[test]
+fn test_boundaries() {
+    assert_eq!(boundaries(1000, 300), vec![0, 256, 512, 768, 1000]);
+    assert_eq!(boundaries(5, 300), vec![0, 5]);
+    assert_eq!(boundaries(0, 300), vec![0]);
+    assert_eq!(boundaries(3, 0), vec![0, 1, 2, 3]);
+}
+
+#[test]
+fn test_block_grid() {
+    use ndarray::Array;
+    let mut m = Array::from_shape_fn((7, 5), |(i, j)| i * 10 + j);
+    let grid = BlockGrid::from_view(m.view(), vec![0, 4, 7], vec![0, 2, 3, 5]);
+    assert_eq!(grid.shape(), (2, 3));
+    assert_eq!(grid.block_shape((1, 2)), (3, 2));
+    assert_eq!(grid[(1, 2)][(0, 0)], 43);
+    assert_eq!(grid[(0, 1)].dim(), (4, 1));
+    assert!(grid.get((2, 0)).is_none());
+    let order: Vec<_> = grid.iter(BlockOrder::Serpentine).map(|(p, _)| p).collect();
+    assert_eq!(order, vec![(0, 0), (0, 1), (0, 2), (1, 2), (1, 1), (1, 0)]);
+    let order: Vec<_> = grid.iter(BlockOrder::ColumnMajor).map(|(p, _)| p).collect();
+    assert_eq!(order[..3], [(0, 0), (1, 0), (0, 1)]);
+    let mut grid = BlockGrid::from_view_mut(m.view_mut(), vec![0, 4, 7], vec![0, 2, 3, 5]);
+    grid[(1, 0)].fill(0);
+    assert_eq!(grid.into_blocks().len(), 6);
+    assert_eq!(m[(4, 1)], 0);
+    assert_eq!(m[(3, 1)], 31);
+}
+
+#[test]
+fn test_orders() {
+    for &order in BLOCK_ORDERS.iter() {
+        for &(rows, cols) in &[(4, 4), (3, 5), (8, 1), (0, 3), (6, 6)] {
+            let mut seen = positions(rows, cols, order);
+            assert_eq!(seen.len(), rows * cols, "{:?}", order);
+            seen.sort();
+            seen.dedup();
+            assert_eq!(seen.len(), rows * cols, "{:?}", order);
+        }
+    }
+    assert_eq!(
+        positions(2, 2, BlockOrder::Morton),
+        vec![(0, 0), (0, 1), (1, 0), (1, 1)]
+    );
+    // Hilbert: each block next to the previous one
+    let curve = positions(8, 8, BlockOrder::Hilbert);
+    let distance = |(i, j): (usize, usize), (k, l): (usize, usize)| {
+        (i as isize - k as isize).abs() + (j as isize - l as isize).abs()
+    };
+    assert!(curve.windows(2).all(|w| distance(w[0], w[1]) == 1));
+    // skinny grids: one square after the other, still continuous for power of two sides
+    for &(rows, cols) in &[(4, 12), (12, 4)] {
+        let curve = positions(rows, cols, BlockOrder::Hilbert);
+        assert_eq!(curve.len(), rows * cols);
+        assert!(curve.windows(2).all(|w| distance(w[0], w[1]) == 1));
+    }
+    let line = positions(1, 30000, BlockOrder::Hilbert);
+    assert!(line.iter().enumerate().all(|(j, &p)| p == (0, j)));
+    assert_eq!(
+        block_products(2, 2, 3, BlockOrder::RowMajor)[..6],
+        [(0, 0, 0), (0, 0, 1), (0, 0, 2), (0, 1, 2), (0, 1, 1), (0, 1, 0)]
+    );
+}
+
+#[test]
+fn test_lru_misses() {
+    // everything fits: each block loaded once
+    let products = block_products(4, 4, 4, BlockOrder::RowMajor);
+    assert_eq!(lru_misses(&products, 48), 48);
+    // a 16 x 16 grid, 4 blocks along k, room for 64 blocks: the space filling curves
+    // reuse the blocks of a and b between neighbour output blocks
+    let misses = |order| lru_misses(&block_products(16, 16, 4, order), 64);
+    let row_major = misses(BlockOrder::RowMajor);
+    assert!(misses(BlockOrder::Serpentine) < row_major);
+    assert!(misses(BlockOrder::Hilbert) < misses(BlockOrder::Serpentine));
+    assert!(misses(BlockOrder::Morton) < row_major);
+}
@@ -0,0 +1,156 @@
+//! Cache-oblivious multiplication (Frigo, Leiserson, Prokop, Ramachandran):
+//! each step halves the largest of m, n and k, so the blocks stay close to cubes whatever
+//! the shape, and very skinny or very deep products are cut along the dimension that
+//! actually carries the work (my_ndarray::mult always cuts in quadrants).
+//! Halving m or n gives two independent products, run in parallel. Halving k gives two
+//! products into the same output: they are serialized, unless the output block is small
+//! enough to accumulate the second half in a temporary and run both in parallel.
+
+use crate::executor::{Executor, Global};
+use crate::trace;
+use ndarray::{linalg, Array, ArrayView, ArrayViewMut, Axis, Ix2, LinalgScalar};
+#[cfg(test)]
+use crate::executor::Sequential;
+
+/// Products of at most this many multiply-adds are leaves.
+const LEAF_VOLUME: usize = 32 * 32 * 32;
+/// Largest output block (in elements) accumulated in a temporary on a k split.
+const ACCUMULATE_LIMIT: usize = 64 * 64;
+
+/// The dimension a step halves.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Cut {
+    /// m: rows of a and of the result.
+    Rows,
+    /// n: columns of b and of the result.
+    Cols,
+    /// k: columns of a and rows of b.
+    Depth,
+}
+
+/// The largest of m, n and k, m first and k last on ties.
+fn largest(m: usize, n: usize, k: usize) -> Cut {
+    if m >= n && m >= k {
+        Cut::Rows
+    } else if n >= k {
+        Cut::Cols
+    } else {
+        Cut::Depth
+    }
+}
+
+///
+/// result += a * b, halving the largest dimension until the products are small.
+///
+pub fn mult<'a, 'b, 'd, A>(
+    a: ArrayView<'a, A, Ix2>,
+    b: ArrayView<'b, A, Ix2>,
+    result: ArrayViewMut<'d, A, Ix2>,
+) -> ArrayViewMut<'d, A, Ix2>
+where
+    A: LinalgScalar + Send + Sync,
+{
+    mult_with(a, b, result, &Global)
+}
+
+///
+/// Same as mult with the tasks run by the given executor.
+///
+pub fn mult_with<'a, 'b, 'd, A, E>(
+    a: ArrayView<'a, A, Ix2>,
+    b: ArrayView<'b, A, Ix2>,
+    mut result: ArrayViewMut<'d, A, Ix2>,
+    executor: &E,
+) -> ArrayViewMut<'d, A, Ix2>
+where
+    A: LinalgScalar + Send + Sync,
+    E: Executor,
+{
+    assert_eq!(a.cols(), b.rows());
+    assert_eq!(result.dim(), (a.rows(), b.cols()));
+    rec(a, b, result.view_mut(), executor);
+    result
+}
+
+fn rec<A, E>(
+    a: ArrayView<A, Ix2>,
+    b: ArrayView<A, Ix2>,
+    mut result: ArrayViewMut<A, Ix2>,
+    executor: &E,
+)
+where
+    A: LinalgScalar + Send + Sync,
+    E: Executor,
+{
+    let (m, k, n) = (a.rows(), a.cols(), b.cols());
+    if m * n * k <= LEAF_VOLUME {
+        let _span = trace::span("cache_oblivious", "leaf");
+        linalg::general_mat_mul(A::one(), &a, &b, A::one(), &mut result);
+        return;
+    }
+    let span = trace::span("cache_oblivious", "divide");
+    match largest(m, n, k) {
+        Cut::Rows => {
+            let (a1, a2) = a.split_at(Axis(0), m / 2);
+            let (r1, r2) = result.split_at(Axis(0), m / 2);
+            drop(span);
+            executor.join(|| rec(a1, b, r1, executor), || rec(a2, b, r2, executor));
+        }
+        Cut::Cols => {
+            let (b1, b2) = b.split_at(Axis(1), n / 2);
+            let (r1, r2) = result.split_at(Axis(1), n / 2);
+            drop(span);
+            executor.join(|| rec(a, b1, r1, executor), || rec(a, b2, r2, executor));
+        }
+        Cut::Depth => {
+            let (a1, a2) = a.split_at(Axis(1), k / 2);
+            let (b1, b2) = b.split_at(Axis(0), k / 2);
+            drop(span);
+            if m * n <= ACCUMULATE_LIMIT {
+                let mut temp = Array::zeros((m, n));
+                executor.join(
+                    || rec(a1, b1, result.view_mut(), executor),
+                    || rec(a2, b2, temp.view_mut(), executor),
+                );
+                result.zip_mut_with(&temp, |r, &t| *r = *r + t);
+            } else {
+                rec(a1, b1, result.view_mut(), executor);
+                rec(a2, b2, result, executor);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_largest() {
+    assert_eq!(largest(100, 3, 3), Cut::Rows);
+    assert_eq!(largest(3, 100, 3), Cut::Cols);
+    assert_eq!(largest(3, 3, 100), Cut::Depth);
+    assert_eq!(largest(8, 8, 8), Cut::Rows);
+    assert_eq!(largest(4, 8, 8), Cut::Cols);
+}
+
+#[test]
+fn test_mult() {
+    // square, skinny (tall a, wide b), deep (k >> m, n), and deep with a large output
+    for &(m, k, n) in &[
+        (67, 45, 71),
+        (3000, 3, 2),
+        (2, 3, 3000),
+        (3, 20000, 4),
+        (90, 2000, 70),
+        (0, 50, 5),
+        (5, 0, 5),
+    ] {
+        let a = Array::from_shape_fn((m, k), |(i, j)| ((i * 3 + j) % 7) as i64 - 3);
+        let b = Array::from_shape_fn((k, n), |(i, j)| ((i + 5 * j) % 4) as i64);
+        let mut verif = Array::from_elem((m, n), 1i64);
+        linalg::general_mat_mul(1, &a, &b, 1, &mut verif);
+        let mut par = Array::from_elem((m, n), 1i64);
+        mult(a.view(), b.view(), par.view_mut());
+        assert_eq!(par, verif, "{}x{}x{}", m, k, n);
+        let mut seq = Array::from_elem((m, n), 1i64);
+        mult_with(a.view(), b.view(), seq.view_mut(), &Sequential);
+        assert_eq!(seq, verif, "{}x{}x{} sequential", m, k, n);
+    }
+}
@@ -0,0 +1,652 @@
+//! CBLAS (cblas_sgemm, cblas_dgemm) and Fortran BLAS (sgemm_, dgemm_) entry points.
+//! Only compiled with the `cblas` feature. They are exported with a `matrix_mult_` prefix
+//! so they never clash with the BLAS ndarray links against (`openblas` feature); the
+//! standard names are only exported with `cblas-alias`, which excludes `openblas`.
+//! Nothing here goes through ndarray's general_mat_mul.
+//! The leaves are vectorisation_packed_simd (f32) and faster_vec (f64, SIMD dot products),
+//! scheduled by matrix_adaptive::Matrix with an adaptive policy.
+use crate::faster_vec;
+use crate::matrix_adaptive;
+use crate::vectorisation_packed_simd;
+use ndarray::{Array, ArrayView, ArrayViewMut, Ix2, LinalgScalar, ShapeBuilder};
+use rayon_adaptive::prelude::*;
+use rayon_adaptive::Policy;
+use std::os::raw::{c_char, c_int};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+
+pub const CBLAS_ROW_MAJOR: c_int = 101;
+pub const CBLAS_COL_MAJOR: c_int = 102;
+pub const CBLAS_NO_TRANS: c_int = 111;
+pub const CBLAS_TRANS: c_int = 112;
+pub const CBLAS_CONJ_TRANS: c_int = 113;
+
+///
+/// Element types of the exported gemm: the sequential leaf used on each block.
+///
+pub trait GemmScalar: LinalgScalar + PartialEq + Send + Sync {
+    fn leaf(a: ArrayView<Self, Ix2>, b: ArrayView<Self, Ix2>, d: ArrayViewMut<Self, Ix2>);
+}
+
+impl GemmScalar for f32 {
+    fn leaf(a: ArrayView<f32, Ix2>, b: ArrayView<f32, Ix2>, mut d: ArrayViewMut<f32, Ix2>) {
+        vectorisation_packed_simd::mult_faster_from_ndarray(a, b, &mut d);
+    }
+}
+
+impl GemmScalar for f64 {
+    fn leaf(a: ArrayView<f64, Ix2>, b: ArrayView<f64, Ix2>, mut d: ArrayViewMut<f64, Ix2>) {
+        faster_vec::mult_faster_from_ndarray(a, b, &mut d);
+    }
+}
+
+/// A column major matrix is its row major transpose.
+unsafe fn operand<'a, A>(
+    ptr: *const A,
+    rows: usize,
+    cols: usize,
+    ld: usize,
+    row_major: bool,
+) -> ArrayView<'a, A, Ix2> {
+    let strides = if row_major { (ld, 1) } else { (1, ld) };
+    ArrayView::from_shape_ptr((rows, cols).strides(strides), ptr)
+}
+
+///
+/// C = alpha * op(A) * op(B) + beta * C
+/// Dimensions are the ones of op(A) (m x k), op(B) (k x n) and C (m x n), as in BLAS.
+/// Returns the position of the first invalid argument of cblas_?gemm (0 if all are valid).
+///
+pub unsafe fn gemm<A: GemmScalar>(
+    row_major: bool,
+    trans_a: bool,
+    trans_b: bool,
+    m: usize,
+    n: usize,
+    k: usize,
+    alpha: A,
+    a: *const A,
+    lda: usize,
+    b: *const A,
+    ldb: usize,
+    beta: A,
+    c: *mut A,
+    ldc: usize,
+) -> usize {
+    // stored shapes
+    let (ar, ac) = if trans_a { (k, m) } else { (m, k) };
+    let (br, bc) = if trans_b { (n, k) } else { (k, n) };
+    let minor = |rows: usize, cols: usize| if row_major { cols } else { rows }.max(1);
+    if lda < minor(ar, ac) {
+        return 9;
+    }
+    if ldb < minor(br, bc) {
+        return 11;
+    }
+    if ldc < minor(m, n) {
+        return 14;
+    }
+    if m == 0 || n == 0 {
+        return 0;
+    }
+    let strides = if row_major { (ldc, 1) } else { (1, ldc) };
+    let mut cview = ArrayViewMut::from_shape_ptr((m, n).strides(strides), c);
+    // BLAS does not read C when beta is zero
+    let scaled = |x: A| {
+        if beta == A::zero() {
+            A::zero()
+        } else {
+            x * beta
+        }
+    };
+    if k == 0 || alpha == A::zero() {
+        if beta != A::one() {
+            cview.mapv_inplace(scaled);
+        }
+        return 0;
+    }
+    let mut aview = operand(a, ar, ac, lda, row_major);
+    if trans_a {
+        aview = aview.reversed_axes();
+    }
+    let mut bview = operand(b, br, bc, ldb, row_major);
+    if trans_b {
+        bview = bview.reversed_axes();
+    }
+    // the leaves want row major operands with contiguous rows
+    let apacked = Array::from_shape_fn((m, k), |ij| aview[ij]);
+    let bpacked = Array::from_shape_fn((k, n), |ij| bview[ij]);
+    let mut product = Array::zeros((m, n));
+    let size = m * n;
+    let mat = matrix_adaptive::Matrix {
+        a: apacked.view(),
+        b: bpacked.view(),
+        d: product.view_mut(),
+        asize: (m, k),
+        bsize: (k, n),
+        dsize: (m, n),
+    };
+    let policy = Policy::Adaptive(
+        (size as f64).log2() as usize + 1,
+        (size as f64).sqrt() as usize + 1,
+    );
+    mat.with_policy(policy).for_each(|e| {
+        let (ra, ca) = e.a.dim();
+        let (_rb, cb) = e.b.dim();
+        if ra != 0 && ca != 0 && cb != 0 {
+            A::leaf(e.a, e.b, e.d);
+        }
+    });
+    // C is only written once the product is complete
+    cview.zip_mut_with(&product, |x, p| *x = scaled(*x) + alpha * *p);
+    0
+}
+
+fn transpose_flag(trans: c_int) -> Option<bool> {
+    match trans {
+        CBLAS_NO_TRANS => Some(false),
+        CBLAS_TRANS | CBLAS_CONJ_TRANS => Some(true),
+        _ => None,
+    }
+}
+
+fn fortran_transpose_flag(trans: c_char) -> Option<bool> {
+    match trans as u8 {
+        b'N' | b'n' => Some(false),
+        b'T' | b't' | b'C' | b'c' => Some(true),
+        _ => None,
+    }
+}
+
+fn report(name: &str, parameter: usize) -> usize {
+    eprintln!(
+        " ** On entry to {} parameter number {} had an illegal value",
+        name, parameter
+    );
+    parameter
+}
+
+///
+/// Check the arguments following the order and run gemm.
+/// `shift` is 0 for CBLAS and 1 for Fortran, which has no order argument: every position
+/// is the CBLAS one minus the shift.
+/// Returns the position reported (0 if all arguments are valid).
+/// A panic in the product aborts the process, C being left untouched.
+///
+unsafe fn checked_gemm<A: GemmScalar>(
+    name: &str,
+    shift: usize,
+    row_major: bool,
+    trans_a: Option<bool>,
+    trans_b: Option<bool>,
+    m: c_int,
+    n: c_int,
+    k: c_int,
+    alpha: A,
+    a: *const A,
+    lda: c_int,
+    b: *const A,
+    ldb: c_int,
+    beta: A,
+    c: *mut A,
+    ldc: c_int,
+) -> usize {
+    let ta = match trans_a {
+        Some(t) => t,
+        None => return report(name, 2 - shift),
+    };
+    let tb = match trans_b {
+        Some(t) => t,
+        None => return report(name, 3 - shift),
+    };
+    for (position, value) in [(4, m), (5, n), (6, k)].iter() {
+        if *value < 0 {
+            return report(name, *position - shift);
+        }
+    }
+    let run = AssertUnwindSafe(|| {
+        gemm(
+            row_major,
+            ta,
+            tb,
+            m as usize,
+            n as usize,
+            k as usize,
+            alpha,
+            a,
+            lda.max(0) as usize,
+            b,
+            ldb.max(0) as usize,
+            beta,
+            c,
+            ldc.max(0) as usize,
+        )
+    });
+    match catch_unwind(run) {
+        Ok(0) => 0,
+        Ok(parameter) => report(name, parameter - shift),
+        Err(_) => {
+            // like the reference xerbla: there is no error status to return
+            eprintln!(" ** {} failed, aborting", name);
+            std::process::abort()
+        }
+    }
+}
+
+unsafe fn cblas_gemm<A: GemmScalar>(
+    name: &str,
+    order: c_int,
+    trans_a: c_int,
+    trans_b: c_int,
+    m: c_int,
+    n: c_int,
+    k: c_int,
+    alpha: A,
+    a: *const A,
+    lda: c_int,
+    b: *const A,
+    ldb: c_int,
+    beta: A,
+    c: *mut A,
+    ldc: c_int,
+) -> usize {
+    let row_major = match order {
+        CBLAS_ROW_MAJOR => true,
+        CBLAS_COL_MAJOR => false,
+        _ => return report(name, 1),
+    };
+    checked_gemm(
+        name,
+        0,
+        row_major,
+        transpose_flag(trans_a),
+        transpose_flag(trans_b),
+        m,
+        n,
+        k,
+        alpha,
+        a,
+        lda,
+        b,
+        ldb,
+        beta,
+        c,
+        ldc,
+    )
+}
+
+// Fortran: column major, every argument by reference.
+unsafe fn fortran_gemm<A: GemmScalar>(
+    name: &str,
+    transa: *const c_char,
+    transb: *const c_char,
+    m: *const c_int,
+    n: *const c_int,
+    k: *const c_int,
+    alpha: *const A,
+    a: *const A,
+    lda: *const c_int,
+    b: *const A,
+    ldb: *const c_int,
+    beta: *const A,
+    c: *mut A,
+    ldc: *const c_int,
+) -> usize {
+    checked_gemm(
+        name,
+        1,
+        false,
+        fortran_transpose_flag(*transa),
+        fortran_transpose_flag(*transb),
+        *m,
+        *n,
+        *k,
+        *alpha,
+        a,
+        *lda,
+        b,
+        *ldb,
+        *beta,
+        c,
+        *ldc,
+    )
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn matrix_mult_cblas_sgemm(
+    order: c_int,
+    trans_a: c_int,
+    trans_b: c_int,
+    m: c_int,
+    n: c_int,
+    k: c_int,
+    alpha: f32,
+    a: *const f32,
+    lda: c_int,
+    b: *const f32,
+    ldb: c_int,
+    beta: f32,
+    c: *mut f32,
+    ldc: c_int,
+) {
+    cblas_gemm(
+        "cblas_sgemm",
+        order,
+        trans_a,
+        trans_b,
+        m,
+        n,
+        k,
+        alpha,
+        a,
+        lda,
+        b,
+        ldb,
+        beta,
+        c,
+        ldc,
+    );
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn matrix_mult_cblas_dgemm(
+    order: c_int,
+    trans_a: c_int,
+    trans_b: c_int,
+    m: c_int,
+    n: c_int,
+    k: c_int,
+    alpha: f64,
+    a: *const f64,
+    lda: c_int,
+    b: *const f64,
+    ldb: c_int,
+    beta: f64,
+    c: *mut f64,
+    ldc: c_int,
+) {
+    cblas_gemm(
+        "cblas_dgemm",
+        order,
+        trans_a,
+        trans_b,
+        m,
+        n,
+        k,
+        alpha,
+        a,
+        lda,
+        b,
+        ldb,
+        beta,
+        c,
+        ldc,
+    );
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn matrix_mult_sgemm_(
+    transa: *const c_char,
+    transb: *const c_char,
+    m: *const c_int,
+    n: *const c_int,
+    k: *const c_int,
+    alpha: *const f32,
+    a: *const f32,
+    lda: *const c_int,
+    b: *const f32,
+    ldb: *const c_int,
+    beta: *const f32,
+    c: *mut f32,
+    ldc: *const c_int,
+) {
+    fortran_gemm(
+        "SGEMM ", transa, transb, m, n, k, alpha, a, lda, b, ldb, beta, c, ldc,
+    );
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn matrix_mult_dgemm_(
+    transa: *const c_char,
+    transb: *const c_char,
+    m: *const c_int,
+    n: *const c_int,
+    k: *const c_int,
+    alpha: *const f64,
+    a: *const f64,
+    lda: *const c_int,
+    b: *const f64,
+    ldb: *const c_int,
+    beta: *const f64,
+    c: *mut f64,
+    ldc: *const c_int,
+) {
+    fortran_gemm(
+        "DGEMM ", transa, transb, m, n, k, alpha, a, lda, b, ldb, beta, c, ldc,
+    );
+}
+
+/// The standard names, for C programs linked against this library instead of a BLAS.
+#[cfg(feature = "cblas-alias")]
+mod alias {
+    use super::*;
+
+    #[no_mangle]
+    pub unsafe extern "C" fn cblas_sgemm(
+        order: c_int,
+        trans_a: c_int,
+        trans_b: c_int,
+        m: c_int,
+        n: c_int,
+        k: c_int,
+        alpha: f32,
+        a: *const f32,
+        lda: c_int,
+        b: *const f32,
+        ldb: c_int,
+        beta: f32,
+        c: *mut f32,
+        ldc: c_int,
+    ) {
+        matrix_mult_cblas_sgemm(
+            order, trans_a, trans_b, m, n, k, alpha, a, lda, b, ldb, beta, c, ldc,
+        )
+    }
+
+    #[no_mangle]
+    pub unsafe extern "C" fn cblas_dgemm(
+        order: c_int,
+        trans_a: c_int,
+        trans_b: c_int,
+        m: c_int,
+        n: c_int,
+        k: c_int,
+        alpha: f64,
+        a: *const f64,
+        lda: c_int,
+        b: *const f64,
+        ldb: c_int,
+        beta: f64,
+        c: *mut f64,
+        ldc: c_int,
+    ) {
+        matrix_mult_cblas_dgemm(
+            order, trans_a, trans_b, m, n, k, alpha, a, lda, b, ldb, beta, c, ldc,
+        )
+    }
+
+    #[no_mangle]
+    pub unsafe extern "C" fn sgemm_(
+        transa: *const c_char,
+        transb: *const c_char,
+        m: *const c_int,
+        n: *const c_int,
+        k: *const c_int,
+        alpha: *const f32,
+        a: *const f32,
+        lda: *const c_int,
+        b: *const f32,
+        ldb: *const c_int,
+        beta: *const f32,
+        c: *mut f32,
+        ldc: *const c_int,
+    ) {
+        matrix_mult_sgemm_(transa, transb, m, n, k, alpha, a, lda, b, ldb, beta, c, ldc)
+    }
+
+    #[no_mangle]
+    pub unsafe extern "C" fn dgemm_(
+        transa: *const c_char,
+        transb: *const c_char,
+        m: *const c_int,
+        n: *const c_int,
+        k: *const c_int,
+        alpha: *const f64,
+        a: *const f64,
+        lda: *const c_int,
+        b: *const f64,
+        ldb: *const c_int,
+        beta: *const f64,
+        c: *mut f64,
+        ldc: *const c_int,
+    ) {
+        matrix_mult_dgemm_(transa, transb, m, n, k, alpha, a, lda, b, ldb, beta, c, ldc)
+    }
+}
+
+#[cfg(test)]
+fn reference(
+    row_major: bool,
+    ta: bool,
+    tb: bool,
+    m: usize,
+    n: usize,
+    k: usize,
+    a: &[f64],
+    lda: usize,
+    b: &[f64],
+    ldb: usize,
+) -> Vec<f64> {
+    let at = |i: usize, j: usize, ld: usize| if row_major { i * ld + j } else { i + j * ld };
+    let mut c = vec![0.0; m * n];
+    for i in 0..m {
+        for j in 0..n {
+            for p in 0..k {
+                let x = if ta { a[at(p, i, lda)] } else { a[at(i, p, lda)] };
+                let y = if tb { b[at(j, p, ldb)] } else { b[at(p, j, ldb)] };
+                c[i * n + j] += x * y;
+            }
+        }
+    }
+    c
+}
+
+#[test]
+fn test_gemm_layouts() {
+    let (m, n, k) = (37, 23, 41);
+    let pad = 3;
+    for &row_major in &[true, false] {
+        for &ta in &[false, true] {
+            for &tb in &[false, true] {
+                let (ar, ac) = if ta { (k, m) } else { (m, k) };
+                let (br, bc) = if tb { (n, k) } else { (k, n) };
+                let lda = if row_major { ac } else { ar } + pad;
+                let ldb = if row_major { bc } else { br } + pad;
+                let ldc = if row_major { n } else { m } + pad;
+                let a: Vec<f64> = (0..lda * ar.max(ac)).map(|i| (i % 7) as f64 - 3.0).collect();
+                let b: Vec<f64> = (0..ldb * br.max(bc)).map(|i| (i % 5) as f64 - 2.0).collect();
+                let mut c: Vec<f64> = (0..ldc * m.max(n)).map(|i| (i % 3) as f64).collect();
+                let c0 = c.clone();
+                let expected = reference(row_major, ta, tb, m, n, k, &a, lda, &b, ldb);
+                unsafe {
+                    matrix_mult_cblas_dgemm(
+                        if row_major { CBLAS_ROW_MAJOR } else { CBLAS_COL_MAJOR },
+                        if ta { CBLAS_TRANS } else { CBLAS_NO_TRANS },
+                        if tb { CBLAS_TRANS } else { CBLAS_NO_TRANS },
+                        m as c_int,
+                        n as c_int,
+                        k as c_int,
+                        2.0,
+                        a.as_ptr(),
+                        lda as c_int,
+                        b.as_ptr(),
+                        ldb as c_int,
+                        0.5,
+                        c.as_mut_ptr(),
+                        ldc as c_int,
+                    );
+                }
+                for i in 0..m {
+                    for j in 0..n {
+                        let idx = if row_major { i * ldc + j } else { i + j * ldc };
+                        assert_eq!(c[idx], 2.0 * expected[i * n + j] + 0.5 * c0[idx]);
+                    }
+                }
+            }
+        }
+    }
+    // beta = 0 does not read C, k = 0 only scales it
+    let (a, b) = ([1.0f64, 2.0], [3.0f64, 4.0]);
+    let dot = |k, beta, c: &mut [f64; 1]| unsafe {
+        let (ap, bp, cp) = (a.as_ptr(), b.as_ptr(), c.as_mut_ptr());
+        gemm(true, false, false, 1, 1, k, 1.0, ap, 2, bp, 1, beta, cp, 1)
+    };
+    let mut c = [f64::NAN];
+    assert_eq!(dot(2, 0.0, &mut c), 0);
+    assert_eq!(c, [11.0]);
+    assert_eq!(dot(0, 0.5, &mut c), 0);
+    assert_eq!(c, [5.5]);
+}
+
+#[test]
+fn test_illegal_arguments() {
+    let (a, b, mut c) = ([1.0f64; 4], [1.0f64; 4], [0.0f64; 4]);
+    let cblas = |order, m, lda, ldc, c: &mut [f64]| unsafe {
+        cblas_gemm(
+            "cblas_dgemm",
+            order,
+            CBLAS_NO_TRANS,
+            CBLAS_NO_TRANS,
+            m,
+            2,
+            2,
+            1.0,
+            a.as_ptr(),
+            lda,
+            b.as_ptr(),
+            2,
+            0.0,
+            c.as_mut_ptr(),
+            ldc,
+        )
+    };
+    assert_eq!(cblas(0, 2, 2, 2, &mut c), 1);
+    assert_eq!(cblas(CBLAS_ROW_MAJOR, -1, 2, 2, &mut c), 4);
+    assert_eq!(cblas(CBLAS_ROW_MAJOR, 2, 1, 2, &mut c), 9);
+    assert_eq!(cblas(CBLAS_ROW_MAJOR, 2, 2, 1, &mut c), 14);
+    // same errors, Fortran positions (no order argument)
+    let fortran = |trans: u8, m: c_int, lda: c_int, ldc: c_int, c: &mut [f64]| unsafe {
+        let (trans, two, one, zero) = (trans as c_char, 2, 1.0, 0.0);
+        fortran_gemm(
+            "DGEMM ",
+            &trans,
+            &trans,
+            &m,
+            &two,
+            &two,
+            &one,
+            a.as_ptr(),
+            &lda,
+            b.as_ptr(),
+            &two,
+            &zero,
+            c.as_mut_ptr(),
+            &ldc,
+        )
+    };
+    assert_eq!(fortran(b'X', 2, 2, 2, &mut c), 1);
+    assert_eq!(fortran(b'N', -1, 2, 2, &mut c), 3);
+    assert_eq!(fortran(b'N', 2, 1, 2, &mut c), 8);
+    assert_eq!(fortran(b'N', 2, 2, 1, &mut c), 13);
+    assert_eq!(fortran(b'N', 2, 2, 2, &mut c), 0);
+    assert_eq!(c, [2.0; 4]);
+}
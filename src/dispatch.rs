@@ -0,0 +1,310 @@
+//! Runtime selection of the SIMD leaf kernels.
+//! The faster and packed_simd kernels are compiled once per instruction set level
+//! (with `#[target_feature]`), the best level supported by the CPU is picked at the first
+//! call, so a binary built for the baseline target still uses AVX2 / AVX-512 where available.
+//! `force` (or the MATRIX_MULT_ISA environment variable) selects a level for testing.
+//! faster chooses its vector width at build time: its variants only gain the instructions
+//! the compiler can use around it (packed_simd FMAs become single instructions with `Avx2`).
+
+use crate::{faster_vec, vectorisation_packed_simd};
+use ndarray::{ArrayView, ArrayViewMut, Ix2};
+use std::error::Error;
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+#[cfg(test)]
+use ndarray::{linalg, Array};
+#[cfg(test)]
+use std::sync::{Mutex, MutexGuard};
+
+/// Instruction set levels, each one implies the previous ones.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Isa {
+    /// The x86_64 baseline (the build's target features on other architectures).
+    Sse2,
+    Avx,
+    /// AVX2 with FMA.
+    Avx2,
+    /// AVX-512 foundation.
+    Avx512,
+}
+
+pub const ISAS: [Isa; 4] = [Isa::Sse2, Isa::Avx, Isa::Avx2, Isa::Avx512];
+
+impl Isa {
+    pub fn name(self) -> &'static str {
+        match self {
+            Isa::Sse2 => "sse2",
+            Isa::Avx => "avx",
+            Isa::Avx2 => "avx2",
+            Isa::Avx512 => "avx512",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Isa> {
+        ISAS.iter().cloned().find(|isa| isa.name() == name)
+    }
+
+    /// True if the CPU running this program supports it.
+    pub fn is_supported(self) -> bool {
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        {
+            match self {
+                Isa::Sse2 => true,
+                Isa::Avx => is_x86_feature_detected!("avx"),
+                Isa::Avx2 => is_x86_feature_detected!("avx2") && is_x86_feature_detected!("fma"),
+                Isa::Avx512 => Isa::Avx2.is_supported() && is_x86_feature_detected!("avx512f"),
+            }
+        }
+        #[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+        {
+            self == Isa::Sse2
+        }
+    }
+}
+
+/// The level cannot be used on this CPU.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Unsupported(pub Isa);
+
+impl fmt::Display for Unsupported {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} is not supported by this CPU", self.0.name())
+    }
+}
+
+impl Error for Unsupported {}
+
+/// Best level supported by the CPU.
+pub fn detect() -> Isa {
+    *ISAS.iter().rev().find(|isa| isa.is_supported()).unwrap()
+}
+
+// 0 before the first selection, the index in ISAS plus one after
+static SELECTED: AtomicUsize = AtomicUsize::new(0);
+
+fn store(isa: Isa) {
+    let index = ISAS.iter().position(|&i| i == isa).unwrap();
+    SELECTED.store(index + 1, Ordering::Relaxed);
+}
+
+///
+/// The level used by the dispatched kernels: the forced one, else MATRIX_MULT_ISA
+/// (sse2, avx, avx2, avx512) if set and supported, else the detected one.
+///
+pub fn selected() -> Isa {
+    match SELECTED.load(Ordering::Relaxed) {
+        0 => {
+            let detected = detect();
+            let isa = match std::env::var("MATRIX_MULT_ISA") {
+                Ok(ref name) if name.is_empty() => detected,
+                Ok(name) => match Isa::from_name(&name) {
+                    Some(isa) if isa.is_supported() => isa,
+                    _ => {
+                        eprintln!(
+                            "MATRIX_MULT_ISA: cannot use '{}', using {}",
+                            name,
+                            detected.name()
+                        );
+                        detected
+                    }
+                },
+                Err(_) => detected,
+            };
+            store(isa);
+            isa
+        }
+        index => ISAS[index - 1],
+    }
+}
+
+///
+/// Use `isa` for all the dispatched kernels, or go back to the automatic selection with None.
+///
+pub fn force(isa: Option<Isa>) -> Result<(), Unsupported> {
+    match isa {
+        Some(isa) if !isa.is_supported() => Err(Unsupported(isa)),
+        Some(isa) => {
+            store(isa);
+            Ok(())
+        }
+        None => {
+            SELECTED.store(0, Ordering::Relaxed);
+            Ok(())
+        }
+    }
+}
+
+/// Tests forcing a level or running the dispatched kernels hold it: the selection is global.
+#[cfg(test)]
+static TEST_LOCK: Mutex<()> = Mutex::new(());
+
+#[cfg(test)]
+pub(crate) fn test_lock() -> MutexGuard<'static, ()> {
+    TEST_LOCK.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+type Kernel = fn(ArrayView<f32, Ix2>, ArrayView<f32, Ix2>, &mut ArrayViewMut<f32, Ix2>);
+type Variant = unsafe fn(ArrayView<f32, Ix2>, ArrayView<f32, Ix2>, &mut ArrayViewMut<f32, Ix2>);
+
+// One copy of each kernel per level: the kernels and the functions they call are
+// #[inline(always)], so they are inlined into these functions and compiled with their
+// target features.
+macro_rules! variants {
+    ($module:ident, $kernel:path) => {
+        mod $module {
+            #[allow(unused_imports)]
+            use ndarray::{ArrayView, ArrayViewMut, Ix2};
+
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            #[target_feature(enable = "avx")]
+            pub unsafe fn avx(
+                a: ArrayView<f32, Ix2>,
+                b: ArrayView<f32, Ix2>,
+                output: &mut ArrayViewMut<f32, Ix2>,
+            ) {
+                $kernel(a, b, output)
+            }
+
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            #[target_feature(enable = "avx2,fma")]
+            pub unsafe fn avx2(
+                a: ArrayView<f32, Ix2>,
+                b: ArrayView<f32, Ix2>,
+                output: &mut ArrayViewMut<f32, Ix2>,
+            ) {
+                $kernel(a, b, output)
+            }
+
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            #[target_feature(enable = "avx512f,avx2,fma")]
+            pub unsafe fn avx512(
+                a: ArrayView<f32, Ix2>,
+                b: ArrayView<f32, Ix2>,
+                output: &mut ArrayViewMut<f32, Ix2>,
+            ) {
+                $kernel(a, b, output)
+            }
+        }
+    };
+}
+
+variants!(packed_simd_variants, crate::vectorisation_packed_simd::mult_faster_from_ndarray);
+variants!(faster_variants, crate::faster_vec::mult_faster_from_ndarray);
+
+fn call(
+    isa: Isa,
+    baseline: Kernel,
+    variants: [Variant; 3],
+    a: ArrayView<f32, Ix2>,
+    b: ArrayView<f32, Ix2>,
+    output: &mut ArrayViewMut<f32, Ix2>,
+) {
+    assert!(isa.is_supported(), "{}", Unsupported(isa));
+    match isa {
+        Isa::Sse2 => baseline(a, b, output),
+        // safe: the CPU supports the features of the variant
+        Isa::Avx => unsafe { variants[0](a, b, output) },
+        Isa::Avx2 => unsafe { variants[1](a, b, output) },
+        Isa::Avx512 => unsafe { variants[2](a, b, output) },
+    }
+}
+
+///
+/// vectorisation_packed_simd::mult_faster_from_ndarray compiled for `isa`.
+/// Panics if the CPU does not support it.
+///
+pub fn mult_packed_simd_with(
+    isa: Isa,
+    a: ArrayView<f32, Ix2>,
+    b: ArrayView<f32, Ix2>,
+    output: &mut ArrayViewMut<f32, Ix2>,
+) {
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    let variants: [Variant; 3] = [
+        packed_simd_variants::avx,
+        packed_simd_variants::avx2,
+        packed_simd_variants::avx512,
+    ];
+    #[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+    let variants: [Variant; 3] = [vectorisation_packed_simd::mult_faster_from_ndarray; 3];
+    call(
+        isa,
+        vectorisation_packed_simd::mult_faster_from_ndarray,
+        variants,
+        a,
+        b,
+        output,
+    )
+}
+
+///
+/// faster_vec::mult_faster_from_ndarray compiled for `isa`.
+/// Panics if the CPU does not support it.
+///
+pub fn mult_faster_with(
+    isa: Isa,
+    a: ArrayView<f32, Ix2>,
+    b: ArrayView<f32, Ix2>,
+    output: &mut ArrayViewMut<f32, Ix2>,
+) {
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    let variants: [Variant; 3] = [
+        faster_variants::avx,
+        faster_variants::avx2,
+        faster_variants::avx512,
+    ];
+    #[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+    let variants: [Variant; 3] = [faster_vec::mult_faster_from_ndarray; 3];
+    call(isa, faster_vec::mult_faster_from_ndarray, variants, a, b, output)
+}
+
+/// output += a * b with the packed_simd kernel of the selected level.
+pub fn mult_packed_simd(
+    a: ArrayView<f32, Ix2>,
+    b: ArrayView<f32, Ix2>,
+    output: &mut ArrayViewMut<f32, Ix2>,
+) {
+    mult_packed_simd_with(selected(), a, b, output)
+}
+
+/// output += a * b with the faster kernel of the selected level.
+pub fn mult_faster(
+    a: ArrayView<f32, Ix2>,
+    b: ArrayView<f32, Ix2>,
+    output: &mut ArrayViewMut<f32, Ix2>,
+) {
+    mult_faster_with(selected(), a, b, output)
+}
+
+#[test]
+fn test_variants_agree() {
+    let (m, k, n) = (37, 53, 45);
+    let an = Array::from_shape_fn((m, k), |(i, j)| ((i * 7 + j) % 5) as f32);
+    let bn = Array::from_shape_fn((k, n), |(i, j)| ((i + 3 * j) % 4) as f32);
+    let mut verif = Array::zeros((m, n));
+    linalg::general_mat_mul(1.0, &an, &bn, 1.0, &mut verif);
+    assert!(detect().is_supported());
+    for &isa in ISAS.iter().filter(|isa| isa.is_supported()) {
+        let mut d1 = Array::zeros((m, n));
+        mult_packed_simd_with(isa, an.view(), bn.view(), &mut d1.view_mut());
+        let mut d2 = Array::zeros((m, n));
+        mult_faster_with(isa, an.view(), bn.view(), &mut d2.view_mut());
+        // small integers: exact whatever the order or fusion of the operations
+        assert_eq!(d1, verif, "packed_simd {:?}", isa);
+        assert_eq!(d2, verif, "faster {:?}", isa);
+    }
+}
+
+#[test]
+fn test_force() {
+    let _lock = test_lock();
+    assert_eq!(force(Some(Isa::Sse2)), Ok(()));
+    assert_eq!(selected(), Isa::Sse2);
+    if let Some(&isa) = ISAS.iter().find(|isa| !isa.is_supported()) {
+        assert_eq!(force(Some(isa)), Err(Unsupported(isa)));
+    }
+    force(None).unwrap();
+    assert!(selected().is_supported());
+    assert_eq!(Isa::from_name("avx2"), Some(Isa::Avx2));
+    assert_eq!(Isa::from_name("neon"), None);
+}
@@ -0,0 +1,375 @@
+//! Where the matrix decompositions cut a dimension.
+//! A DivisionStrategy gives the split point of a length; the same length always gets the
+//! same point, so the k splits of a and b (or the row splits of a and d) stay consistent.
+//! `my_ndarray::mult_with_division` takes a strategy, the rayon_adaptive matrices
+//! (matrix::Matrix, matrix_adaptive::Matrix) get one with `with_division`.
+
+use crate::simd::SimdScalar;
+use rayon_adaptive::prelude::*;
+use std::mem::size_of;
+#[cfg(test)]
+use crate::{matrix, matrix_adaptive, my_ndarray};
+#[cfg(test)]
+use ndarray::{linalg, Array};
+#[cfg(test)]
+use rayon_adaptive::Policy;
+
+pub trait DivisionStrategy: Sync {
+    /// Where to cut `len` elements: in 1..len when len >= 2.
+    fn split_point(&self, len: usize) -> usize;
+}
+
+/// In the middle.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Halving;
+
+impl DivisionStrategy for Halving {
+    fn split_point(&self, len: usize) -> usize {
+        len / 2
+    }
+}
+
+///
+/// At the power of two (half or quarter of the next power of two) closest to the middle,
+/// so most blocks have power of two sizes.
+///
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PowerOfTwoFriendly;
+
+impl DivisionStrategy for PowerOfTwoFriendly {
+    fn split_point(&self, len: usize) -> usize {
+        let power = len.next_power_of_two();
+        let (before, after) = (power / 4, power / 2);
+        if after - len / 2 > len / 2 - before {
+            before
+        } else {
+            after
+        }
+    }
+}
+
+/// The multiple of `multiple` closest to the middle, if there is one in 1..len.
+fn aligned_middle(len: usize, multiple: usize) -> usize {
+    let half = len / 2;
+    let down = half / multiple * multiple;
+    let up = down + multiple;
+    match (down > 0, up < len) {
+        (true, true) if up - half < half - down => up,
+        (true, _) => down,
+        (false, true) => up,
+        (false, false) => half,
+    }
+}
+
+///
+/// At a multiple of the number of elements in a 64 bytes cache line, closest to the middle:
+/// the rows of the blocks start on a cache line (for aligned matrices).
+///
+#[derive(Clone, Copy, Debug)]
+pub struct CacheLineAligned {
+    pub elements: usize,
+}
+
+impl CacheLineAligned {
+    pub fn of<A>() -> Self {
+        CacheLineAligned {
+            elements: (64 / size_of::<A>().max(1)).max(1),
+        }
+    }
+}
+
+impl DivisionStrategy for CacheLineAligned {
+    fn split_point(&self, len: usize) -> usize {
+        aligned_middle(len, self.elements.max(1))
+    }
+}
+
+///
+/// At a multiple of the SIMD vector width, closest to the middle:
+/// the vectorised kernels have no scalar tail except on the last block.
+///
+#[derive(Clone, Copy, Debug)]
+pub struct SimdAligned {
+    pub lanes: usize,
+}
+
+impl SimdAligned {
+    pub fn of<A: SimdScalar>() -> Self {
+        SimdAligned { lanes: A::LANES }
+    }
+}
+
+impl DivisionStrategy for SimdAligned {
+    fn split_point(&self, len: usize) -> usize {
+        aligned_middle(len, self.lanes.max(1))
+    }
+}
+
+///
+/// Balance an estimated cost: `cost(len)` is the cost of a part of `len` elements,
+/// the split point (between len / 4 and 3 len / 4) minimizes the cost of the larger part,
+/// the closest to the middle on ties.
+///
+#[derive(Clone, Copy, Debug)]
+pub struct CostModel<F>(pub F);
+
+impl<F: Fn(usize) -> f64 + Sync> DivisionStrategy for CostModel<F> {
+    fn split_point(&self, len: usize) -> usize {
+        if len < 2 {
+            return len / 2;
+        }
+        let half = len / 2;
+        let cost = |i: usize| (self.0)(i).max((self.0)(len - i));
+        let mut best = half;
+        let mut best_cost = cost(half);
+        for distance in 1..=len / 4 {
+            for &i in &[half - distance, half + distance] {
+                let c = cost(i);
+                if i > 0 && i < len && c < best_cost {
+                    best = i;
+                    best_cost = c;
+                }
+            }
+        }
+        best
+    }
+}
+
+///
+/// Cost of the vectorised kernels: one unit per vector of `lanes` elements and one per
+/// element of the scalar tail.
+///
+pub fn simd_cost(lanes: usize) -> CostModel<impl Fn(usize) -> f64 + Sync + Send + Clone> {
+    let lanes = lanes.max(1);
+    CostModel(move |len: usize| (len / lanes + len % lanes) as f64)
+}
+
+///
+/// rayon_adaptive divisibles whose `divide` can use any DivisionStrategy.
+///
+pub trait DivideWith: Divisible {
+    fn divide_with<S: DivisionStrategy>(self, strategy: &S) -> (Self, Self);
+
+    /// divide_at with a strategy. The index is kept by default: override it when
+    /// divide_at does not use the index.
+    fn divide_at_with<S: DivisionStrategy>(self, index: usize, _strategy: &S) -> (Self, Self) {
+        self.divide_at(index)
+    }
+
+    /// extract_iter with a strategy. The size is kept by default: override it when
+    /// the extraction divides the data.
+    fn extract_iter_with<S: DivisionStrategy>(
+        &mut self,
+        size: usize,
+        _strategy: &S,
+    ) -> <Self as ParallelIterator>::SequentialIterator
+    where
+        Self: ParallelIterator,
+    {
+        self.extract_iter(size)
+    }
+
+    /// The same divisible, divided with `strategy`.
+    fn with_division<S>(self, strategy: S) -> WithDivision<Self, S>
+    where
+        S: DivisionStrategy + Clone + Send,
+    {
+        WithDivision {
+            iter: self,
+            strategy,
+        }
+    }
+}
+
+///
+/// A divisible divided with a given strategy (see DivideWith::with_division),
+/// a parallel iterator over the same items if it is one.
+/// `divide_at` and `extract_iter` go through divide_at_with and extract_iter_with.
+///
+pub struct WithDivision<I, S> {
+    pub iter: I,
+    pub strategy: S,
+}
+
+impl<I, S> Divisible for WithDivision<I, S>
+where
+    I: DivideWith,
+    S: DivisionStrategy + Clone + Send,
+{
+    type Power = I::Power;
+
+    fn base_length(&self) -> Option<usize> {
+        self.iter.base_length()
+    }
+
+    fn divide(self) -> (Self, Self) {
+        let (left, right) = self.iter.divide_with(&self.strategy);
+        (
+            WithDivision {
+                iter: left,
+                strategy: self.strategy.clone(),
+            },
+            WithDivision {
+                iter: right,
+                strategy: self.strategy,
+            },
+        )
+    }
+
+    fn divide_at(self, index: usize) -> (Self, Self) {
+        let (left, right) = self.iter.divide_at_with(index, &self.strategy);
+        (
+            WithDivision {
+                iter: left,
+                strategy: self.strategy.clone(),
+            },
+            WithDivision {
+                iter: right,
+                strategy: self.strategy,
+            },
+        )
+    }
+}
+
+impl<I, S> ParallelIterator for WithDivision<I, S>
+where
+    I: DivideWith + ParallelIterator,
+    S: DivisionStrategy + Clone + Send,
+{
+    type Item = I::Item;
+
+    type SequentialIterator = I::SequentialIterator;
+
+    fn to_sequential(self) -> Self::SequentialIterator {
+        self.iter.to_sequential()
+    }
+
+    fn extract_iter(&mut self, size: usize) -> Self::SequentialIterator {
+        self.iter.extract_iter_with(size, &self.strategy)
+    }
+}
+
+#[test]
+fn test_split_points() {
+    assert_eq!(Halving.split_point(1000), 500);
+    assert_eq!(PowerOfTwoFriendly.split_point(1000), 512);
+    assert_eq!(PowerOfTwoFriendly.split_point(600), 256);
+    assert_eq!(PowerOfTwoFriendly.split_point(500), 256);
+    let line = CacheLineAligned::of::<f32>();
+    assert_eq!(line.elements, 16);
+    assert_eq!(line.split_point(1000), 496);
+    assert_eq!(line.split_point(20), 16);
+    assert_eq!(line.split_point(10), 5);
+    let simd = SimdAligned { lanes: 8 };
+    assert_eq!(simd.split_point(100), 48);
+    assert_eq!(simd.split_point(9), 8);
+    // 12 + 18 (1 vector + 4 tail elements, 2 vectors + 2) beats 15 + 15 (1 + 7 each)
+    assert_eq!(simd_cost(8).split_point(30), 12);
+    assert_eq!(CostModel(|len: usize| (len * len) as f64).split_point(99), 49);
+    let strategies: Vec<&dyn DivisionStrategy> =
+        vec![&Halving, &PowerOfTwoFriendly, &line, &simd];
+    for strategy in strategies {
+        for len in 2..300 {
+            let point = strategy.split_point(len);
+            assert!(point > 0 && point < len, "{} of {}", point, len);
+        }
+    }
+    for len in 2..300 {
+        let point = simd_cost(8).split_point(len);
+        assert!(point > 0 && point < len);
+    }
+}
+
+#[cfg(test)]
+fn check_strategy<S: DivisionStrategy + Clone + Send>(strategy: S) {
+    let (m, k, n) = (203, 170, 150);
+    let an = Array::from_shape_fn((m, k), |(i, j)| ((i * 7 + j) % 5) as f32);
+    let bn = Array::from_shape_fn((k, n), |(i, j)| ((i + 3 * j) % 4) as f32);
+    let mut verif = Array::zeros((m, n));
+    linalg::general_mat_mul(1.0, &an, &bn, 1.0, &mut verif);
+    let mut d1 = Array::zeros((m, n));
+    my_ndarray::mult_with_division(
+        an.view(),
+        bn.view(),
+        d1.view_mut(),
+        &strategy,
+        &crate::executor::Global,
+    );
+    let mut d2 = Array::zeros((m, n));
+    let mat = matrix_adaptive::Matrix {
+        a: an.view(),
+        b: bn.view(),
+        d: d2.view_mut(),
+        asize: an.dim(),
+        bsize: bn.dim(),
+        dsize: (m, n),
+    };
+    mat.with_division(strategy.clone())
+        .with_policy(Policy::Join(500))
+        .for_each(|mut e| {
+            linalg::general_mat_mul(1.0, &e.a, &e.b, 1.0, &mut e.d);
+        });
+    // small integers: exact
+    assert_eq!((d1 == verif, d2 == verif), (true, true));
+    // matrix::Matrix with the divisions of divide and of the adaptive extractions
+    for policy in &[Policy::Join(500), Policy::Adaptive(500, 5000)] {
+        let mut d3 = Array::zeros((m, n));
+        let mat = matrix::Matrix {
+            matrix: vec![(vec![(an.view(), bn.view())], d3.view_mut())],
+        };
+        mat.with_division(strategy.clone())
+            .with_policy(*policy)
+            .for_each(|e| {
+                for (vect, mut output) in e.matrix {
+                    for (a, b) in vect {
+                        linalg::general_mat_mul(1.0, &a, &b, 1.0, &mut output);
+                    }
+                }
+            });
+        assert!(d3 == verif, "{:?}", policy);
+    }
+    let mut d4 = Array::zeros((m, n));
+    let mat = matrix_adaptive::Matrix {
+        a: an.view(),
+        b: bn.view(),
+        d: d4.view_mut(),
+        asize: an.dim(),
+        bsize: bn.dim(),
+        dsize: (m, n),
+    };
+    mat.with_division(strategy)
+        .with_policy(Policy::Adaptive(500, 5000))
+        .for_each(|mut e| {
+            linalg::general_mat_mul(1.0, &e.a, &e.b, 1.0, &mut e.d);
+        });
+    assert!(d4 == verif);
+}
+
+#[test]
+fn test_extract_with_division() {
+    // the first leaf extracted from a 100 x 100 output: quadrants cut by the strategy
+    let an = Array::<f32, _>::zeros((100, 100));
+    let mut d1 = Array::<f32, _>::zeros((100, 100));
+    let mut d2 = Array::<f32, _>::zeros((100, 100));
+    let mut halved = matrix::Matrix {
+        matrix: vec![(vec![(an.view(), an.view())], d1.view_mut())],
+    }
+    .with_division(Halving);
+    let leaf = halved.extract_iter(100).next().unwrap();
+    assert_eq!(leaf.matrix[0].1.dim(), (50, 50));
+    let mut powers = matrix::Matrix {
+        matrix: vec![(vec![(an.view(), an.view())], d2.view_mut())],
+    }
+    .with_division(PowerOfTwoFriendly);
+    let leaf = powers.extract_iter(100).next().unwrap();
+    assert_eq!(leaf.matrix[0].1.dim(), (64, 64));
+}
+
+#[test]
+fn test_strategies() {
+    check_strategy(Halving);
+    check_strategy(PowerOfTwoFriendly);
+    check_strategy(CacheLineAligned::of::<f32>());
+    check_strategy(SimdAligned::of::<f32>());
+    check_strategy(simd_cost(8));
+}
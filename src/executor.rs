@@ -0,0 +1,307 @@
+use rayon::ThreadPool;
+use rayon_adaptive::prelude::*;
+use rayon_adaptive::Policy;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+#[cfg(test)]
+use crate::{matrix_adaptive, my_ndarray, rayon_mult};
+#[cfg(test)]
+use ndarray::{linalg, Array};
+
+///
+/// Where and how the parallel algorithms run their tasks.
+/// Every recursive kernel takes an executor and only forks through `join`,
+/// the rayon_adaptive iterators (matrix::Matrix, matrix_adaptive::Matrix, ...) go through `for_each`.
+/// Implementations: Global (rayon global pool), Pool (a caller's rayon::ThreadPool),
+/// Adaptive (a rayon_adaptive Policy), Sequential and ScopedThreads (std threads, no rayon).
+///
+pub trait Executor: Sync {
+    fn join<FA, FB, RA, RB>(&self, a: FA, b: FB) -> (RA, RB)
+    where
+        FA: FnOnce() -> RA + Send,
+        FB: FnOnce() -> RB + Send,
+        RA: Send,
+        RB: Send;
+
+    /// Number of tasks that can run at the same time.
+    fn num_threads(&self) -> usize;
+
+    /// Run `op` in the context of the executor (inside the pool for a Pool).
+    fn install<F, R>(&self, op: F) -> R
+    where
+        F: FnOnce() -> R + Send,
+        R: Send,
+    {
+        op()
+    }
+
+    ///
+    /// Apply `op` to every item of a rayon_adaptive iterator.
+    /// By default the iterator is divided with `join` until the pieces are
+    /// smaller than 1/4 of the work of each thread.
+    ///
+    fn for_each<I, F>(&self, iter: I, op: F)
+    where
+        I: ParallelIterator + Send,
+        F: Fn(I::Item) + Sync + Send,
+    {
+        let threads = self.num_threads().max(1);
+        let grain = iter
+            .base_length()
+            .map(|length| length / (4 * threads))
+            .unwrap_or(1)
+            .max(1);
+        self.install(|| divide_for_each(self, iter, grain, &op))
+    }
+}
+
+fn divide_for_each<E, I, F>(executor: &E, iter: I, grain: usize, op: &F)
+where
+    E: Executor + ?Sized,
+    I: ParallelIterator + Send,
+    F: Fn(I::Item) + Sync + Send,
+{
+    match iter.base_length() {
+        Some(length) if length > grain => {
+            let (left, right) = iter.divide();
+            executor.join(
+                || divide_for_each(executor, left, grain, op),
+                || divide_for_each(executor, right, grain, op),
+            );
+        }
+        _ => iter.to_sequential().for_each(op),
+    }
+}
+
+/// The global rayon pool.
+pub struct Global;
+
+impl Executor for Global {
+    fn join<FA, FB, RA, RB>(&self, a: FA, b: FB) -> (RA, RB)
+    where
+        FA: FnOnce() -> RA + Send,
+        FB: FnOnce() -> RB + Send,
+        RA: Send,
+        RB: Send,
+    {
+        rayon::join(a, b)
+    }
+
+    fn num_threads(&self) -> usize {
+        rayon::current_num_threads()
+    }
+}
+
+/// A rayon pool managed by the caller.
+pub struct Pool<'p>(pub &'p ThreadPool);
+
+impl<'p> Executor for Pool<'p> {
+    fn join<FA, FB, RA, RB>(&self, a: FA, b: FB) -> (RA, RB)
+    where
+        FA: FnOnce() -> RA + Send,
+        FB: FnOnce() -> RB + Send,
+        RA: Send,
+        RB: Send,
+    {
+        // install runs directly when we already are on one of the pool's threads
+        self.0.install(|| rayon::join(a, b))
+    }
+
+    fn num_threads(&self) -> usize {
+        self.0.current_num_threads()
+    }
+
+    fn install<F, R>(&self, op: F) -> R
+    where
+        F: FnOnce() -> R + Send,
+        R: Send,
+    {
+        self.0.install(op)
+    }
+}
+
+///
+/// Scheduling by a rayon_adaptive policy.
+/// The iterators are given the policy, the recursive kernels fork with rayon::join
+/// (or not at all for Policy::Sequential).
+///
+pub struct Adaptive(pub Policy);
+
+impl Executor for Adaptive {
+    fn join<FA, FB, RA, RB>(&self, a: FA, b: FB) -> (RA, RB)
+    where
+        FA: FnOnce() -> RA + Send,
+        FB: FnOnce() -> RB + Send,
+        RA: Send,
+        RB: Send,
+    {
+        match self.0 {
+            Policy::Sequential => (a(), b()),
+            _ => rayon::join(a, b),
+        }
+    }
+
+    fn num_threads(&self) -> usize {
+        match self.0 {
+            Policy::Sequential => 1,
+            _ => rayon::current_num_threads(),
+        }
+    }
+
+    fn for_each<I, F>(&self, iter: I, op: F)
+    where
+        I: ParallelIterator + Send,
+        F: Fn(I::Item) + Sync + Send,
+    {
+        iter.with_policy(self.0).for_each(op)
+    }
+}
+
+/// Everything on the calling thread.
+pub struct Sequential;
+
+impl Executor for Sequential {
+    fn join<FA, FB, RA, RB>(&self, a: FA, b: FB) -> (RA, RB)
+    where
+        FA: FnOnce() -> RA + Send,
+        FB: FnOnce() -> RB + Send,
+        RA: Send,
+        RB: Send,
+    {
+        (a(), b())
+    }
+
+    fn num_threads(&self) -> usize {
+        1
+    }
+
+    fn for_each<I, F>(&self, iter: I, op: F)
+    where
+        I: ParallelIterator + Send,
+        F: Fn(I::Item) + Sync + Send,
+    {
+        iter.to_sequential().for_each(op)
+    }
+}
+
+///
+/// Plain std scoped threads, for applications that do not want rayon threads.
+/// A join spawns a thread for its second task while fewer than `max_threads`
+/// threads (the caller included) are running, otherwise it runs both tasks itself.
+///
+pub struct ScopedThreads {
+    max_threads: usize,
+    running: AtomicUsize,
+}
+
+impl ScopedThreads {
+    pub fn new(max_threads: usize) -> Self {
+        ScopedThreads {
+            max_threads: max_threads.max(1),
+            running: AtomicUsize::new(1),
+        }
+    }
+}
+
+/// Gives back the thread of a join, even when one of its tasks panics.
+struct Running<'a>(&'a AtomicUsize);
+
+impl<'a> Drop for Running<'a> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Executor for ScopedThreads {
+    fn join<FA, FB, RA, RB>(&self, a: FA, b: FB) -> (RA, RB)
+    where
+        FA: FnOnce() -> RA + Send,
+        FB: FnOnce() -> RB + Send,
+        RA: Send,
+        RB: Send,
+    {
+        if self.running.fetch_add(1, Ordering::SeqCst) >= self.max_threads {
+            self.running.fetch_sub(1, Ordering::SeqCst);
+            return (a(), b());
+        }
+        let _spawned = Running(&self.running);
+        thread::scope(|scope| {
+            let handle = scope.spawn(b);
+            let ra = a();
+            match handle.join() {
+                Ok(rb) => (ra, rb),
+                Err(e) => std::panic::resume_unwind(e),
+            }
+        })
+    }
+
+    fn num_threads(&self) -> usize {
+        self.max_threads
+    }
+}
+
+#[cfg(test)]
+fn check_executor<E: Executor>(executor: &E) {
+    let size = 200;
+    let an = Array::from_shape_fn((size, size), |(i, j)| ((i * 7 + j) % 5) as f32);
+    let bn = Array::from_shape_fn((size, size), |(i, j)| ((i + j * 3) % 4) as f32);
+    let mut verif = Array::zeros((size, size));
+    linalg::general_mat_mul(1.0, &an, &bn, 1.0, &mut verif);
+
+    let mut dest = Array::zeros((size, size));
+    my_ndarray::mult_with(an.view(), bn.view(), dest.view_mut(), executor);
+    assert_eq!(dest, verif);
+
+    let mut dest = Array::zeros((size, size));
+    let (ddim1, ddim2) = dest.dim();
+    let m = matrix_adaptive::Matrix {
+        a: an.view(),
+        b: bn.view(),
+        d: dest.view_mut(),
+        asize: an.dim(),
+        bsize: bn.dim(),
+        dsize: (ddim1, ddim2),
+    };
+    executor.for_each(m, |e| {
+        let mut output = e.d;
+        linalg::general_mat_mul(1.0, &e.a, &e.b, 1.0, &mut output);
+    });
+    assert_eq!(dest, verif);
+
+    let n = 1 << 14;
+    let a: Vec<f32> = (0..n).map(|i| (i % 101) as f32).collect();
+    let b: Vec<f32> = (0..n).map(|i| (i % 101 + 7) as f32).collect();
+    let mut seqmul = vec![0f32; n];
+    rayon_mult::seq_matmulz(&a[..], &b[..], &mut seqmul[..]);
+    let mut rmul = vec![0f32; n];
+    rayon_mult::matmulz_with(&a[..], &b[..], &mut rmul[..], executor);
+    assert_eq!(rmul, seqmul);
+    let mut rmul = vec![0f32; n];
+    rayon_mult::matmul_strassen_with(&a[..], &b[..], &mut rmul[..], executor);
+    assert_eq!(rmul, seqmul);
+}
+
+#[test]
+fn test_executors() {
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(3)
+        .build()
+        .expect("Pool creation failed");
+    check_executor(&Global);
+    check_executor(&Pool(&pool));
+    check_executor(&Adaptive(Policy::Join(1000)));
+    check_executor(&Adaptive(Policy::Adaptive(100, 10_000)));
+    check_executor(&Sequential);
+    check_executor(&ScopedThreads::new(4));
+}
+
+#[test]
+fn test_scoped_threads_panic() {
+    let executor = ScopedThreads::new(2);
+    for _ in 0..3 {
+        let result = std::panic::catch_unwind(|| executor.join(|| 1, || panic!("task failed")));
+        assert!(result.is_err());
+        assert_eq!(executor.running.load(Ordering::SeqCst), 1);
+    }
+    assert_eq!(executor.join(|| 1, || 2), (1, 2));
+}
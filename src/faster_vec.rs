@@ -1,19 +1,126 @@
 use crate::my_ndarray;
+use crate::simd::SimdScalar;
+use crate::summation::{self, Summation};
 use faster::*;
 use smallvec::SmallVec;
 use std::iter;
+use std::ops::Add;
 use std::time::Instant;
 use ndarray::{ArrayView,ArrayViewMut,Ix2};
 use std::slice::{from_raw_parts, from_raw_parts_mut};
 
 
 ///
-/// into = a * b (f32)
+/// Element types faster vectorises, each one with its faster vector type
+/// (f32 -> f32s, u32 -> u32s, ...), so that multiply_add is written once for all of them.
+///
+pub trait FasterScalar: SimdScalar {
+    /// multiply_add with the vector type of the element.
+    fn faster_multiply_add(
+        into: &mut [Self],
+        a: &[Self],
+        b: &[Self],
+        awidth: usize,
+        aheight: usize,
+        bwidth: usize,
+        bheight: usize,
+        intowidth: usize,
+        intoheight: usize,
+        stridesa: usize,
+        stridesb: usize,
+        stridesinto: usize,
+    );
+}
+
+macro_rules! faster_scalar {
+    ($scalar:ty, $vector:ident, $zero:expr, $add:ident) => {
+        impl FasterScalar for $scalar {
+            #[inline(always)]
+            fn faster_multiply_add(
+                into: &mut [$scalar],
+                a: &[$scalar],
+                b: &[$scalar],
+                awidth: usize,
+                aheight: usize,
+                bwidth: usize,
+                bheight: usize,
+                intowidth: usize,
+                intoheight: usize,
+                stridesa: usize,
+                stridesb: usize,
+                stridesinto: usize,
+            ) {
+                assert_eq!(awidth, bheight);
+                assert_eq!(aheight, intoheight);
+                assert_eq!(bwidth, intowidth);
+                let h = intoheight;
+                let l = awidth;
+                let pads = iter::repeat($vector($zero))
+                    .take(stridesb)
+                    .collect::<SmallVec<[_; 512]>>();
+                let columns = b.simd_iter($vector($zero));
+                let columns = columns.stride_into::<SmallVec<[_; 512]>>(stridesb, &pads);
+                let mut column_data = iter::repeat($zero)
+                    .take(bheight)
+                    .collect::<SmallVec<[_; 512]>>();
+                for (x, mut column) in columns.into_iter().take(bwidth).enumerate() {
+                    column.scalar_fill(&mut column_data);
+                    for y in 0..h {
+                        let row = &a[(y * stridesa)..((y) * stridesa + l)];
+                        let index = (y * stridesinto) + x;
+                        let row = row.simd_iter($vector($zero));
+                        let dot = (row, column_data.simd_iter($vector($zero)))
+                            .zip()
+                            .simd_reduce($vector($zero), |acc, (a, b)| acc + a * b)
+                            .sum();
+                        into[index] = into[index].$add(dot);
+                    }
+                }
+            }
+        }
+    };
+}
+
+faster_scalar!(f32, f32s, 0.0, add);
+faster_scalar!(f64, f64s, 0.0, add);
+faster_scalar!(i32, i32s, 0, wrapping_add);
+faster_scalar!(u32, u32s, 0, wrapping_add);
+faster_scalar!(i64, i64s, 0, wrapping_add);
+faster_scalar!(u64, u64s, 0, wrapping_add);
+
+///
+/// into = a * b, for any FasterScalar element type
 /// This function only work with 2-dim matrix 
 /// 'strides*' is the number of element between 2 consecutive element of a same column in the GLOBAL matrix
 /// Indeed, this function is build for work with BLOCK of matrix 
 /// 
-pub fn multiply_add(
+#[inline(always)]
+pub fn multiply_add<A: FasterScalar>(
+    into: &mut [A],
+    a: &[A],
+    b: &[A],
+    awidth: usize,
+    aheight: usize,
+    bwidth: usize,
+    bheight: usize,
+    intowidth: usize,
+    intoheight: usize,
+    stridesa: usize,
+    stridesb: usize,
+    stridesinto: usize,
+) {
+    A::faster_multiply_add(
+        into, a, b, awidth, aheight, bwidth, bheight, intowidth, intoheight, stridesa, stridesb,
+        stridesinto,
+    )
+}
+
+///
+/// into = a * b (f32) with the scalar products accumulated with the given summation mode
+/// The products are computed with faster, only the reduction along k changes.
+/// Summation::Naive is the same as multiply_add.
+///
+pub fn multiply_add_with_summation(
     into: &mut [f32],
     a: &[f32],
     b: &[f32],
@@ -26,7 +133,15 @@ pub fn multiply_add(
     stridesa: usize,
     stridesb: usize,
     stridesinto: usize,
+    mode: Summation,
 ) {
+    if mode == Summation::Naive {
+        multiply_add(
+            into, a, b, awidth, aheight, bwidth, bheight, intowidth, intoheight, stridesa,
+            stridesb, stridesinto,
+        );
+        return;
+    }
     assert_eq!(awidth, bheight);
     assert_eq!(aheight, intoheight);
     assert_eq!(bwidth, intowidth);
@@ -40,24 +155,30 @@ pub fn multiply_add(
     let mut column_data = iter::repeat(0.0)
         .take(bheight)
         .collect::<SmallVec<[_; 512]>>();
+    // the products of a row and a column, overwritten for each output element
+    let mut products = column_data.clone();
     for (x, mut column) in columns.into_iter().take(bwidth).enumerate() {
         column.scalar_fill(&mut column_data);
         for y in 0..h {
             let row = &a[(y * stridesa)..((y) * stridesa + l)];
-            into[((y * stridesinto) + x)] +=
-                (row.simd_iter(f32s(0.)), column_data.simd_iter(f32s(0.)))
-                    .zip()
-                    .simd_reduce(f32s(0.0), |acc, (a, b)| acc + a * b)
-                    .sum();
+            (row.simd_iter(f32s(0.)), column_data.simd_iter(f32s(0.)))
+                .zip()
+                .simd_map(|(a, b)| a * b)
+                .scalar_fill(&mut products);
+            into[(y * stridesinto) + x] += summation::sum(&products, mode);
         }
     }
 }
 
-/// use for matrix multiplication f32
-/// function that use the ndarray representation and the vectorization of faster
+/// use for matrix multiplication, for any FasterScalar element type
+/// function that use the ndarray representation and multiply_add
 /// unsafe because we transform a raw pointer into a slice
 /// 
-pub fn mult_faster_from_ndarray(a: ArrayView<f32,Ix2> ,b: ArrayView<f32,Ix2>,output: &mut ArrayViewMut<f32,Ix2>) {
+#[inline(always)]
+pub fn mult_faster_from_ndarray<A: FasterScalar>(a: ArrayView<A,Ix2> ,b: ArrayView<A,Ix2>,output: &mut ArrayViewMut<A,Ix2>) {
+    if output.is_empty() {
+        return;
+    }
     let (raw_ptr_a, len_a) = my_ndarray::view_ptr(a);
     let stridesa = a.strides();
     let (raw_ptr_b, len_b) = my_ndarray::view_ptr(b);
@@ -88,12 +209,50 @@ pub fn mult_faster_from_ndarray(a: ArrayView<f32,Ix2> ,b: ArrayView<f32,Ix2>,out
                 );
 }
 
+///
+/// Same as mult_faster_from_ndarray with a selectable summation mode
+///
+pub fn mult_faster_from_ndarray_with_summation(
+    a: ArrayView<f32, Ix2>,
+    b: ArrayView<f32, Ix2>,
+    output: &mut ArrayViewMut<f32, Ix2>,
+    mode: Summation,
+) {
+    let (raw_ptr_a, len_a) = my_ndarray::view_ptr(a);
+    let stridesa = a.strides();
+    let (raw_ptr_b, len_b) = my_ndarray::view_ptr(b);
+    let stridesb = b.strides();
+    let raw_ptr_r = output.as_mut_ptr();
+    let dimr = output.shape();
+    let dima = a.shape();
+    let dimb = b.shape();
+    let (row, col) = (dimr[0], dimr[1]);
+    let strides = output.strides();
+    let len_r = (row - 1) * strides[0] as usize + col;
+    let slicea = unsafe { from_raw_parts(raw_ptr_a, len_a) };
+    let sliceb = unsafe { from_raw_parts(raw_ptr_b, len_b) };
+    let mut slicer = unsafe { from_raw_parts_mut(raw_ptr_r, len_r) };
+    multiply_add_with_summation(
+        &mut slicer,
+        &slicea,
+        &sliceb,
+        dima[1],
+        dima[0],
+        dimb[1],
+        dimb[0],
+        dimr[1],
+        dimr[0],
+        stridesa[0] as usize,
+        stridesb[0] as usize,
+        strides[0] as usize,
+        mode,
+    );
+}
+
 ///
 /// into = a * b (u32)
-/// This function only work with 2-dim matrix 
-/// 'strides*' is the number of element between 2 consecutive element of a same column in the GLOBAL matrix
-/// Indeed, this function is build for work with BLOCK of matrix 
 /// 
+#[deprecated(note = "multiply_add is generic over the element type")]
 pub fn multiply_add_u32(
     into: &mut [u32],
     a: &[u32],
@@ -108,54 +267,31 @@ pub fn multiply_add_u32(
     stridesb: usize,
     stridesinto: usize,
 ) {
-    assert_eq!(awidth, bheight);
-    assert_eq!(aheight, intoheight);
-    assert_eq!(bwidth, intowidth);
-
-    let h = intoheight;
-    let l = awidth;
-
-    let pads = iter::repeat(u32s(0))
-        .take(stridesb)
-        .collect::<SmallVec<[_; 512]>>();
-    let columns = b.simd_iter(u32s(0));
-    let columns = columns.stride_into::<SmallVec<[_; 512]>>(stridesb, &pads);
-    let mut column_data = iter::repeat(0u32)
-        .take(bheight)
-        .collect::<SmallVec<[_; 512]>>();
-    for (x, mut column) in columns.into_iter().take(bwidth).enumerate() {
-        column.scalar_fill(&mut column_data);
-        for y in 0..h {
-            let row = &a[(y * stridesa)..((y) * stridesa + l)];
-            into[((y * stridesinto) + x)] +=
-                (row.simd_iter(u32s(0)), column_data.simd_iter(u32s(0)))
-                    .zip()
-                    .simd_reduce(u32s(0), |acc, (a, b)| acc + a * b)
-                    .sum();
-        }
-    }
+    multiply_add(
+        into, a, b, awidth, aheight, bwidth, bheight, intowidth, intoheight, stridesa, stridesb,
+        stridesinto,
+    )
 }
 
 
 ///
-/// Debug function for print the time of a square matrix mult f32
+/// Debug function for print the time of a square matrix mult
 /// if power2 then the size of the matrix will be the next power of 2.
 /// the string will be print
 /// 
-pub fn timed_matmul(size: usize, name: &str, power2: bool) -> u64 {
+pub fn timed_matmul<A: FasterScalar + num_traits::NumCast>(
+    size: usize,
+    name: &str,
+    power2: bool,
+) -> u64 {
     let mut size = size;
     if power2 {
         size = size.next_power_of_two();
     }
     let n = size * size;
-    let mut a = vec![0f32; n];
-    let mut b = vec![0f32; n];
-
-    for i in 0..n {
-        a[i] = i as f32;
-        b[i] = (i + 7) as f32;
-    }
-    let mut dest = vec![0f32; n];
+    let a: Vec<A> = (0..n).map(|i| num_traits::cast(i).unwrap()).collect();
+    let b: Vec<A> = (0..n).map(|i| num_traits::cast(i + 7).unwrap()).collect();
+    let mut dest = vec![A::zero(); n];
 
     let start = Instant::now();
     multiply_add(
@@ -175,37 +311,8 @@ pub fn timed_matmul(size: usize, name: &str, power2: bool) -> u64 {
 
 ///
 /// Debug function for print the time of a square matrix mult u32
-/// if power2 then the size of the matrix will be the next power of 2.
-/// the string will be print
 /// 
+#[deprecated(note = "timed_matmul is generic over the element type")]
 pub fn timed_matmul_u32(size: usize, name: &str, power2: bool) -> u64 {
-    let mut size = size;
-    if power2 {
-        size = size.next_power_of_two();
-    }
-    let n = size * size;
-    let mut a = vec![0u32; n];
-    let mut b = vec![0u32; n];
-    for i in 0..n {
-        a[i] = i as u32;
-        b[i] = (i + 7) as u32;
-    }
-    let mut dest = vec![0u32; n];
-    let a1 = a.as_slice();
-    let b1 = b.as_slice();
-
-    let start = Instant::now();
-    multiply_add_u32(
-        &mut dest, a1, b1, size, size, size, size, size, size, size, size, size,
-    );
-    let dur = Instant::now() - start;
-    let nanos = u64::from(dur.subsec_nanos()) + dur.as_secs() * 1_000_000_000u64;
-    println!(
-        "{}:\t{}x{} matrix: {} s",
-        name,
-        size,
-        size,
-        nanos as f32 / 1e9f32
-    );
-    nanos
-}
\ No newline at end of file
+    timed_matmul::<u32>(size, name, power2)
+}
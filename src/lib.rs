@@ -2,12 +2,35 @@
 extern crate approx;
 
 pub mod benchmark;
+pub mod block_grid;
+pub mod cache_oblivious;
+#[cfg(feature = "cblas")]
+pub mod cblas;
+#[cfg(all(feature = "cblas-alias", feature = "openblas"))]
+compile_error!("cblas-alias exports the BLAS symbols of openblas: build with --no-default-features");
+pub mod dispatch;
+pub mod division;
+pub mod executor;
 pub mod faster_vec;
 pub mod matrix;
 pub mod matrix_adaptive;
+pub mod matrix_io;
+pub mod matrix_market;
+pub mod mixed_precision;
 pub mod my_ndarray;
 pub mod naive_sequential;
+pub mod npy;
+pub mod progress;
 pub mod rayon_mult;
+pub mod regression;
+pub mod report;
+pub mod reproducible;
+pub mod roofline;
+pub mod simd;
 pub mod split;
+pub mod stats;
+pub mod summation;
+pub mod testing;
+pub mod trace;
 pub mod vectorisation;
-pub mod vectorisation_packed_simd;
\ No newline at end of file
+pub mod vectorisation_packed_simd;
@@ -1,32 +1,565 @@
-extern crate nalgebra as na;
-#[macro_use]
-extern crate approx;
+use matrix_mult::report::{self, BenchResult};
+use matrix_mult::{
+    dispatch, matrix_adaptive, matrix_io, my_ndarray, naive_sequential, rayon_mult, regression,
+    trace,
+};
+use ndarray::{linalg, Array, ArrayView, ArrayViewMut, Ix2};
+use rayon_adaptive::prelude::*;
+use rayon_adaptive::Policy;
+use std::env;
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::Instant;
 
-use na::{Matrix4, Matrix4x3, Vector4};
+const USAGE: &str = "
+Usage: matmul bench [--size N]... [--samples S] [--threads N] [--output R]
+       matmul compare R [--samples S] [--threshold T] [--alpha A] [--output R2]
+       matmul multiply A B [-o C] [--kernel K] [--policy P] [--threads N] [--trace T]
+       matmul --help
+Parallel matrix multiplication.
+Commands:
+    bench           Run the benchmark in different modes and print the timings.
+    compare         Run again the configurations of the results file R and compare
+                    the mean times. Exits with status 1 if one is slower by more
+                    than the threshold, significantly (Welch's t-test).
+    multiply        Multiply the matrices of files A and B and write the product.
+                    '-' is the standard input or output, files ending in .mtx
+                    are Matrix Market, .npy/.npz NumPy, the others text
+                    ('rows cols' then the rows).
+Options:
+    --size N        Row-size of matrices (rounded up to power of 2) [default: 1024]
+                    Repeat the option (or give N1,N2,...) to run several sizes.
+    --samples S     Runs of each algorithm, the median is displayed [default: 1,
+                    for compare: as many as in R]
+    --threshold T   Relative slowdown reported as a regression [default: 0.05]
+    --alpha A       Significance level of the t-test [default: 0.05]
+    --output R      Write the results to R, JSON if it ends in .json, CSV otherwise.
+    -o, --output C  Product file [default: -]
+    --kernel K      Sequential kernel of the leaves: blas, naive, faster, simd [default: blas]
+                    faster and simd use the best instruction set of the CPU,
+                    MATRIX_MULT_ISA=sse2|avx|avx2|avx512 forces one.
+    --policy P      Scheduling: adaptive, join, rayon, sequential [default: adaptive]
+    --threads N     Number of threads [default: one per core]
+    --trace T       Write the divide and leaf tasks of the multiplication to T,
+                    Chrome trace event JSON (chrome://tracing, Perfetto).
+    -h, --help      Show this message.
+";
+
+/// The naive row-major algorithm takes several minutes on larger inputs.
+const MAX_ROW_MAJOR_SIZE: usize = 1024;
+const MAX_Z_ORDER_SIZE: usize = 2048;
+
+/// The algorithms run by `matmul bench`, as (kernel, policy).
+const ALGORITHMS: [(&str, &str); 9] = [
+    ("row-major", "sequential"),
+    ("z-order", "sequential"),
+    ("z-order", "recursive"),
+    ("strassen", "recursive"),
+    ("blas", "recursive"),
+    ("blas", "sequential"),
+    ("blas", "join"),
+    ("blas", "rayon"),
+    ("blas", "adaptive"),
+];
+
+/// Speedups are relative to the sequential z-order algorithm.
+const BASELINE: (&str, &str) = ("z-order", "sequential");
+
+struct BenchOptions {
+    sizes: Vec<usize>,
+    samples: usize,
+    threads: Option<usize>,
+    output: Option<PathBuf>,
+}
+
+#[derive(Clone, Copy)]
+enum Kernel {
+    Blas,
+    Naive,
+    Faster,
+    Simd,
+}
+
+#[derive(Clone, Copy)]
+enum Scheduling {
+    Adaptive,
+    Join,
+    Rayon,
+    Sequential,
+}
+
+struct CompareOptions {
+    baseline: PathBuf,
+    samples: Option<usize>,
+    threshold: f64,
+    alpha: f64,
+    output: Option<PathBuf>,
+}
+
+struct MultiplyOptions {
+    a: PathBuf,
+    b: PathBuf,
+    output: PathBuf,
+    kernel: Kernel,
+    scheduling: Scheduling,
+    threads: Option<usize>,
+    trace: Option<PathBuf>,
+}
+
+fn parse_size(value: Option<&String>) -> Result<Vec<usize>, String> {
+    let value = value.ok_or_else(|| "--size needs a value".to_owned())?;
+    value
+        .split(',')
+        .map(|s| match s.parse::<usize>() {
+            Ok(size) if size > 0 => Ok(size),
+            _ => Err(format!("invalid size '{}'", s)),
+        })
+        .collect()
+}
+
+fn parse_positive(option: &str, value: Option<&String>) -> Result<usize, String> {
+    let value = value.ok_or_else(|| format!("{} needs a value", option))?;
+    match value.parse::<usize>() {
+        Ok(n) if n > 0 => Ok(n),
+        _ => Err(format!("invalid {} value '{}'", option, value)),
+    }
+}
+
+fn parse_bench(args: &[String]) -> Result<BenchOptions, String> {
+    let mut sizes = Vec::new();
+    let mut samples = 1;
+    let mut threads = None;
+    let mut output = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--size" => sizes.extend(parse_size(args.next())?),
+            "--samples" => samples = parse_positive(arg, args.next())?,
+            "--threads" => threads = Some(parse_positive(arg, args.next())?),
+            "--output" => {
+                let file = args.next().ok_or_else(|| "--output needs a value".to_owned())?;
+                output = Some(PathBuf::from(file));
+            }
+            _ => return Err(format!("unknown option '{}'", arg)),
+        }
+    }
+    if sizes.is_empty() {
+        sizes.push(1024);
+    }
+    Ok(BenchOptions {
+        sizes,
+        samples,
+        threads,
+        output,
+    })
+}
+
+fn parse_fraction(option: &str, value: Option<&String>) -> Result<f64, String> {
+    let value = value.ok_or_else(|| format!("{} needs a value", option))?;
+    match value.parse::<f64>() {
+        Ok(x) if x >= 0.0 && x.is_finite() => Ok(x),
+        _ => Err(format!("invalid {} value '{}'", option, value)),
+    }
+}
+
+fn parse_compare(args: &[String]) -> Result<CompareOptions, String> {
+    let mut baseline = None;
+    let mut samples = None;
+    let mut threshold = 0.05;
+    let mut alpha = 0.05;
+    let mut output = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--samples" => samples = Some(parse_positive(arg, args.next())?),
+            "--threshold" => threshold = parse_fraction(arg, args.next())?,
+            "--alpha" => alpha = parse_fraction(arg, args.next())?,
+            "--output" => {
+                let file = args.next().ok_or_else(|| "--output needs a value".to_owned())?;
+                output = Some(PathBuf::from(file));
+            }
+            file if !file.starts_with('-') && baseline.is_none() => {
+                baseline = Some(PathBuf::from(file))
+            }
+            _ => return Err(format!("unknown option '{}'", arg)),
+        }
+    }
+    Ok(CompareOptions {
+        baseline: baseline.ok_or_else(|| "compare needs a results file".to_owned())?,
+        samples,
+        threshold,
+        alpha,
+        output,
+    })
+}
+
+fn parse_multiply(args: &[String]) -> Result<MultiplyOptions, String> {
+    let mut files = Vec::new();
+    let mut output = PathBuf::from("-");
+    let mut kernel = Kernel::Blas;
+    let mut scheduling = Scheduling::Adaptive;
+    let mut threads = None;
+    let mut trace = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .map(String::as_str)
+                .ok_or_else(|| format!("{} needs a value", arg))
+        };
+        match arg.as_str() {
+            "-o" | "--output" => output = PathBuf::from(value()?),
+            "--kernel" => {
+                kernel = match value()? {
+                    "blas" => Kernel::Blas,
+                    "naive" => Kernel::Naive,
+                    "faster" => Kernel::Faster,
+                    "simd" => Kernel::Simd,
+                    other => return Err(format!("unknown kernel '{}'", other)),
+                }
+            }
+            "--policy" => {
+                scheduling = match value()? {
+                    "adaptive" => Scheduling::Adaptive,
+                    "join" => Scheduling::Join,
+                    "rayon" => Scheduling::Rayon,
+                    "sequential" => Scheduling::Sequential,
+                    other => return Err(format!("unknown policy '{}'", other)),
+                }
+            }
+            "--threads" => threads = Some(parse_positive(arg, args.next())?),
+            "--trace" => trace = Some(PathBuf::from(value()?)),
+            file if file == "-" || !file.starts_with('-') => files.push(PathBuf::from(file)),
+            _ => return Err(format!("unknown option '{}'", arg)),
+        }
+    }
+    if files.len() != 2 {
+        return Err("multiply needs two input files".to_owned());
+    }
+    let b = files.pop().unwrap();
+    let a = files.pop().unwrap();
+    if a == PathBuf::from("-") && b == PathBuf::from("-") {
+        return Err("only one input can be the standard input".to_owned());
+    }
+    Ok(MultiplyOptions {
+        a,
+        b,
+        output,
+        kernel,
+        scheduling,
+        threads,
+        trace,
+    })
+}
+
+/// Run `f` once and return its duration in nanoseconds.
+fn time_ns<F: FnOnce()>(f: F) -> u64 {
+    let start = Instant::now();
+    f();
+    let dur = Instant::now() - start;
+    u64::from(dur.subsec_nanos()) + dur.as_secs() * 1_000_000_000u64
+}
+
+fn adaptive(a: ArrayView<f32, Ix2>, b: ArrayView<f32, Ix2>, policy: Policy) -> u64 {
+    let mut dest = Array::zeros((a.rows(), b.cols()));
+    let (ddim1, ddim2) = dest.dim();
+    let mat = matrix_adaptive::Matrix {
+        a,
+        b,
+        d: dest.view_mut(),
+        asize: a.dim(),
+        bsize: b.dim(),
+        dsize: (ddim1, ddim2),
+    };
+    time_ns(|| {
+        mat.with_policy(policy).for_each(|mut e| {
+            linalg::general_mat_mul(1.0, &e.a, &e.b, 1.0, &mut e.d);
+        })
+    })
+}
+
+///
+/// Time one multiplication of two n x n matrices (n a power of two) by an algorithm of ALGORITHMS.
+/// None for the algorithms too slow at that size.
+///
+fn run_algorithm(kernel: &str, policy: &str, n: usize, a: &[f32], b: &[f32]) -> Option<u64> {
+    let an = ArrayView::from_shape((n, n), a).expect("square matrix");
+    let bn = ArrayView::from_shape((n, n), b).expect("square matrix");
+    let slice_kernel = |f: fn(&[f32], &[f32], &mut [f32])| {
+        let mut dest = vec![0f32; n * n];
+        time_ns(|| f(a, b, &mut dest))
+    };
+    let size = (n * n) as f32;
+    match (kernel, policy) {
+        ("row-major", "sequential") if n <= MAX_ROW_MAJOR_SIZE => {
+            Some(slice_kernel(rayon_mult::seq_matmul))
+        }
+        ("z-order", "sequential") if n <= MAX_Z_ORDER_SIZE => {
+            Some(slice_kernel(rayon_mult::seq_matmulz))
+        }
+        ("z-order", "recursive") => Some(slice_kernel(rayon_mult::matmulz)),
+        ("strassen", "recursive") => Some(slice_kernel(rayon_mult::matmul_strassen)),
+        ("blas", "recursive") => {
+            let mut dest = Array::zeros((n, n));
+            Some(time_ns(|| {
+                my_ndarray::mult(an, bn, dest.view_mut());
+            }))
+        }
+        ("blas", "sequential") => Some(adaptive(an, bn, Policy::Sequential)),
+        ("blas", "join") => Some(adaptive(an, bn, Policy::Join(n * n / 64 + 1))),
+        ("blas", "rayon") => Some(adaptive(an, bn, Policy::Rayon(1))),
+        ("blas", "adaptive") => Some(adaptive(
+            an,
+            bn,
+            Policy::Adaptive(size.log2() as usize, size.sqrt() as usize),
+        )),
+        _ => None,
+    }
+}
+
+/// The inputs of the benchmarks, n x n row major.
+fn bench_inputs(n: usize) -> (Vec<f32>, Vec<f32>) {
+    let a = (0..n * n).map(|i| (i % 7) as f32).collect();
+    let b = (0..n * n).map(|i| ((i + 7) % 5) as f32).collect();
+    (a, b)
+}
+
+///
+/// Run an algorithm `samples` times on n x n matrices, None if it does not run at this size.
+///
+fn bench_algorithm(
+    kernel: &str,
+    policy: &str,
+    n: usize,
+    samples: usize,
+    inputs: &(Vec<f32>, Vec<f32>),
+) -> Option<BenchResult> {
+    let threads = if policy == "sequential" {
+        1
+    } else {
+        rayon::current_num_threads()
+    };
+    let times = (0..samples)
+        .map(|_| run_algorithm(kernel, policy, n, &inputs.0, &inputs.1).map(|t| t as f64))
+        .collect::<Option<Vec<f64>>>()?;
+    Some(BenchResult::new(kernel, policy, (n, n, n), threads, &times))
+}
+
+fn bench_size(size: usize, samples: usize) -> Vec<BenchResult> {
+    let n = size.next_power_of_two();
+    let inputs = bench_inputs(n);
+    let runs: Vec<(String, Option<BenchResult>)> = ALGORITHMS
+        .iter()
+        .map(|&(kernel, policy)| {
+            let name = format!("{} {}", kernel, policy);
+            (name, bench_algorithm(kernel, policy, n, samples, &inputs))
+        })
+        .collect();
+    let baseline = runs
+        .iter()
+        .filter_map(|(_, r)| r.as_ref())
+        .find(|r| (r.kernel.as_str(), r.policy.as_str()) == BASELINE)
+        .map(|r| r.times.median);
+    println!("{}x{} matrix:", n, n);
+    for (name, result) in &runs {
+        match result {
+            None => println!("  {:<20} skipped", name),
+            Some(result) => {
+                let speedup = baseline
+                    .map(|seq| format!("{:.2}x", seq / result.times.median))
+                    .unwrap_or_else(|| "-".to_owned());
+                println!(
+                    "  {:<20} {:>10.4} s  {:>8.2} GFLOP/s  speedup: {}",
+                    name,
+                    result.times.median / 1e9,
+                    result.gflops(),
+                    speedup
+                );
+            }
+        }
+    }
+    runs.into_iter().filter_map(|(_, r)| r).collect()
+}
+
+/// In a pool of `threads` threads if given, the global pool otherwise.
+fn with_threads<R: Send, F: FnOnce() -> R + Send>(threads: Option<usize>, op: F) -> Result<R, String> {
+    match threads {
+        Some(threads) => {
+            let pool = rayon::ThreadPoolBuilder::new()
+                .num_threads(threads)
+                .build()
+                .map_err(|e| e.to_string())?;
+            Ok(pool.install(op))
+        }
+        None => Ok(op()),
+    }
+}
+
+fn write_results(path: &Path, results: &[BenchResult]) -> Result<(), String> {
+    let file = File::create(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let written = if path.extension().map_or(false, |e| e == "json") {
+        report::write_json(BufWriter::new(file), results)
+    } else {
+        report::write_csv(BufWriter::new(file), results)
+    };
+    written.map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+fn bench(options: &BenchOptions) -> Result<(), String> {
+    let results = with_threads(options.threads, || {
+        options
+            .sizes
+            .iter()
+            .flat_map(|&size| bench_size(size, options.samples))
+            .collect::<Vec<BenchResult>>()
+    })?;
+    match options.output {
+        Some(ref path) => write_results(path, &results),
+        None => Ok(()),
+    }
+}
+
+///
+/// Run again the configuration of a previous result, None if `matmul bench` has no such
+/// configuration.
+///
+fn rerun(baseline: &BenchResult, samples: Option<usize>) -> Result<Option<BenchResult>, String> {
+    let (m, n, k) = baseline.shape;
+    if m != n || n != k || !n.is_power_of_two() {
+        return Ok(None);
+    }
+    let samples = samples.unwrap_or(baseline.times.samples);
+    // sequential results are recorded with one thread whatever the pool
+    let threads = if baseline.policy == "sequential" {
+        None
+    } else {
+        Some(baseline.threads)
+    };
+    with_threads(threads, || {
+        bench_algorithm(&baseline.kernel, &baseline.policy, n, samples, &bench_inputs(n))
+    })
+}
+
+/// True if a configuration regressed.
+fn compare(options: &CompareOptions) -> Result<bool, String> {
+    let baseline = report::load(&options.baseline)
+        .map_err(|e| format!("{}: {}", options.baseline.display(), e))?;
+    let mut current = Vec::new();
+    for result in &baseline {
+        match rerun(result, options.samples)? {
+            Some(result) => current.push(result),
+            None => eprintln!(
+                "matmul: skipping unknown configuration {} {} {:?}",
+                result.kernel, result.policy, result.shape
+            ),
+        }
+    }
+    let comparisons = regression::compare(&baseline, &current);
+    regression::write_table(io::stdout(), &comparisons, options.threshold, options.alpha)
+        .map_err(|e| e.to_string())?;
+    if let Some(ref path) = options.output {
+        write_results(path, &current)?;
+    }
+    Ok(comparisons
+        .iter()
+        .any(|c| c.is_regression(options.threshold, options.alpha)))
+}
+
+fn leaf(kernel: Kernel, a: ArrayView<f32, Ix2>, b: ArrayView<f32, Ix2>, mut d: ArrayViewMut<f32, Ix2>) {
+    if a.is_empty() || b.is_empty() || d.is_empty() {
+        return;
+    }
+    match kernel {
+        Kernel::Blas => linalg::general_mat_mul(1.0, &a, &b, 1.0, &mut d),
+        Kernel::Naive => naive_sequential::mult_index_optimized(a, b, d),
+        Kernel::Faster => dispatch::mult_faster(a, b, &mut d),
+        Kernel::Simd => dispatch::mult_packed_simd(a, b, &mut d),
+    }
+}
+
+fn multiply(options: &MultiplyOptions) -> Result<(), String> {
+    let load = |path: &PathBuf| {
+        matrix_io::load::<f32>(path).map_err(|e| format!("{}: {}", path.display(), e))
+    };
+    let a = load(&options.a)?;
+    let b = load(&options.b)?;
+    if a.cols() != b.rows() {
+        return Err(format!(
+            "cannot multiply a {}x{} matrix by a {}x{} matrix",
+            a.rows(),
+            a.cols(),
+            b.rows(),
+            b.cols()
+        ));
+    }
+    let mut product = Array::zeros((a.rows(), b.cols()));
+    let mut builder = rayon::ThreadPoolBuilder::new();
+    if let Some(threads) = options.threads {
+        builder = builder.num_threads(threads);
+    }
+    let pool = builder.build().map_err(|e| e.to_string())?;
+    let size = (a.rows() * b.cols()).max(1);
+    let policy = match options.scheduling {
+        Scheduling::Adaptive => {
+            let size = size as f64;
+            Policy::Adaptive(size.log2() as usize + 1, size.sqrt() as usize + 1)
+        }
+        Scheduling::Join => Policy::Join(size / (4 * pool.current_num_threads()) + 1),
+        Scheduling::Rayon => Policy::Rayon(1),
+        Scheduling::Sequential => Policy::Sequential,
+    };
+    let kernel = options.kernel;
+    let (ddim1, ddim2) = product.dim();
+    let mat = matrix_adaptive::Matrix {
+        a: a.view(),
+        b: b.view(),
+        d: product.view_mut(),
+        asize: a.dim(),
+        bsize: b.dim(),
+        dsize: (ddim1, ddim2),
+    };
+    let run = || {
+        pool.install(|| {
+            mat.with_policy(policy)
+                .for_each(|e| leaf(kernel, e.a, e.b, e.d))
+        })
+    };
+    match options.trace {
+        Some(ref path) => {
+            let ((), tasks) = trace::record(run);
+            File::create(path)
+                .and_then(|file| tasks.write_chrome_json(BufWriter::new(file)))
+                .map_err(|e| format!("{}: {}", path.display(), e))?;
+        }
+        None => run(),
+    }
+    matrix_io::save(&options.output, product.view())
+        .map_err(|e| format!("{}: {}", options.output.display(), e))
+}
 
 fn main() {
-    let a = Matrix4::new(
-        1.0, 1.0, 2.0, -5.0, 2.0, 5.0, -1.0, -9.0, 2.0, 1.0, -1.0, 3.0, 1.0, 3.0, 2.0, 7.0,
-    );
-    let mut b = Vector4::new(3.0, -3.0, -11.0, -5.0);
-    let decomp = a.lu();
-    let x = decomp.solve(&b).expect("Linear resolution failed.");
-    assert_relative_eq!(a * x, b);
-    /*
-     * It is possible to perform the resolution in-place.
-     * This is particularly useful to avoid allocations when
-     * `b` is a `DVector` or a `DMatrix`.
-     */
-    assert!(decomp.solve_mut(&mut b), "Linear resolution failed.");
-    assert_relative_eq!(x, b);
-
-    /*
-     * It is possible to solve multiple systems
-     * simultaneously by using a matrix for `b`.
-     */
-    let b = Matrix4x3::new(
-        3.0, 2.0, 0.0, -3.0, 0.0, 0.0, -11.0, 5.0, -3.0, -5.0, 10.0, 4.0,
-    );
-    let x = decomp.solve(&b).expect("Linear resolution failed.");
-    assert_relative_eq!(a * x, b);
+    let args: Vec<String> = env::args().skip(1).collect();
+    let result = match args.first().map(String::as_str) {
+        Some("bench") => parse_bench(&args[1..]).and_then(|options| bench(&options)),
+        Some("compare") => parse_compare(&args[1..]).and_then(|options| {
+            let regressed = compare(&options)?;
+            if regressed {
+                process::exit(1);
+            }
+            Ok(())
+        }),
+        Some("multiply") => parse_multiply(&args[1..]).and_then(|options| multiply(&options)),
+        Some("-h") | Some("--help") => {
+            print!("{}", USAGE);
+            Ok(())
+        }
+        Some(command) => Err(format!("unknown command '{}'", command)),
+        None => Err("missing command".to_owned()),
+    };
+    if let Err(message) = result {
+        eprintln!("matmul: {}\n{}", message, USAGE);
+        process::exit(2);
+    }
 }
@@ -1,4 +1,6 @@
+use crate::division::{DivideWith, DivisionStrategy, PowerOfTwoFriendly};
 use crate::my_ndarray;
+use crate::trace::{self, Traced};
 use ndarray::Ix2;
 use ndarray::LinalgScalar;
 #[cfg(test)]
@@ -8,6 +10,7 @@ use ndarray::{ArrayView, ArrayViewMut};
 use rand::Rng;
 use rayon_adaptive::prelude::*;
 use rayon_adaptive::BasicPower;
+use std::iter::{once, Once};
 #[cfg(test)]
 use rayon_adaptive::Policy;
 
@@ -33,8 +36,20 @@ where
         )
     }
 
+    // quadrants: the index is not used
     #[allow(unused_variables)]
-    fn divide_at(mut self, index: usize) -> (Self, Self) {
+    fn divide_at(self, index: usize) -> (Self, Self) {
+        self.divide_with(&PowerOfTwoFriendly)
+    }
+}
+
+impl<'a, 'b, 'd, A> DivideWith for Matrix<'a, 'b, 'd, A>
+where
+    A: LinalgScalar + Send + Sync,
+{
+    /// Cut the output blocks in quadrants where the strategy says.
+    fn divide_with<S: DivisionStrategy>(mut self, strategy: &S) -> (Self, Self) {
+        let _span = trace::span("matrix", "divide");
         if self.matrix.is_empty() {
             let other: Vec<(
                 Vec<(ArrayView<A, Ix2>, ArrayView<A, Ix2>)>,
@@ -52,7 +67,7 @@ where
                     ArrayViewMut<A, Ix2>,
                 )> = Vec::new();
                 let sub_matrix = self.matrix.pop().unwrap();
-                let (v1, v2, v3, v4) = divide_matrix(sub_matrix);
+                let (v1, v2, v3, v4) = divide_matrix(sub_matrix, strategy);
                 copy_self.push(v1);
                 copy_self.push(v2);
                 other.push(v3);
@@ -64,13 +79,59 @@ where
             }
         }
     }
+
+    #[allow(unused_variables)]
+    fn divide_at_with<S: DivisionStrategy>(self, index: usize, strategy: &S) -> (Self, Self) {
+        self.divide_with(strategy)
+    }
+
+    fn extract_iter_with<S: DivisionStrategy>(
+        &mut self,
+        size: usize,
+        strategy: &S,
+    ) -> <Self as ParallelIterator>::SequentialIterator {
+        let whole = Matrix {
+            matrix: std::mem::take(&mut self.matrix),
+        };
+        let leaf = if whole.base_length().unwrap_or(0) <= size.max(1) {
+            whole
+        } else {
+            let (left, right) = whole.divide_with(strategy);
+            *self = right;
+            left
+        };
+        Traced::new(once(leaf), "matrix", "leaf")
+    }
+}
+
+///
+/// The leaves: each matrix of the division, traced as one leaf task until the caller
+/// is done with it (as matrix_adaptive::Matrix). The leaves of `.cut()` are not traced.
+///
+impl<'a, 'b, 'd, A> ParallelIterator for Matrix<'a, 'b, 'd, A>
+where
+    A: LinalgScalar + Send + Sync,
+{
+    type Item = Self;
+
+    type SequentialIterator = Traced<Once<Self>>;
+
+    fn to_sequential(self) -> Self::SequentialIterator {
+        Traced::new(once(self), "matrix", "leaf")
+    }
+
+    // The whole matrix if it is small enough, else the first part of a division.
+    fn extract_iter(&mut self, size: usize) -> Self::SequentialIterator {
+        self.extract_iter_with(size, &PowerOfTwoFriendly)
+    }
 }
 
-fn divide_matrix<'a, 'b, 'c, A>(
+fn divide_matrix<'a, 'b, 'c, A, S>(
     mut sub_matrix: (
         Vec<(ArrayView<'a, A, Ix2>, ArrayView<'b, A, Ix2>)>,
         ArrayViewMut<'c, A, Ix2>,
     ),
+    strategy: &S,
 ) -> (
     (
         Vec<(ArrayView<'a, A, Ix2>, ArrayView<'b, A, Ix2>)>,
@@ -91,16 +152,17 @@ fn divide_matrix<'a, 'b, 'c, A>(
 )
 where
     A: LinalgScalar + Send + Sync,
+    S: DivisionStrategy,
 {
     let mut r1: Vec<(ArrayView<A, Ix2>, ArrayView<A, Ix2>)> = Vec::new();
     let mut r2: Vec<(ArrayView<A, Ix2>, ArrayView<A, Ix2>)> = Vec::new();
     let mut r3: Vec<(ArrayView<A, Ix2>, ArrayView<A, Ix2>)> = Vec::new();
     let mut r4: Vec<(ArrayView<A, Ix2>, ArrayView<A, Ix2>)> = Vec::new();
-    let (d1, d2, d3, d4) = my_ndarray::divide_power2_friendly_mut(sub_matrix.1);
+    let (d1, d2, d3, d4) = my_ndarray::divide_mut_with(sub_matrix.1, strategy);
     while !sub_matrix.0.is_empty() {
         let (a, b) = sub_matrix.0.pop().unwrap();
-        let (a1, a2, a3, a4) = my_ndarray::divide_power2_friendly(a);
-        let (b1, b2, b3, b4) = my_ndarray::divide_power2_friendly(b);
+        let (a1, a2, a3, a4) = my_ndarray::divide_with(a, strategy);
+        let (b1, b2, b3, b4) = my_ndarray::divide_with(b, strategy);
         r1.push((a1, b1));
         r1.push((a2, b3));
         r2.push((a1, b2));
@@ -1,99 +1,78 @@
+use crate::division::{DivideWith, DivisionStrategy, PowerOfTwoFriendly};
 use crate::my_ndarray;
-use ndarray::Ix2;
-use ndarray::LinalgScalar;
+use crate::reproducible;
+use crate::simd::SimdScalar;
+use crate::split::split;
+use crate::trace::{self, Traced};
+use ndarray::{Ix2, LinalgScalar};
 #[cfg(test)]
-use ndarray::{linalg, Array};
-use ndarray::{ArrayView, ArrayViewMut, Axis,ShapeBuilder};
+use ndarray::linalg;
+use ndarray::{Array, ArrayView, ArrayViewMut, Axis};
 #[cfg(test)]
 use rand::Rng;
 use rayon_adaptive::prelude::*;
-use rayon_adaptive::IndexedPower;
+use rayon_adaptive::{IndexedPower, Policy};
 use std::iter::{once, Once};
 
-#[cfg(test)]
-use rayon_adaptive::Policy;
-
-pub struct Matrix<'a, 'b, 'd, A> {
+/// The output element type D defaults to the input one,
+/// it only differs for mixed precision (16-bit inputs, f32 output).
+pub struct Matrix<'a, 'b, 'd, A, D = A> {
     pub a: ArrayView<'a, A, Ix2>,
     pub b: ArrayView<'b, A, Ix2>,
-    pub d: ArrayViewMut<'d, A, Ix2>,
+    pub d: ArrayViewMut<'d, D, Ix2>,
     pub asize : (usize,usize),
     pub bsize : (usize,usize),
     pub dsize : (usize,usize),
 }
 
-impl<'a, 'b, 'd, A> Divisible for Matrix<'a, 'b, 'd, A>
+impl<'a, 'b, 'd, A> Matrix<'a, 'b, 'd, A>
 where
-    A: LinalgScalar + Send + Sync,
+    A: SimdScalar,
+{
+    ///
+    /// Deterministic mode: d += a * b, bitwise identical whatever the policy, the number of
+    /// threads or the steals.
+    /// The divisions and extractions only cut the output, never k, and each leaf adds the
+    /// products of an element in increasing k order (reproducible::mult_ordered), so the
+    /// k-reduction order of every element is fixed.
+    ///
+    pub fn mult_deterministic(self, policy: Policy) {
+        self.with_policy(policy)
+            .for_each(|e| reproducible::mult_ordered(e.a, e.b, e.d));
+    }
+}
+
+impl<'a, 'b, 'd, A, D> Divisible for Matrix<'a, 'b, 'd, A, D>
+where
+    A: Send + Sync,
+    D: Send + Sync,
 {
     // Can be changed to IndexedPower, need to change divide and divide_mut of my_ndarray
     type Power = IndexedPower;
 
     fn base_length(&self) -> Option<usize> {
         let dim = self.d.shape();
-        let dima = self.a.shape();
-        if dima[1]<=1 {
-            Some(1)
-        }
-        else {
-            Some(dim[0] * dim[1])
-        }
+        Some(dim[0] * dim[1])
     }
 
-    fn divide(self) -> (Self,Self) {
-        
-        let mut axis = 0;
-        let (row, col) = self.d.dim();
-        if col > row {
-            axis = 1;
-        }
-
-        if axis == 0 {
-            let mut r2 = row;
-             if !row.is_power_of_two() {
-                r2 = row.next_power_of_two();
-            }
-            let rowbef =  r2 / 4;
-            let rownext = r2 / 2;
-            let diff1 = row / 2 - rowbef;
-            let diff2 = rownext - row / 2;
-            if diff1 < diff2 {
-                self.divide_at(rowbef * col)
-            }
-            else {
-                self.divide_at(rownext * col)
-            }
-        } else {
-            let mut c2 = col;
-            if !col.is_power_of_two(){
-                c2 = col.next_power_of_two();
-            }
-            let colbef = c2 / 4;
-            let colnext = c2 / 2;
-            let diff1 = col / 2 - colbef;
-            let diff2 = colnext - col / 2;
-            if diff1 < diff2 {
-                self.divide_at(colbef * row)
-            }
-            else {
-
-                self.divide_at(colnext * row)
-            }
-        }
+    fn divide(self) -> (Self, Self) {
+        self.divide_with(&PowerOfTwoFriendly)
     }
+
     #[allow(unused_mut)]
     fn divide_at(mut self, index: usize) -> (Self, Self) {
+        let _span = trace::span("matrix_adaptive", "divide");
         let mut axis = 0;
         let (row, col) = self.d.dim();
         if col > row {
             axis = 1;
         }
         if axis == 0 {
-            let (ar, ac) = self.a.dim();
-            let (d1, d2) = my_ndarray::divide_mut_at_id_along_axis(self.d, index - 1, Axis(axis));
+            // index counts whole rows (rows * col)
+            let (d1, d2) = my_ndarray::divide_mut_at_id_along_axis(self.d, index, Axis(axis));
             let dim_temp = d1.dim().0;
-            let (a1, a2) =
-                my_ndarray::divide_at_id_along_axis(self.a, dim_temp * ac, Axis(axis));
+            // split a by rows: a flat index is ambiguous when a has no columns (k = 0)
+            let (a1, a2) = self.a.split_at(Axis(axis), dim_temp);
             let (ra1,ca1) = a1.dim();
             let (rd1,cd1) = d1.dim();
             let (ra2,ca2) = a2.dim();
@@ -166,76 +145,119 @@ where
 }
 
 
-impl<'a, 'b, 'd, A> ParallelIterator for Matrix<'a, 'b, 'd, A>
+impl<'a, 'b, 'd, A, D> DivideWith for Matrix<'a, 'b, 'd, A, D>
 where
-    A: LinalgScalar + Send + Sync,
+    A: Send + Sync,
+    D: Send + Sync,
+{
+    /// Cut the longer axis of the output where the strategy says.
+    fn divide_with<S: DivisionStrategy>(self, strategy: &S) -> (Self, Self) {
+        let (row, col) = self.d.dim();
+        if col > row {
+            let point = strategy.split_point(col).max(1);
+            self.divide_at(point * row)
+        } else {
+            let point = strategy.split_point(row);
+            self.divide_at(point * col)
+        }
+    }
+}
+
+impl<'a, 'b, 'd, A, D> ParallelIterator for Matrix<'a, 'b, 'd, A, D>
+where
+    A: Send + Sync,
+    D: Send + Sync,
 {
     type Item = Self;
 
-    type SequentialIterator = Once<Self>;
+    // traced until the caller is done with the leaf
+    type SequentialIterator = Traced<Once<Self>>;
 
     fn to_sequential(self) -> Self::SequentialIterator {
-        once(self)
+        Traced::new(once(self), "matrix_adaptive", "leaf")
     }
 
+    // The extracted piece is a block of whole output rows (or columns for a single row),
+    // so it never writes to the same part of d as the remainder.
+    // Splitting k needs private buffers, see mult_split_k.
     fn extract_iter(&mut self, size: usize) -> Self::SequentialIterator {
-        let (x2,z2) = self.dsize;
-        let (x,y) = self.asize;
-        let (y1,z1) = self.bsize;
-        let (ar,ac) = self.a.dim();
-        let (br,bc) = self.b.dim();
-
-        let stridesd = self.d.strides();
-        let (f,g) = (stridesd[0],stridesd[1]);
-        
-        let mut idx = (size* y) / (ar * bc) as usize;
-        idx = idx.next_power_of_two() / 2;
-        if idx == 0 {
-            idx = 1;
-        }
-
-        if idx > ac {
-            idx = ac;
-        }
-
-        // Cut along the column
-        let (a1, a2) =
-        my_ndarray::divide_at_id_along_axis(self.a, idx, Axis(1));
-        // Cut alog the row
-        let (b1, b2) =
-                my_ndarray::divide_at_id_along_axis(self.b, (idx) * bc - 1, Axis(0));
-        let (rd,cd) = self.d.dim();
-        let raw_mut= &mut self.d[[0,0]];
-        let cpy_d:ArrayViewMut<A,Ix2> = unsafe{ArrayViewMut::from_shape_ptr((rd,cd).strides((f as usize ,g as usize)),raw_mut)};
-        
-        if idx != ac {
+        let (row, col) = self.d.dim();
+        let empty = ArrayViewMut::from_shape((0, 0), &mut []).expect("empty view");
+        let d = std::mem::replace(&mut self.d, empty);
+        if row > 1 || col <= 1 {
+            let cut = (size / col.max(1)).max(1).min(row);
+            let (a1, a2) = self.a.split_at(Axis(0), cut);
+            let (d1, d2) = d.split_at(Axis(0), cut);
             self.a = a2;
-            self.b = b2;
-            once(Matrix {
+            self.d = d2;
+            let leaf = Matrix {
                 a: a1,
-                b: b1,
-                d: cpy_d,
+                b: self.b,
+                d: d1,
                 asize: self.asize,
                 bsize: self.bsize,
                 dsize: self.dsize,
-            })
-        }
-        else {
-            self.a = a1;
+            };
+            Traced::new(once(leaf), "matrix_adaptive", "leaf")
+        } else {
+            let cut = size.max(1).min(col);
+            let (b1, b2) = self.b.split_at(Axis(1), cut);
+            let (d1, d2) = d.split_at(Axis(1), cut);
             self.b = b2;
-            once(Matrix {
-                a: a2,
+            self.d = d2;
+            let leaf = Matrix {
+                a: self.a,
                 b: b1,
-                d: cpy_d,
+                d: d1,
                 asize: self.asize,
                 bsize: self.bsize,
                 dsize: self.dsize,
-            })
+            };
+            Traced::new(once(leaf), "matrix_adaptive", "leaf")
         }
     }
 }
 
 
+///
+/// d += a * b with the k dimension split in chunks of at least `min_depth` columns of a.
+/// For small outputs with a large k (where cutting d gives no parallelism).
+/// Each chunk runs `resolution` into its own zeroed buffer,
+/// the buffers are added into d once every chunk is done.
+///
+pub fn mult_split_k<'a, 'b, 'd, A, F>(
+    a: ArrayView<'a, A, Ix2>,
+    b: ArrayView<'b, A, Ix2>,
+    mut d: ArrayViewMut<'d, A, Ix2>,
+    min_depth: usize,
+    resolution: F,
+) where
+    A: LinalgScalar + Send + Sync,
+    F: Fn(ArrayView<A, Ix2>, ArrayView<A, Ix2>, ArrayViewMut<A, Ix2>) + Sync + Send + Clone,
+{
+    let dim = d.dim();
+    let partials: Vec<Array<A, Ix2>> = split(
+        (a, b),
+        |(a, b)| {
+            let k = a.cols() / 2;
+            let (a1, a2) = a.split_at(Axis(1), k);
+            let (b1, b2) = b.split_at(Axis(0), k);
+            ((a1, b1), (a2, b2))
+        },
+        |(a, _b)| a.cols(),
+    )
+    .with_policy(Policy::Join(min_depth.max(1)))
+    .map(move |(a, b)| {
+        let mut buffer = Array::zeros(dim);
+        resolution(a, b, buffer.view_mut());
+        buffer
+    })
+    .collect();
+    for partial in partials {
+        d.zip_mut_with(&partial, |x, y| *x = *x + *y);
+    }
+}
+
 #[test]
 fn test_mult_join() {
     let height = 2000;
@@ -275,6 +297,28 @@ fn test_mult_join() {
     );
 }
 
+#[test]
+fn test_divide_to_single_elements() {
+    // thin outputs (a single column) must also divide down to one element
+    for &(rows, cols) in &[(37, 1), (2, 1), (1, 29), (37, 29)] {
+        let an = Array::from_shape_fn((rows, 50), |(i, j)| ((i + j) % 7) as i64 - 3);
+        let bn = Array::from_shape_fn((50, cols), |(i, j)| ((i * j) % 5) as i64 - 2);
+        let mut dest = Array::zeros((rows, cols));
+        let m = Matrix {
+            a: an.view(),
+            b: bn.view(),
+            d: dest.view_mut(),
+            asize: an.dim(),
+            bsize: bn.dim(),
+            dsize: (rows, cols),
+        };
+        m.with_policy(Policy::Join(1)).for_each(|mut e| {
+            linalg::general_mat_mul(1, &e.a, &e.b, 1, &mut e.d);
+        });
+        assert_eq!(dest, an.dot(&bn));
+    }
+}
+
 #[test]
 fn test_mult() {
     let height = 2000;
@@ -312,3 +356,63 @@ fn test_mult() {
         epsilon = 1e-1f32
     );
 }
+
+// Integer inputs make the result exact: any race on d shows up as a wrong value.
+#[test]
+fn test_mult_stress_many_threads() {
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(16)
+        .build()
+        .expect("Pool creation failed");
+    let mut rng = rand::thread_rng();
+    for _ in 0..50 {
+        let height = rng.gen_range(1, 120);
+        let depth = rng.gen_range(1, 120);
+        let width = rng.gen_range(1, 120);
+        let an = Array::from_shape_fn((height, depth), |_| rng.gen_range(-50i64, 50));
+        let bn = Array::from_shape_fn((depth, width), |_| rng.gen_range(-50i64, 50));
+        let mut verif = Array::zeros((height, width));
+        linalg::general_mat_mul(1i64, &an, &bn, 1i64, &mut verif);
+        for policy in &[Policy::Adaptive(1, 64), Policy::Adaptive(8, 512), Policy::Join(4)] {
+            let mut dest = Array::zeros((height, width));
+            let (ddim1, ddim2) = dest.dim();
+            let m = Matrix {
+                a: an.view(),
+                b: bn.view(),
+                d: dest.view_mut(),
+                asize: an.dim(),
+                bsize: bn.dim(),
+                dsize: (ddim1, ddim2),
+            };
+            pool.install(|| {
+                m.with_policy(*policy).for_each(|e| {
+                    let mut output = e.d;
+                    linalg::general_mat_mul(1i64, &e.a, &e.b, 1i64, &mut output);
+                })
+            });
+            assert_eq!(dest, verif);
+        }
+    }
+}
+
+#[test]
+fn test_mult_split_k() {
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(16)
+        .build()
+        .expect("Pool creation failed");
+    let mut rng = rand::thread_rng();
+    let an = Array::from_shape_fn((3, 10_000), |_| rng.gen_range(-50i64, 50));
+    let bn = Array::from_shape_fn((10_000, 2), |_| rng.gen_range(-50i64, 50));
+    let mut verif = Array::zeros((3, 2));
+    linalg::general_mat_mul(1i64, &an, &bn, 1i64, &mut verif);
+    for _ in 0..20 {
+        let mut dest = Array::zeros((3, 2));
+        pool.install(|| {
+            mult_split_k(an.view(), bn.view(), dest.view_mut(), 100, |a, b, mut d| {
+                linalg::general_mat_mul(1i64, &a, &b, 1i64, &mut d)
+            })
+        });
+        assert_eq!(dest, verif);
+    }
+}
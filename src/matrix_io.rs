@@ -0,0 +1,173 @@
+use crate::matrix_market;
+use crate::npy::{self, NpyElement};
+use ndarray::{Array, ArrayView, Ix2};
+use num_traits::{Num, NumCast};
+use std::fmt::Display;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+///
+/// Plain text matrices: a "rows cols" line followed by the rows,
+/// values separated by whitespace. Empty lines and lines starting with '#' are ignored.
+///
+pub fn read<A, R>(reader: R) -> io::Result<Array<A, Ix2>>
+where
+    A: FromStr,
+    R: BufRead,
+{
+    let mut values = Vec::new();
+    let mut shape = None;
+    for (number, line) in reader.lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut words = line.split_whitespace();
+        if shape.is_none() {
+            let mut dim = || {
+                words
+                    .next()
+                    .and_then(|w| w.parse::<usize>().ok())
+                    .ok_or_else(|| invalid(number, "expected the 'rows cols' header"))
+            };
+            let rows = dim()?;
+            let cols = dim()?;
+            // no reservation: the header is not trusted, values grows as they are read
+            let size = rows.checked_mul(cols).ok_or_else(|| {
+                invalid(number, &format!("a {}x{} matrix is too large", rows, cols))
+            })?;
+            shape = Some((rows, cols, size));
+            continue;
+        }
+        for word in words {
+            let value = word
+                .parse::<A>()
+                .map_err(|_| invalid(number, &format!("invalid value '{}'", word)))?;
+            values.push(value);
+        }
+    }
+    let (rows, cols, size) = shape.ok_or_else(|| invalid(0, "empty matrix file"))?;
+    if values.len() != size {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "expected {} values for a {}x{} matrix, found {}",
+                size,
+                rows,
+                cols,
+                values.len()
+            ),
+        ));
+    }
+    Ok(Array::from_shape_vec((rows, cols), values).expect("checked length"))
+}
+
+pub(crate) fn invalid(line: usize, message: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("line {}: {}", line + 1, message),
+    )
+}
+
+/// Write `matrix` in the format read by `read`.
+pub fn write<A, W>(mut writer: W, matrix: ArrayView<A, Ix2>) -> io::Result<()>
+where
+    A: Display,
+    W: Write,
+{
+    let (rows, cols) = matrix.dim();
+    writeln!(writer, "{} {}", rows, cols)?;
+    for row in matrix.genrows() {
+        let mut separator = "";
+        for value in row {
+            write!(writer, "{}{}", separator, value)?;
+            separator = " ";
+        }
+        writeln!(writer)?;
+    }
+    writer.flush()
+}
+
+fn has_extension(path: &Path, extension: &str) -> bool {
+    path.extension().map_or(false, |e| e.eq_ignore_ascii_case(extension))
+}
+
+///
+/// Load a matrix from a file, "-" is the standard input (text format).
+/// Files ending in .mtx are Matrix Market, .npy NumPy, .npz NumPy archives
+/// (their first array), the others text.
+///
+pub fn load<A: FromStr + Num + NumCast + Copy>(path: &Path) -> io::Result<Array<A, Ix2>> {
+    if path == Path::new("-") {
+        let stdin = io::stdin();
+        let lock = stdin.lock();
+        read(lock)
+    } else if has_extension(path, "mtx") {
+        matrix_market::read(BufReader::new(File::open(path)?))
+    } else if has_extension(path, "npy") {
+        npy::read(BufReader::new(File::open(path)?))
+    } else if has_extension(path, "npz") {
+        npy::read_npz(BufReader::new(File::open(path)?))?
+            .into_iter()
+            .next()
+            .map(|(_, matrix)| matrix)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "empty npz archive"))
+    } else {
+        read(BufReader::new(File::open(path)?))
+    }
+}
+
+///
+/// Save a matrix to a file, "-" is the standard output (text format).
+/// Files ending in .mtx are written as dense real Matrix Market,
+/// .npy as NumPy, .npz as a NumPy archive with the array "arr_0" and the others as text.
+///
+pub fn save<A>(path: &Path, matrix: ArrayView<A, Ix2>) -> io::Result<()>
+where
+    A: Display + Num + NpyElement,
+{
+    if path == Path::new("-") {
+        let stdout = io::stdout();
+        let lock = stdout.lock();
+        write(BufWriter::new(lock), matrix)
+    } else if has_extension(path, "mtx") {
+        matrix_market::write(
+            BufWriter::new(File::create(path)?),
+            matrix,
+            matrix_market::Format::Array,
+            matrix_market::Field::Real,
+        )
+    } else if has_extension(path, "npy") {
+        npy::write(BufWriter::new(File::create(path)?), matrix)
+    } else if has_extension(path, "npz") {
+        npy::write_npz(BufWriter::new(File::create(path)?), &[("arr_0", matrix)])
+    } else {
+        write(BufWriter::new(File::create(path)?), matrix)
+    }
+}
+
+#[test]
+fn test_text_round_trip() {
+    let matrix = Array::from_shape_fn((3, 4), |(i, j)| i as f32 * 0.5 - j as f32);
+    let mut buffer = Vec::new();
+    write(&mut buffer, matrix.view()).expect("write failed");
+    let read_back: Array<f32, Ix2> = read(&buffer[..]).expect("read failed");
+    assert_eq!(read_back, matrix);
+
+    let empty: Array<f32, Ix2> = read(&b"# nothing\n0 5\n"[..]).expect("read failed");
+    assert_eq!(empty.dim(), (0, 5));
+
+    let short = read::<f32, _>(&b"2 2\n1 2\n3\n"[..]);
+    assert_eq!(short.unwrap_err().kind(), io::ErrorKind::InvalidData);
+    let garbage = read::<f32, _>(&b"2 2\n1 x\n3 4\n"[..]);
+    assert!(garbage.unwrap_err().to_string().contains("line 2"));
+    // headers too large to allocate or to multiply
+    let huge = read::<f32, _>(&b"100000000 100000000\n1 2\n"[..]);
+    assert_eq!(huge.unwrap_err().kind(), io::ErrorKind::InvalidData);
+    let overflow = format!("{} 2\n", usize::MAX);
+    let overflow = read::<f32, _>(overflow.as_bytes());
+    assert!(overflow.unwrap_err().to_string().contains("too large"));
+}
@@ -0,0 +1,364 @@
+use crate::matrix_io::invalid;
+use ndarray::{Array, ArrayView, Ix2};
+use num_traits::Num;
+use std::fmt::Display;
+use std::io::{self, BufRead, Write};
+use std::str::FromStr;
+
+///
+/// Matrix Market storage: dense column major values or (row, col, value) triplets.
+///
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Array,
+    Coordinate,
+}
+
+///
+/// Matrix Market field. Pattern (coordinate only) has no values, the entries are ones.
+///
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Field {
+    Real,
+    Integer,
+    Pattern,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Symmetry {
+    General,
+    Symmetric,
+    SkewSymmetric,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Header {
+    pub format: Format,
+    pub field: Field,
+    pub symmetry: Symmetry,
+}
+
+/// Parse the "%%MatrixMarket matrix <format> <field> <symmetry>" banner.
+pub fn parse_header(line: &str) -> io::Result<Header> {
+    let words: Vec<String> = line.split_whitespace().map(str::to_lowercase).collect();
+    if words.first().map(String::as_str) != Some("%%matrixmarket") {
+        return Err(invalid(0, "missing '%%MatrixMarket' banner"));
+    }
+    if words.len() != 5 {
+        return Err(invalid(
+            0,
+            "expected '%%MatrixMarket matrix <format> <field> <symmetry>'",
+        ));
+    }
+    if words[1] != "matrix" {
+        return Err(invalid(
+            0,
+            &format!("unsupported object '{}' (only 'matrix')", words[1]),
+        ));
+    }
+    let format = match words[2].as_str() {
+        "array" => Format::Array,
+        "coordinate" => Format::Coordinate,
+        other => {
+            return Err(invalid(
+                0,
+                &format!("unknown format '{}' (expected array or coordinate)", other),
+            ))
+        }
+    };
+    let field = match words[3].as_str() {
+        "real" | "double" => Field::Real,
+        "integer" => Field::Integer,
+        "pattern" => Field::Pattern,
+        other => {
+            return Err(invalid(
+                0,
+                &format!(
+                    "unsupported field '{}' (expected real, integer or pattern)",
+                    other
+                ),
+            ))
+        }
+    };
+    let symmetry = match words[4].as_str() {
+        "general" => Symmetry::General,
+        "symmetric" => Symmetry::Symmetric,
+        "skew-symmetric" => Symmetry::SkewSymmetric,
+        other => {
+            return Err(invalid(
+                0,
+                &format!(
+                    "unsupported symmetry '{}' (expected general, symmetric or skew-symmetric)",
+                    other
+                ),
+            ))
+        }
+    };
+    if field == Field::Pattern && format == Format::Array {
+        return Err(invalid(
+            0,
+            "pattern field is only valid for coordinate matrices",
+        ));
+    }
+    Ok(Header {
+        format,
+        field,
+        symmetry,
+    })
+}
+
+fn parse_number<T: FromStr>(word: Option<&str>, line: usize, what: &str) -> io::Result<T> {
+    let word = word.ok_or_else(|| invalid(line, &format!("missing {}", what)))?;
+    word.parse::<T>()
+        .map_err(|_| invalid(line, &format!("invalid {} '{}'", what, word)))
+}
+
+///
+/// Read a dense or sparse Matrix Market file into a dense matrix.
+/// Symmetric and skew-symmetric matrices are expanded.
+///
+pub fn read<A, R>(reader: R) -> io::Result<Array<A, Ix2>>
+where
+    A: FromStr + Num + Copy,
+    R: BufRead,
+{
+    let mut lines = reader.lines().enumerate();
+    let header = match lines.next() {
+        Some((_, line)) => parse_header(&line?)?,
+        None => return Err(invalid(0, "empty file")),
+    };
+    // the mirrored entries are negated: unsigned types cannot hold them
+    if header.symmetry == Symmetry::SkewSymmetric && "-1".parse::<A>().is_err() {
+        return Err(invalid(
+            0,
+            "skew-symmetric matrices cannot be read into an unsigned type",
+        ));
+    }
+    // skip the comments, the size line follows
+    let mut data = lines.filter_map(|(number, line)| match line {
+        Ok(ref l) if l.trim().is_empty() || l.starts_with('%') => None,
+        _ => Some((number, line)),
+    });
+    let (number, size_line) = data.next().ok_or_else(|| invalid(1, "missing size line"))?;
+    let size_line = size_line?;
+    let mut words = size_line.split_whitespace();
+    let rows: usize = parse_number(words.next(), number, "number of rows")?;
+    let cols: usize = parse_number(words.next(), number, "number of columns")?;
+    if header.symmetry != Symmetry::General && rows != cols {
+        return Err(invalid(
+            number,
+            &format!("a {}x{} matrix cannot be symmetric", rows, cols),
+        ));
+    }
+    if rows.checked_mul(cols).is_none() {
+        return Err(invalid(
+            number,
+            &format!("a {}x{} matrix is too large", rows, cols),
+        ));
+    }
+    let mut matrix = Array::zeros((rows, cols));
+    let set = |matrix: &mut Array<A, Ix2>, i: usize, j: usize, value: A| {
+        matrix[(i, j)] = value;
+        match header.symmetry {
+            Symmetry::General => (),
+            Symmetry::Symmetric => matrix[(j, i)] = value,
+            Symmetry::SkewSymmetric => matrix[(j, i)] = A::zero() - value,
+        }
+    };
+    match header.format {
+        Format::Coordinate => {
+            let entries: usize = parse_number(words.next(), number, "number of entries")?;
+            let mut count = 0;
+            for (number, line) in data {
+                let line = line?;
+                let mut words = line.split_whitespace();
+                let i: usize = parse_number(words.next(), number, "row index")?;
+                let j: usize = parse_number(words.next(), number, "column index")?;
+                if i == 0 || j == 0 || i > rows || j > cols {
+                    return Err(invalid(
+                        number,
+                        &format!(
+                            "entry ({}, {}) out of bounds for a {}x{} matrix",
+                            i, j, rows, cols
+                        ),
+                    ));
+                }
+                let value = match header.field {
+                    Field::Pattern => A::one(),
+                    _ => parse_number(words.next(), number, "value")?,
+                };
+                set(&mut matrix, i - 1, j - 1, value);
+                count += 1;
+            }
+            if count != entries {
+                return Err(invalid(
+                    number,
+                    &format!("expected {} entries, found {}", entries, count),
+                ));
+            }
+        }
+        Format::Array => {
+            // column major, only the lower triangle of symmetric matrices
+            // (without the diagonal for skew-symmetric ones)
+            // walked lazily, never stored
+            let symmetry = header.symmetry;
+            let mut positions = (0..cols).flat_map(move |j| {
+                let first = match symmetry {
+                    Symmetry::General => 0,
+                    Symmetry::Symmetric => j,
+                    Symmetry::SkewSymmetric => j + 1,
+                };
+                (first.min(rows)..rows).map(move |i| (i, j))
+            });
+            for (number, line) in data {
+                let line = line?;
+                for word in line.split_whitespace() {
+                    let (i, j) = positions.next().ok_or_else(|| {
+                        invalid(
+                            number,
+                            &format!("too many values for a {}x{} matrix", rows, cols),
+                        )
+                    })?;
+                    let value = parse_number(Some(word), number, "value")?;
+                    set(&mut matrix, i, j, value);
+                }
+            }
+            if positions.next().is_some() {
+                return Err(invalid(
+                    number,
+                    &format!("not enough values for a {}x{} matrix", rows, cols),
+                ));
+            }
+        }
+    }
+    Ok(matrix)
+}
+
+///
+/// Write a general Matrix Market file.
+/// Coordinate format only stores the non zero entries, a pattern field drops their values.
+///
+pub fn write<A, W>(
+    mut writer: W,
+    matrix: ArrayView<A, Ix2>,
+    format: Format,
+    field: Field,
+) -> io::Result<()>
+where
+    A: Display + Num + Copy,
+    W: Write,
+{
+    let name = match field {
+        Field::Real => "real",
+        Field::Integer => "integer",
+        Field::Pattern if format == Format::Array => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "pattern field is only valid for coordinate matrices",
+            ))
+        }
+        Field::Pattern => "pattern",
+    };
+    let (rows, cols) = matrix.dim();
+    match format {
+        Format::Array => {
+            writeln!(writer, "%%MatrixMarket matrix array {} general", name)?;
+            writeln!(writer, "{} {}", rows, cols)?;
+            for column in matrix.gencolumns() {
+                for value in column {
+                    writeln!(writer, "{}", value)?;
+                }
+            }
+        }
+        Format::Coordinate => {
+            writeln!(writer, "%%MatrixMarket matrix coordinate {} general", name)?;
+            let entries = matrix.iter().filter(|v| !v.is_zero()).count();
+            writeln!(writer, "{} {} {}", rows, cols, entries)?;
+            for ((i, j), value) in matrix.indexed_iter() {
+                if value.is_zero() {
+                    continue;
+                }
+                if field == Field::Pattern {
+                    writeln!(writer, "{} {}", i + 1, j + 1)?;
+                } else {
+                    writeln!(writer, "{} {} {}", i + 1, j + 1, value)?;
+                }
+            }
+        }
+    }
+    writer.flush()
+}
+
+#[test]
+fn test_matrix_market_round_trip() {
+    let matrix = Array::from_shape_fn((4, 3), |(i, j)| {
+        if (i + j) % 2 == 0 {
+            (i * 3 + j) as f64 + 0.5
+        } else {
+            0.0
+        }
+    });
+    for &format in &[Format::Array, Format::Coordinate] {
+        let mut buffer = Vec::new();
+        write(&mut buffer, matrix.view(), format, Field::Real).expect("write failed");
+        let read_back: Array<f64, Ix2> = read(&buffer[..]).expect("read failed");
+        assert_eq!(read_back, matrix);
+    }
+}
+
+#[test]
+fn test_matrix_market_read() {
+    let symmetric = b"%%MatrixMarket matrix coordinate integer symmetric
+% a comment
+3 3 3
+1 1 4
+3 1 -2
+2 2 7
+";
+    let m: Array<i32, Ix2> = read(&symmetric[..]).expect("read failed");
+    assert_eq!(m, ndarray::arr2(&[[4, 0, -2], [0, 7, 0], [-2, 0, 0]]));
+
+    let pattern = b"%%MatrixMarket matrix coordinate pattern general\n2 3 2\n1 3\n2 1\n";
+    let m: Array<f32, Ix2> = read(&pattern[..]).expect("read failed");
+    assert_eq!(m, ndarray::arr2(&[[0.0, 0.0, 1.0], [1.0, 0.0, 0.0]]));
+
+    let skew = b"%%MatrixMarket matrix array real skew-symmetric\n3 3\n1.5\n2\n-3\n";
+    let m: Array<f64, Ix2> = read(&skew[..]).expect("read failed");
+    assert_eq!(
+        m,
+        ndarray::arr2(&[[0.0, -1.5, -2.0], [1.5, 0.0, 3.0], [2.0, -3.0, 0.0]])
+    );
+
+    let symmetric = b"%%MatrixMarket matrix array integer symmetric\n2 2\n1 2\n3\n";
+    let m: Array<i32, Ix2> = read(&symmetric[..]).expect("read failed");
+    assert_eq!(m, ndarray::arr2(&[[1, 2], [2, 3]]));
+}
+
+#[test]
+fn test_matrix_market_errors() {
+    let error = |text: &[u8]| read::<f64, _>(text).unwrap_err().to_string();
+    assert!(error(b"3 3\n").contains("banner"));
+    assert!(error(b"%%MatrixMarket matrix dense real general\n1 1\n1\n")
+        .contains("unknown format 'dense'"));
+    assert!(
+        error(b"%%MatrixMarket matrix array complex general\n1 1\n1 0\n")
+            .contains("unsupported field")
+    );
+    assert!(error(b"%%MatrixMarket matrix array pattern general\n1 1\n").contains("pattern"));
+    assert!(
+        error(b"%%MatrixMarket matrix coordinate real general\n2 2 1\n3 1 1.0\n")
+            .contains("line 3")
+    );
+    assert!(
+        error(b"%%MatrixMarket matrix coordinate real general\n2 2 2\n1 1 1.0\n")
+            .contains("expected 2 entries")
+    );
+    let skew = b"%%MatrixMarket matrix coordinate integer skew-symmetric\n2 2 1\n2 1 3\n";
+    assert!(read::<u32, _>(&skew[..])
+        .unwrap_err()
+        .to_string()
+        .contains("unsigned"));
+    assert_eq!(read::<i32, _>(&skew[..]).unwrap()[(0, 1)], -3);
+    let huge = format!("%%MatrixMarket matrix array real general\n{} 2\n1\n", usize::MAX);
+    assert!(error(huge.as_bytes()).contains("too large"));
+}
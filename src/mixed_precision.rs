@@ -0,0 +1,279 @@
+use ndarray::{Array, ArrayView, ArrayViewMut, Ix2};
+use packed_simd::f32x8;
+#[cfg(test)]
+use crate::matrix_adaptive;
+#[cfg(test)]
+use ndarray::linalg;
+#[cfg(test)]
+use rand::Rng;
+#[cfg(test)]
+use rayon_adaptive::prelude::*;
+#[cfg(test)]
+use rayon_adaptive::Policy;
+
+///
+/// 16-bit storage format for mixed precision multiplication.
+/// Values are only stored in 16 bits, every computation is done in f32.
+/// `UNIT_ROUNDOFF` is the relative error of a rounding to the format.
+///
+pub trait HalfFloat: Copy + Send + Sync + 'static {
+    const UNIT_ROUNDOFF: f32;
+    fn from_f32(x: f32) -> Self;
+    fn to_f32(self) -> f32;
+}
+
+///
+/// IEEE 754 binary16: 1 sign bit, 5 exponent bits, 10 mantissa bits.
+///
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct F16(u16);
+
+///
+/// bfloat16: the upper half of an f32 (1 sign bit, 8 exponent bits, 7 mantissa bits).
+///
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Bf16(u16);
+
+impl F16 {
+    pub fn from_bits(bits: u16) -> Self {
+        F16(bits)
+    }
+    pub fn to_bits(self) -> u16 {
+        self.0
+    }
+}
+
+impl Bf16 {
+    pub fn from_bits(bits: u16) -> Self {
+        Bf16(bits)
+    }
+    pub fn to_bits(self) -> u16 {
+        self.0
+    }
+}
+
+impl HalfFloat for F16 {
+    const UNIT_ROUNDOFF: f32 = 1.0 / 2048.0;
+
+    // round to nearest, ties to even
+    fn from_f32(x: f32) -> Self {
+        let bits = x.to_bits();
+        let sign = ((bits >> 16) & 0x8000) as u16;
+        let exp = ((bits >> 23) & 0xff) as i32;
+        let man = bits & 0x007f_ffff;
+        if exp == 0xff {
+            // inf stays inf, nan stays a quiet nan
+            let nan = if man != 0 { 0x0200 } else { 0 };
+            return F16(sign | 0x7c00 | nan | (man >> 13) as u16);
+        }
+        let half_exp = exp - 127 + 15;
+        if half_exp >= 0x1f {
+            return F16(sign | 0x7c00);
+        }
+        if half_exp <= 0 {
+            // subnormal or zero
+            if half_exp < -10 {
+                return F16(sign);
+            }
+            let man = man | 0x0080_0000;
+            let shift = (14 - half_exp) as u32;
+            let mut half_man = man >> shift;
+            let round_bit = 1 << (shift - 1);
+            if (man & round_bit) != 0 && (man & (3 * round_bit - 1)) != 0 {
+                half_man += 1;
+            }
+            return F16(sign | half_man as u16);
+        }
+        let round_bit = 0x1000;
+        let mut half = ((half_exp as u32) << 10) | (man >> 13);
+        if (man & round_bit) != 0 && (man & (3 * round_bit - 1)) != 0 {
+            // a carry into the exponent is still the right rounding (up to inf)
+            half += 1;
+        }
+        F16(sign | half as u16)
+    }
+
+    fn to_f32(self) -> f32 {
+        let h = self.0;
+        let sign = ((h & 0x8000) as u32) << 16;
+        let exp = ((h >> 10) & 0x1f) as u32;
+        let man = (h & 0x03ff) as u32;
+        if exp == 0 {
+            // subnormal: man * 2^-24
+            let value = man as f32 / 16_777_216.0;
+            return if sign != 0 { -value } else { value };
+        }
+        if exp == 0x1f {
+            return f32::from_bits(sign | 0x7f80_0000 | (man << 13));
+        }
+        f32::from_bits(sign | ((exp + 112) << 23) | (man << 13))
+    }
+}
+
+impl HalfFloat for Bf16 {
+    const UNIT_ROUNDOFF: f32 = 1.0 / 256.0;
+
+    // round to nearest, ties to even
+    fn from_f32(x: f32) -> Self {
+        let bits = x.to_bits();
+        if x.is_nan() {
+            return Bf16(((bits >> 16) | 0x0040) as u16);
+        }
+        let round = ((bits >> 16) & 1) + 0x7fff;
+        Bf16((bits.wrapping_add(round) >> 16) as u16)
+    }
+
+    fn to_f32(self) -> f32 {
+        f32::from_bits((self.0 as u32) << 16)
+    }
+}
+
+///
+/// Round every element of a f32 matrix to the 16-bit format H.
+///
+pub fn to_half<H: HalfFloat>(a: ArrayView<f32, Ix2>) -> Array<H, Ix2> {
+    a.mapv(H::from_f32)
+}
+
+///
+/// Widen every element of a 16-bit matrix back to f32.
+///
+pub fn to_f32<H: HalfFloat>(a: ArrayView<H, Ix2>) -> Array<f32, Ix2> {
+    a.mapv(H::to_f32)
+}
+
+///
+/// output += a * b with a and b stored in 16 bits and the accumulation done in f32
+/// The operands are converted to f32 when they are packed:
+/// b once (transposed so its columns are contiguous), a one row at a time.
+/// The dot products are vectorised with packed_simd.
+///
+pub fn mult_mixed_from_ndarray<H>(
+    a: ArrayView<H, Ix2>,
+    b: ArrayView<H, Ix2>,
+    output: &mut ArrayViewMut<f32, Ix2>,
+) where
+    H: HalfFloat,
+{
+    let (m, k) = a.dim();
+    let (kb, n) = b.dim();
+    assert_eq!(k, kb);
+    assert_eq!(output.dim(), (m, n));
+    if m == 0 || n == 0 || k == 0 {
+        return;
+    }
+    let mut packed_b = vec![0f32; n * k];
+    for ((row, col), x) in b.indexed_iter() {
+        packed_b[col * k + row] = x.to_f32();
+    }
+    let mut packed_row = vec![0f32; k];
+    for i in 0..m {
+        for (p, x) in packed_row.iter_mut().zip(a.row(i).iter()) {
+            *p = x.to_f32();
+        }
+        for (j, column) in packed_b.chunks_exact(k).enumerate() {
+            output[[i, j]] += dot_f32(&packed_row, column);
+        }
+    }
+}
+
+fn dot_f32(x: &[f32], y: &[f32]) -> f32 {
+    let mut acc = f32x8::splat(0.0);
+    x.chunks_exact(8)
+        .zip(y.chunks_exact(8))
+        .for_each(|(cx, cy)| {
+            let vx = f32x8::from_slice_unaligned(cx);
+            let vy = f32x8::from_slice_unaligned(cy);
+            acc = vx.mul_add(vy, acc);
+        });
+    let len = x.len();
+    let calc_len = len - len % 8;
+    acc.sum()
+        + x[calc_len..len]
+            .iter()
+            .zip(y[calc_len..len].iter())
+            .map(|(a, b)| a * b)
+            .sum::<f32>()
+}
+
+#[test]
+fn test_conversions() {
+    assert_eq!(F16::from_f32(1.0).to_bits(), 0x3c00);
+    assert_eq!(F16::from_f32(-2.0).to_bits(), 0xc000);
+    assert_eq!(F16::from_f32(65504.0).to_bits(), 0x7bff);
+    assert_eq!(F16::from_f32(65536.0).to_bits(), 0x7c00);
+    assert_eq!(F16::from_f32(5.960_464_5e-8).to_bits(), 0x0001);
+    assert_eq!(F16::from_f32(1e-9).to_bits(), 0x0000);
+    // 1 + 2^-11 is a tie between 1 and 1 + 2^-10: rounds to even
+    assert_eq!(F16::from_f32(1.0 + 1.0 / 2048.0).to_bits(), 0x3c00);
+    assert!(F16::from_f32(std::f32::NAN).to_f32().is_nan());
+    assert_eq!(F16::from_bits(0x0001).to_f32(), 5.960_464_5e-8);
+    assert_eq!(Bf16::from_f32(1.0).to_bits(), 0x3f80);
+    assert_eq!(Bf16::from_f32(-3.0).to_f32(), -3.0);
+    assert!(Bf16::from_f32(std::f32::NAN).to_f32().is_nan());
+    for i in -1000..1000 {
+        let x = i as f32 / 7.0;
+        let h = F16::from_f32(x).to_f32();
+        let bf = Bf16::from_f32(x).to_f32();
+        assert!((h - x).abs() <= x.abs() * F16::UNIT_ROUNDOFF);
+        assert!((bf - x).abs() <= x.abs() * Bf16::UNIT_ROUNDOFF);
+    }
+}
+
+#[cfg(test)]
+fn check_mixed<H: HalfFloat>() {
+    let height = 300;
+    let depth = 200;
+    let width = 250;
+    let mut rng = rand::thread_rng();
+    let an = Array::from_shape_fn((height, depth), |_| rng.gen_range(0.0f32, 1.0));
+    let bn = Array::from_shape_fn((depth, width), |_| rng.gen_range(0.0f32, 1.0));
+    let ah = to_half::<H>(an.view());
+    let bh = to_half::<H>(bn.view());
+    let mut dest = Array::zeros((height, width));
+    let (ddim1, ddim2) = dest.dim();
+    let m = matrix_adaptive::Matrix {
+        a: ah.view(),
+        b: bh.view(),
+        d: dest.view_mut(),
+        asize: ah.dim(),
+        bsize: bh.dim(),
+        dsize: (ddim1, ddim2),
+    };
+    m.cut()
+        .with_policy(Policy::Join(height * width / 16))
+        .for_each(|e| {
+            let mut output = e.d;
+            mult_mixed_from_ndarray(e.a, e.b, &mut output);
+        });
+
+    // same inputs: only the f32 accumulation differs
+    let mut rounded = Array::zeros((height, width));
+    linalg::general_mat_mul(
+        1f32,
+        &to_f32(ah.view()),
+        &to_f32(bh.view()),
+        1f32,
+        &mut rounded,
+    );
+    let accumulation = 2.0 * depth as f32 * std::f32::EPSILON;
+    // f32 inputs: the storage rounding of a and b dominates
+    let mut exact = Array::zeros((height, width));
+    linalg::general_mat_mul(1f32, &an, &bn, 1f32, &mut exact);
+    let storage = 2.0 * H::UNIT_ROUNDOFF + accumulation;
+    for ((d, r), e) in dest.iter().zip(rounded.iter()).zip(exact.iter()) {
+        // the products are all positive so |a||b| == a * b
+        assert!((d - r).abs() <= accumulation * r);
+        assert!((d - e).abs() <= storage * e);
+    }
+}
+
+#[test]
+fn test_mult_f16() {
+    check_mixed::<F16>();
+}
+
+#[test]
+fn test_mult_bf16() {
+    check_mixed::<Bf16>();
+}
@@ -1,4 +1,10 @@
-use crate::faster_vec;
+use crate::division::{DivisionStrategy, Halving};
+use crate::executor::{Executor, Global};
+use crate::faster_vec::{self, FasterScalar};
+use crate::progress::{self, Cancelled, Monitor};
+use crate::roofline;
+use crate::simd::SimdScalar;
+use crate::trace;
 use ndarray::linalg;
 use ndarray::s;
 use ndarray::Array;
@@ -6,63 +12,61 @@ use ndarray::Dimension;
 use ndarray::Ix2;
 use ndarray::LinalgScalar;
 use ndarray::{ArrayView, ArrayViewMut, Axis};
+use std::mem::size_of;
 use std::slice::{from_raw_parts, from_raw_parts_mut};
 use std::time::Instant;
 
 const MULT_CHUNK: usize = 1 * 1024;
+/// Side of the square leaves of mult (and of rayon_mult::matmulz), for the memory traffic
+/// estimates.
+pub(crate) const LEAF_SIZE: usize = 32;
+
+/// Square operands with distinct values, for the timed_matmul functions.
+fn timed_operands<A: LinalgScalar + num_traits::NumCast>(
+    size: usize,
+) -> (Array<A, Ix2>, Array<A, Ix2>) {
+    let value = |x: usize| num_traits::cast(x).unwrap();
+    let a = Array::from_shape_fn((size, size), |(i, j)| value(i * size + j));
+    let b = Array::from_shape_fn((size, size), |(i, j)| value((i * size) + j + 7));
+    (a, b)
+}
 
-pub fn timed_matmul_seq_f32(size: usize, name: &str, power2: bool) -> u64 {
+pub fn timed_matmul_seq<A: SimdScalar + num_traits::NumCast>(
+    size: usize,
+    name: &str,
+    power2: bool,
+) -> u64 {
     let mut size = size;
     if power2 {
         size = size.next_power_of_two();
     }
-    let a = Array::from_shape_fn((size, size), |(i, j)| (i * size + j) as f32);
-    let b = Array::from_shape_fn((size, size), |(i, j)| ((i * size) + j + 7) as f32);
+    let (a, b) = timed_operands::<A>(size);
     let mut dest = Array::zeros((size, size));
 
     let start = Instant::now();
-    linalg::general_mat_mul(1.0, &a.view(), &b.view(), 1.0, &mut dest.view_mut());
-    let dur = Instant::now() - start;
-    let nanos = u64::from(dur.subsec_nanos()) + dur.as_secs() * 1_000_000_000u64;
-    println!(
-        "{}:\t{}x{} matrix: {} s",
-        name,
-        size,
-        size,
-        nanos as f32 / 1e9f32
+    linalg::general_mat_mul(
+        A::one(),
+        &a.view(),
+        &b.view(),
+        A::one(),
+        &mut dest.view_mut(),
     );
-    nanos
-}
-pub fn timed_matmul_seq_u32(size: usize, name: &str, power2: bool) -> u64 {
-    let mut size = size;
-    if power2 {
-        size = size.next_power_of_two();
-    }
-    let a = Array::from_shape_fn((size, size), |(i, j)| (i * size + j) as u32);
-    let b = Array::from_shape_fn((size, size), |(i, j)| ((i * size) + j + 7) as u32);
-    let mut dest = Array::zeros((size, size));
-
-    let start = Instant::now();
-    linalg::general_mat_mul(1u32, &a.view(), &b.view(), 1u32, &mut dest.view_mut());
     let dur = Instant::now() - start;
     let nanos = u64::from(dur.subsec_nanos()) + dur.as_secs() * 1_000_000_000u64;
-    println!(
-        "{}:\t{}x{} matrix: {} s",
-        name,
-        size,
-        size,
-        nanos as f32 / 1e9f32
-    );
+    roofline::print_timing(name, size, size, size_of::<A>(), nanos);
     nanos
 }
 
-pub fn timed_matmul_ndarray_f32(size: usize, name: &str, power2: bool) -> u64 {
+pub fn timed_matmul_ndarray<A: SimdScalar + num_traits::NumCast>(
+    size: usize,
+    name: &str,
+    power2: bool,
+) -> u64 {
     let mut size = size;
     if power2 {
         size = size.next_power_of_two();
     }
-    let a = Array::from_shape_fn((size, size), |(i, j)| (i * size + j) as f32);
-    let b = Array::from_shape_fn((size, size), |(i, j)| ((i * size) + j + 7) as f32);
+    let (a, b) = timed_operands::<A>(size);
     let mut dest = Array::zeros((size, size));
 
     let start = Instant::now();
@@ -72,131 +76,191 @@ pub fn timed_matmul_ndarray_f32(size: usize, name: &str, power2: bool) -> u64 {
     // let mut verif = Array::zeros((size,size));
     // linalg::general_mat_mul(1.0, &a, &b, 1.0, &mut verif);
     // assert_eq!(dest,verif);
-    println!(
-        "{}:\t{}x{} matrix: {} s",
-        name,
-        size,
-        size,
-        nanos as f32 / 1e9f32
-    );
+    roofline::print_timing(name, size, LEAF_SIZE, size_of::<A>(), nanos);
     nanos
 }
 
-pub fn timed_matmul_ndarray_u32(size: usize, name: &str, power2: bool) -> u64 {
+pub fn timed_matmul_faster<A: FasterScalar + num_traits::NumCast>(
+    size: usize,
+    name: &str,
+    power2: bool,
+) -> u64 {
     let mut size = size;
     if power2 {
         size = size.next_power_of_two();
     }
-    let a = Array::from_shape_fn((size, size), |(i, j)| (i * size + j) as u32);
-    let b = Array::from_shape_fn((size, size), |(i, j)| ((i * size) + j + 7) as u32);
+    let (a, b) = timed_operands::<A>(size);
     let mut dest = Array::zeros((size, size));
 
     let start = Instant::now();
-    mult(a.view(), b.view(), dest.view_mut());
+    mult_nd_faster(a.view(), b.view(), dest.view_mut());
     let dur = Instant::now() - start;
     let nanos = u64::from(dur.subsec_nanos()) + dur.as_secs() * 1_000_000_000u64;
     // let mut verif = Array::zeros((size,size));
     // linalg::general_mat_mul(1.0, &a, &b, 1.0, &mut verif);
     // assert_eq!(dest,verif);
-    println!(
-        "{}:\t{}x{} matrix: {} s",
-        name,
-        size,
-        size,
-        nanos as f32 / 1e9f32
-    );
+    roofline::print_timing(name, size, LEAF_SIZE, size_of::<A>(), nanos);
     nanos
 }
 
+#[deprecated(note = "timed_matmul_seq is generic over the element type")]
+pub fn timed_matmul_seq_f32(size: usize, name: &str, power2: bool) -> u64 {
+    timed_matmul_seq::<f32>(size, name, power2)
+}
+#[deprecated(note = "timed_matmul_seq is generic over the element type")]
+pub fn timed_matmul_seq_u32(size: usize, name: &str, power2: bool) -> u64 {
+    timed_matmul_seq::<u32>(size, name, power2)
+}
+#[deprecated(note = "timed_matmul_ndarray is generic over the element type")]
+pub fn timed_matmul_ndarray_f32(size: usize, name: &str, power2: bool) -> u64 {
+    timed_matmul_ndarray::<f32>(size, name, power2)
+}
+#[deprecated(note = "timed_matmul_ndarray is generic over the element type")]
+pub fn timed_matmul_ndarray_u32(size: usize, name: &str, power2: bool) -> u64 {
+    timed_matmul_ndarray::<u32>(size, name, power2)
+}
+#[deprecated(note = "timed_matmul_faster is generic over the element type")]
 pub fn timed_matmul_faster_f32(size: usize, name: &str, power2: bool) -> u64 {
-    let mut size = size;
-    if power2 {
-        size = size.next_power_of_two();
-    }
-    let a = Array::from_shape_fn((size, size), |(i, j)| (i * size + j) as f32);
-    let b = Array::from_shape_fn((size, size), |(i, j)| ((i * size) + j + 7) as f32);
-    let mut dest = Array::zeros((size, size));
-
-    let start = Instant::now();
-    mult_nd_faster(a.view(), b.view(), dest.view_mut());
-    let dur = Instant::now() - start;
-    let nanos = u64::from(dur.subsec_nanos()) + dur.as_secs() * 1_000_000_000u64;
-    // let mut verif = Array::zeros((size,size));
-    // linalg::general_mat_mul(1.0, &a, &b, 1.0, &mut verif);
-    // assert_eq!(dest,verif);
-    println!(
-        "{}:\t{}x{} matrix: {} s",
-        name,
-        size,
-        size,
-        nanos as f32 / 1e9f32
-    );
-    nanos
+    timed_matmul_faster::<f32>(size, name, power2)
 }
+#[deprecated(note = "timed_matmul_faster is generic over the element type")]
 pub fn timed_matmul_faster_u32(size: usize, name: &str, power2: bool) -> u64 {
-    let mut size = size;
-    if power2 {
-        size = size.next_power_of_two();
-    }
-    let a = Array::from_shape_fn((size, size), |(i, j)| (i * size + j) as u32);
-    let b = Array::from_shape_fn((size, size), |(i, j)| ((i * size) + j + 7) as u32);
-    let mut dest = Array::zeros((size, size));
-
-    let start = Instant::now();
-    mult_nd_faster_u32(a.view(), b.view(), dest.view_mut());
-    let dur = Instant::now() - start;
-    let nanos = u64::from(dur.subsec_nanos()) + dur.as_secs() * 1_000_000_000u64;
-    // let mut verif = Array::zeros((size,size));
-    // linalg::general_mat_mul(1.0, &a, &b, 1.0, &mut verif);
-    // assert_eq!(dest,verif);
-    println!(
-        "{}:\t{}x{} matrix: {} s",
-        name,
-        size,
-        size,
-        nanos as f32 / 1e9f32
-    );
-    nanos
+    timed_matmul_faster::<u32>(size, name, power2)
 }
 pub fn mult<'a, 'b, 'd, A>(
+    a: ArrayView<'a, A, Ix2>,
+    b: ArrayView<'b, A, Ix2>,
+    result: ArrayViewMut<'d, A, Ix2>,
+) -> ArrayViewMut<'d, A, Ix2>
+where
+    A: LinalgScalar + Send + Sync,
+{
+    mult_with(a, b, result, &Global)
+}
+
+///
+/// Same as mult with the tasks run by the given executor
+///
+pub fn mult_with<'a, 'b, 'd, A, E>(
+    a: ArrayView<'a, A, Ix2>,
+    b: ArrayView<'b, A, Ix2>,
+    result: ArrayViewMut<'d, A, Ix2>,
+    executor: &E,
+) -> ArrayViewMut<'d, A, Ix2>
+where
+    A: LinalgScalar + Send + Sync,
+    E: Executor,
+{
+    mult_with_division(a, b, result, &Halving, executor)
+}
+
+///
+/// Same as mult_with, the matrices being cut where the strategy says
+/// (mult_with halves them).
+///
+pub fn mult_with_division<'a, 'b, 'd, A, S, E>(
+    a: ArrayView<'a, A, Ix2>,
+    b: ArrayView<'b, A, Ix2>,
+    result: ArrayViewMut<'d, A, Ix2>,
+    strategy: &S,
+    executor: &E,
+) -> ArrayViewMut<'d, A, Ix2>
+where
+    A: LinalgScalar + Send + Sync,
+    S: DivisionStrategy,
+    E: Executor,
+{
+    mult_rec(a, b, result, strategy, executor, &|a, b, result| {
+        linalg::general_mat_mul(A::one(), &a, &b, A::one(), result)
+    })
+}
+
+///
+/// Same as mult_with, reporting each leaf to the monitor's progress sink.
+/// The cancellation token is checked before each leaf,
+/// a cancelled multiplication returns Err(Cancelled) and leaves result partially computed.
+///
+pub fn mult_monitored<'a, 'b, 'd, A, E>(
+    a: ArrayView<'a, A, Ix2>,
+    b: ArrayView<'b, A, Ix2>,
+    result: ArrayViewMut<'d, A, Ix2>,
+    executor: &E,
+    monitor: &Monitor,
+) -> Result<ArrayViewMut<'d, A, Ix2>, Cancelled>
+where
+    A: LinalgScalar + Send + Sync,
+    E: Executor,
+{
+    monitor.start();
+    let result = mult_rec(a, b, result, &Halving, executor, &|a, b, result| {
+        monitor.leaf(|| {
+            linalg::general_mat_mul(A::one(), &a, &b, A::one(), result);
+            progress::flops(a.rows(), b.cols(), a.cols())
+        })
+    });
+    monitor.result().map(|_| result)
+}
+
+/// The recursion of mult_with_division, `leaf` computing result += a * b on the leaves.
+fn mult_rec<'a, 'b, 'd, A, S, E, L>(
     a: ArrayView<'a, A, Ix2>,
     b: ArrayView<'b, A, Ix2>,
     mut result: ArrayViewMut<'d, A, Ix2>,
+    strategy: &S,
+    executor: &E,
+    leaf: &L,
 ) -> ArrayViewMut<'d, A, Ix2>
 where
     A: LinalgScalar + Send + Sync,
+    S: DivisionStrategy,
+    E: Executor,
+    L: Fn(ArrayView<A, Ix2>, ArrayView<A, Ix2>, &mut ArrayViewMut<A, Ix2>) + Sync,
 {
     let dima = a.shape();
     if dima[0] * dima[1] <= MULT_CHUNK {
-        linalg::general_mat_mul(A::one(), &a, &b, A::one(), &mut result);
+        let _span = trace::span("my_ndarray", "leaf");
+        leaf(a, b, &mut result);
         return result;
     }
     let (rrow, rcol) = result.dim();
-    let (a1, a2, a3, a4) = divide(a);
-    let (b1, b2, b3, b4) = divide(b);
-    let (d1, d2, d3, d4) = divide_mut(result.slice_mut(s![0..rrow;1,0..rcol;1]));
+    let span = trace::span("my_ndarray", "divide");
+    let (a1, a2, a3, a4) = divide_with(a, strategy);
+    let (b1, b2, b3, b4) = divide_with(b, strategy);
+    let (d1, d2, d3, d4) = divide_mut_with(result.slice_mut(s![0..rrow;1,0..rcol;1]), strategy);
+    drop(span);
 
     let (d, f, g, h) = join4(
-        || mult(a1, b1, d1),
-        || mult(a1, b2, d2),
-        || mult(a3, b1, d3),
-        || mult(a3, b2, d4),
+        executor,
+        || mult_rec(a1, b1, d1, strategy, executor, leaf),
+        || mult_rec(a1, b2, d2, strategy, executor, leaf),
+        || mult_rec(a3, b1, d3, strategy, executor, leaf),
+        || mult_rec(a3, b2, d4, strategy, executor, leaf),
     );
 
     let (_r1, _r2, _r3, _r4) = join4(
-        || mult(a2, b3, d),
-        || mult(a2, b4, f),
-        || mult(a4, b3, g),
-        || mult(a4, b4, h),
+        executor,
+        || mult_rec(a2, b3, d, strategy, executor, leaf),
+        || mult_rec(a2, b4, f, strategy, executor, leaf),
+        || mult_rec(a4, b3, g, strategy, executor, leaf),
+        || mult_rec(a4, b4, h, strategy, executor, leaf),
     );
     result
 }
 
-pub fn mult_nd_faster<'a, 'b, 'd>(
-    a: ArrayView<'a, f32, Ix2>,
-    b: ArrayView<'b, f32, Ix2>,
-    mut result: ArrayViewMut<'d, f32, Ix2>,
-) -> ArrayViewMut<'d, f32, Ix2> {
+pub fn mult_nd_faster<'a, 'b, 'd, A: FasterScalar>(
+    a: ArrayView<'a, A, Ix2>,
+    b: ArrayView<'b, A, Ix2>,
+    result: ArrayViewMut<'d, A, Ix2>,
+) -> ArrayViewMut<'d, A, Ix2> {
+    mult_nd_faster_with(a, b, result, &Global)
+}
+
+pub fn mult_nd_faster_with<'a, 'b, 'd, A: FasterScalar, E: Executor>(
+    a: ArrayView<'a, A, Ix2>,
+    b: ArrayView<'b, A, Ix2>,
+    mut result: ArrayViewMut<'d, A, Ix2>,
+    executor: &E,
+) -> ArrayViewMut<'d, A, Ix2> {
     let dima = a.shape();
     let dimb = b.shape();
     if dima[0] == 0 || dima[1] == 0 || dimb[0] == 0 || dimb[1] == 0 {
@@ -240,76 +304,39 @@ pub fn mult_nd_faster<'a, 'b, 'd>(
     let (d1, d2, d3, d4) = divide_mut(result.slice_mut(s![0..rrow;1,0..rcol;1]));
 
     let (d, f, g, h) = join4(
-        || mult_nd_faster(a1, b1, d1),
-        || mult_nd_faster(a1, b2, d2),
-        || mult_nd_faster(a3, b1, d3),
-        || mult_nd_faster(a3, b2, d4),
+        executor,
+        || mult_nd_faster_with(a1, b1, d1, executor),
+        || mult_nd_faster_with(a1, b2, d2, executor),
+        || mult_nd_faster_with(a3, b1, d3, executor),
+        || mult_nd_faster_with(a3, b2, d4, executor),
     );
 
     let (_r1, _r2, _r3, _r4) = join4(
-        || mult_nd_faster(a2, b3, d),
-        || mult_nd_faster(a2, b4, f),
-        || mult_nd_faster(a4, b3, g),
-        || mult_nd_faster(a4, b4, h),
+        executor,
+        || mult_nd_faster_with(a2, b3, d, executor),
+        || mult_nd_faster_with(a2, b4, f, executor),
+        || mult_nd_faster_with(a4, b3, g, executor),
+        || mult_nd_faster_with(a4, b4, h, executor),
     );
     result
 }
+#[deprecated(note = "mult_nd_faster is generic over the element type")]
 pub fn mult_nd_faster_u32<'a, 'b, 'd>(
     a: ArrayView<'a, u32, Ix2>,
     b: ArrayView<'b, u32, Ix2>,
-    mut result: ArrayViewMut<'d, u32, Ix2>,
+    result: ArrayViewMut<'d, u32, Ix2>,
 ) -> ArrayViewMut<'d, u32, Ix2> {
-    let dim = a.shape();
-    let dimb = b.shape();
-    if dim[0] * dim[1] <= MULT_CHUNK {
-        let (raw_ptr_a, len_a) = view_ptr(a);
-        let stridesa = a.strides();
-        let (raw_ptr_b, len_b) = view_ptr(b);
-        let stridesb = b.strides();
-        let raw_ptr_r = result.as_mut_ptr();
-        let dimr = result.shape();
-        let (row, col) = (dimr[0], dimr[1]);
-        let strides = result.strides();
-        let len_r = (row - 1) * strides[0] as usize + col;
-
-        let slicea = unsafe { from_raw_parts(raw_ptr_a, len_a) };
-        let sliceb = unsafe { from_raw_parts(raw_ptr_b, len_b) };
-        let mut slicer = unsafe { from_raw_parts_mut(raw_ptr_r, len_r) };
-        faster_vec::multiply_add_u32(
-            &mut slicer,
-            &slicea,
-            &sliceb,
-            dim[1],
-            dim[0],
-            dimb[1],
-            dimb[0],
-            dimr[1],
-            dimr[0],
-            stridesa[0] as usize,
-            stridesb[0] as usize,
-            strides[0] as usize,
-        );
-        return result;
-    }
-    let (rrow, rcol) = result.dim();
-    let (a1, a2, a3, a4) = divide(a);
-    let (b1, b2, b3, b4) = divide(b);
-    let (d1, d2, d3, d4) = divide_mut(result.slice_mut(s![0..rrow;1,0..rcol;1]));
-
-    let (d, f, g, h) = join4(
-        || mult_nd_faster_u32(a1, b1, d1),
-        || mult_nd_faster_u32(a1, b2, d2),
-        || mult_nd_faster_u32(a3, b1, d3),
-        || mult_nd_faster_u32(a3, b2, d4),
-    );
+    mult_nd_faster_with(a, b, result, &Global)
+}
 
-    let (_r1, _r2, _r3, _r4) = join4(
-        || mult_nd_faster_u32(a2, b3, d),
-        || mult_nd_faster_u32(a2, b4, f),
-        || mult_nd_faster_u32(a4, b3, g),
-        || mult_nd_faster_u32(a4, b4, h),
-    );
-    result
+#[deprecated(note = "mult_nd_faster_with is generic over the element type")]
+pub fn mult_nd_faster_u32_with<'a, 'b, 'd, E: Executor>(
+    a: ArrayView<'a, u32, Ix2>,
+    b: ArrayView<'b, u32, Ix2>,
+    result: ArrayViewMut<'d, u32, Ix2>,
+    executor: &E,
+) -> ArrayViewMut<'d, u32, Ix2> {
+    mult_nd_faster_with(a, b, result, executor)
 }
 
 pub fn divide_mut<'a: 'b, 'b, A>(
@@ -440,6 +467,51 @@ where
     (a11, a12, a21, a22)
 }
 
+///
+/// Quadrants of a, cut where the strategy says on each axis.
+///
+pub fn divide_with<'a, A, D, S>(
+    a: ArrayView<'a, A, D>,
+    strategy: &S,
+) -> (
+    ArrayView<'a, A, D>,
+    ArrayView<'a, A, D>,
+    ArrayView<'a, A, D>,
+    ArrayView<'a, A, D>,
+)
+where
+    D: Dimension,
+    S: DivisionStrategy,
+{
+    let (row, col) = (a.shape()[0], a.shape()[1]);
+    let (a1, a2) = a.split_at(Axis(0), strategy.split_point(row));
+    let col = strategy.split_point(col);
+    let (a11, a12) = a1.split_at(Axis(1), col);
+    let (a21, a22) = a2.split_at(Axis(1), col);
+    (a11, a12, a21, a22)
+}
+
+pub fn divide_mut_with<'a, A, D, S>(
+    a: ArrayViewMut<'a, A, D>,
+    strategy: &S,
+) -> (
+    ArrayViewMut<'a, A, D>,
+    ArrayViewMut<'a, A, D>,
+    ArrayViewMut<'a, A, D>,
+    ArrayViewMut<'a, A, D>,
+)
+where
+    D: Dimension,
+    S: DivisionStrategy,
+{
+    let (row, col) = (a.shape()[0], a.shape()[1]);
+    let (a1, a2) = a.split_at(Axis(0), strategy.split_point(row));
+    let col = strategy.split_point(col);
+    let (a11, a12) = a1.split_at(Axis(1), col);
+    let (a21, a22) = a2.split_at(Axis(1), col);
+    (a11, a12, a21, a22)
+}
+
 pub fn divide_at_id_along_axis<'a, A>(
     a: ArrayView<'a, A, Ix2>,
     index: usize,
@@ -482,8 +554,15 @@ pub fn divide_mut_at_id_along_axis<'a, A>(
     }
 }
 
-fn join4<F1, F2, F3, F4, R1, R2, R3, R4>(f1: F1, f2: F2, f3: F3, f4: F4) -> (R1, R2, R3, R4)
+fn join4<E, F1, F2, F3, F4, R1, R2, R3, R4>(
+    executor: &E,
+    f1: F1,
+    f2: F2,
+    f3: F3,
+    f4: F4,
+) -> (R1, R2, R3, R4)
 where
+    E: Executor,
     F1: FnOnce() -> R1 + Send,
     R1: Send,
     F2: FnOnce() -> R2 + Send,
@@ -493,7 +572,10 @@ where
     F4: FnOnce() -> R4 + Send,
     R4: Send,
 {
-    let ((r1, r2), (r3, r4)) = rayon::join(|| rayon::join(f1, f2), || rayon::join(f3, f4));
+    let ((r1, r2), (r3, r4)) = executor.join(
+        || executor.join(f1, f2),
+        || executor.join(f3, f4),
+    );
     (r1, r2, r3, r4)
 }
 
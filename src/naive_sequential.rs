@@ -1,13 +1,13 @@
-use crate::my_ndarray;
-use crate::split::split;
+use crate::block_grid::{self, BlockGrid, BlockOrder};
+use crate::executor::{Executor, Global};
+use crate::split::split_at;
+use crate::summation::{self, Summation};
 use ndarray::LinalgScalar;
 #[cfg(test)]
 use ndarray::{linalg, Array};
-use ndarray::{ArrayView, ArrayViewMut, Axis, Ix1, Ix2};
+use ndarray::{ArrayView, ArrayViewMut, Ix1, Ix2};
 #[cfg(test)]
 use rand::Rng;
-use rayon_adaptive::prelude::*;
-use rayon_adaptive::Policy;
 use std::ops::AddAssign;
 use std::fmt::Debug;
 
@@ -45,6 +45,28 @@ pub fn mult_jik<'a, 'b, 'd, A>(
     }
 }
 
+///
+/// Same as mult but the scalar products are accumulated with the given summation mode
+/// (see summation::Summation).
+///
+pub fn mult_with_summation<'a, 'b, 'd, A>(
+    a: ArrayView<'a, A, Ix2>,
+    b: ArrayView<'b, A, Ix2>,
+    mut result: ArrayViewMut<'d, A, Ix2>,
+    mode: Summation,
+) where
+    A: LinalgScalar + AddAssign,
+{
+    for idx_a in 0..a.rows() {
+        let arow = a.row(idx_a);
+        for idx_b in 0..b.cols() {
+            let bcolumn = b.column(idx_b);
+            let c = result.get_mut((idx_a, idx_b)).expect("Index out of bounds");
+            *c += summation::dot(arow, bcolumn, mode);
+        }
+    }
+}
+
 fn scalar_mult<'a, 'b, A>(a: ArrayView<'a, A, Ix1>, b: ArrayView<'b, A, Ix1>) -> A
 where
     A: LinalgScalar + AddAssign,
@@ -78,6 +100,10 @@ pub fn mult_index_optimized<'a, 'b, 'd, A>(
     }
 }
 
+///
+/// Cut a, b and result in blocks of at most chunkh rows and columns, and chunkw along
+/// the common dimension k.
+///
 pub fn cut_in_blocks<'a, 'b, 'd, A>(
     a: ArrayView<'a, A, Ix2>,
     b: ArrayView<'b, A, Ix2>,
@@ -85,147 +111,166 @@ pub fn cut_in_blocks<'a, 'b, 'd, A>(
     chunkw: usize,
     chunkh: usize,
 ) -> (
-    Vec<Vec<ArrayView<'a, A, Ix2>>>,
-    Vec<Vec<ArrayView<'b, A, Ix2>>>,
-    Vec<Vec<ArrayViewMut<'d, A, Ix2>>>,
+    BlockGrid<ArrayView<'a, A, Ix2>>,
+    BlockGrid<ArrayView<'b, A, Ix2>>,
+    BlockGrid<ArrayViewMut<'d, A, Ix2>>,
 )
 where
     A: LinalgScalar + AddAssign + Sync + Send,
 {
-    let (_arow, acol) = a.dim();
-    let (_brow, bcol) = b.dim();
-    let (_rrow, rcol) = result.dim();
-    let avec_blocks: Vec<Vec<ArrayView<'a, A, Ix2>>> = split(
-        a,
-        |a| {
-            let (ar, _ac) = a.dim();
-            let ar = ar.next_power_of_two();
-            my_ndarray::divide_at_id_along_axis(a, ar / 2 * acol - 1, Axis(0))
-        },
-        |a| {
-            let (ar, _ac) = a.dim();
-            ar
-        },
-    )
-    .cut()
-    .with_policy(Policy::Join(chunkh))
-    .map(|subblock| {
-        split(
-            subblock.data,
-            |s| {
-                let (_ar, ac) = s.dim();
-                let ac = ac.next_power_of_two();
-                my_ndarray::divide_at_id_along_axis(s, ac / 2, Axis(1))
-            },
-            |a| {
-                let (_ar, ac) = a.dim();
-                ac
-            },
-        )
-        .with_policy(Policy::Join(chunkw))
-        .collect()
-    })
-    .collect();
+    cut_in_blocks_with(a, b, result, chunkh, chunkh, chunkw)
+}
 
-    let bvec_blocks: Vec<Vec<ArrayView<'b, A, Ix2>>> = split(
-        b,
-        |b| {
-            let (br, _bc) = b.dim();
-            let br = br.next_power_of_two();
-            my_ndarray::divide_at_id_along_axis(b, br / 2 * bcol - 1, Axis(0))
-        },
-        |b| {
-            let (br, _bc) = b.dim();
-            br
-        },
-    )
-    .cut()
-    .with_policy(Policy::Join(chunkw))
-    .map(|subblock| {
-        split(
-            subblock.data,
-            |s| {
-                let (_br, bc) = s.dim();
-                let bc = bc.next_power_of_two();
-                my_ndarray::divide_at_id_along_axis(s, bc / 2, Axis(1))
-            },
-            |b| {
-                let (_br, bc) = b.dim();
-                bc
-            },
-        )
-        .with_policy(Policy::Join(chunkh))
-        .collect()
-    })
-    .collect();
-    let rvec_blocks: Vec<Vec<ArrayViewMut<'d, A, Ix2>>> = split(
-        result,
-        |r| {
-            let (rr, _rc) = r.dim();
-            let rr = rr.next_power_of_two();
-            my_ndarray::divide_mut_at_id_along_axis(r, rr / 2 * rcol - 1, Axis(0))
-        },
-        |r| {
-            let (rr, _rc) = r.dim();
-            rr
-        },
+///
+/// Cut a (m x k), b (k x n) and result (m x n) in blocks of at most row_block x k_block,
+/// k_block x col_block and row_block x col_block.
+/// Block (i, l) of a, (l, j) of b and (i, j) of result have matching shapes.
+///
+pub fn cut_in_blocks_with<'a, 'b, 'd, A>(
+    a: ArrayView<'a, A, Ix2>,
+    b: ArrayView<'b, A, Ix2>,
+    result: ArrayViewMut<'d, A, Ix2>,
+    row_block: usize,
+    col_block: usize,
+    k_block: usize,
+) -> (
+    BlockGrid<ArrayView<'a, A, Ix2>>,
+    BlockGrid<ArrayView<'b, A, Ix2>>,
+    BlockGrid<ArrayViewMut<'d, A, Ix2>>,
+) {
+    let (m, k) = a.dim();
+    let n = b.cols();
+    assert_eq!(b.rows(), k, "inner dimensions differ");
+    assert_eq!(result.dim(), (m, n), "result has the wrong shape");
+    let rows = block_grid::boundaries(m, row_block);
+    let cols = block_grid::boundaries(n, col_block);
+    let depth = block_grid::boundaries(k, k_block);
+    (
+        BlockGrid::from_view(a, rows.clone(), depth.clone()),
+        BlockGrid::from_view(b, depth, cols.clone()),
+        BlockGrid::from_view_mut(result, rows, cols),
     )
-    .cut()
-    .with_policy(Policy::Join(chunkh))
-    .map(|subblock| {
-        split(
-            subblock.data,
-            |s| {
-                let (_rr, rc) = s.dim();
-                let rc = rc.next_power_of_two();
-                my_ndarray::divide_mut_at_id_along_axis(s, rc / 2, Axis(1))
-            },
-            |r| {
-                let (_rr, rc) = r.dim();
-                rc
-            },
-        )
-        .with_policy(Policy::Join(chunkh))
-        .collect()
-    })
-    .collect();
-    (avec_blocks, bvec_blocks, rvec_blocks)
 }
 
-
+///
+/// cblocks += ablocks * bblocks, block by block with `resolution`:
+/// each block (i, k) of A in row-major order, with the blocks of row k of B.
+/// mult_blocks_ordered visits the blocks of the result in a given order instead.
+///
 pub fn mult_blocks<A,F>(
-    ablocks: Vec<Vec<ArrayView<A, Ix2>>>,
-    bblocks: Vec<Vec<ArrayView<A, Ix2>>>,
-    mut cblocks: Vec<Vec<ArrayViewMut<A, Ix2>>>,
+    ablocks: BlockGrid<ArrayView<A, Ix2>>,
+    bblocks: BlockGrid<ArrayView<A, Ix2>>,
+    mut cblocks: BlockGrid<ArrayViewMut<A, Ix2>>,
     resolution : F,
 ) where
     A: LinalgScalar + AddAssign,
     F: Fn(ArrayView<A, Ix2>, ArrayView<A, Ix2>, ArrayViewMut<A,Ix2>) 
 {
-    for line_a in 0..ablocks.len() {
-        let aline = ablocks.get(line_a).expect("out of range in Matrix A");
-        for (idx_a, line_b) in (0..aline.len()).zip(0..bblocks.len()) {
-            let a = aline.get(idx_a).expect("out of range in Matrix A");
-            let bline = bblocks.get(line_b).expect("out of range in Matrix B");
-            let nb_col_b = bline.len();
-            for idx_b in 0..bline.len() {
-                let mut calc_b = idx_b;
-                if (line_b / nb_col_b) % 2 == 1 {
-                    calc_b = nb_col_b - idx_b - 1;
-                }
-                let b = bline.get(calc_b).expect("out of range in Matrix B");
-                let resline = cblocks
-                    .get_mut(line_a)
-                    .expect("out of range in Matrix Result");
-                let res = resline
-                    .get_mut(calc_b)
-                    .expect("out of range in Matrix Result");
-                resolution(a.view(), b.view(), res.view_mut());
+    assert_eq!(ablocks.row_bounds(), cblocks.row_bounds());
+    assert_eq!(ablocks.col_bounds(), bblocks.row_bounds());
+    assert_eq!(bblocks.col_bounds(), cblocks.col_bounds());
+    let (rows, cols) = cblocks.shape();
+    let depth = ablocks.shape().1;
+    for i in 0..rows {
+        for k in 0..depth {
+            for index in 0..cols {
+                let j = if (k / cols) % 2 == 1 { cols - index - 1 } else { index };
+                resolution(
+                    ablocks[(i, k)].view(),
+                    bblocks[(k, j)].view(),
+                    cblocks[(i, j)].view_mut(),
+                );
             }
         }
     }
 }
 
+///
+/// cblocks += ablocks * bblocks, the blocks of the result visited in `order`, and for each
+/// one the k blocks back and forth (see block_grid::block_products).
+/// block_grid::lru_misses estimates the blocks loaded by each order.
+///
+pub fn mult_blocks_ordered<A, F>(
+    ablocks: BlockGrid<ArrayView<A, Ix2>>,
+    bblocks: BlockGrid<ArrayView<A, Ix2>>,
+    mut cblocks: BlockGrid<ArrayViewMut<A, Ix2>>,
+    order: BlockOrder,
+    resolution: F,
+) where
+    A: LinalgScalar + AddAssign,
+    F: Fn(ArrayView<A, Ix2>, ArrayView<A, Ix2>, ArrayViewMut<A, Ix2>),
+{
+    assert_eq!(ablocks.row_bounds(), cblocks.row_bounds());
+    assert_eq!(ablocks.col_bounds(), bblocks.row_bounds());
+    assert_eq!(bblocks.col_bounds(), cblocks.col_bounds());
+    let (rows, cols) = cblocks.shape();
+    let depth = ablocks.shape().1;
+    for (i, j, k) in block_grid::block_products(rows, cols, depth, order) {
+        resolution(
+            ablocks[(i, k)].view(),
+            bblocks[(k, j)].view(),
+            cblocks[(i, j)].view_mut(),
+        );
+    }
+}
+
+///
+/// mult_blocks with the blocks of the result distributed over the threads of the global pool.
+///
+pub fn mult_blocks_par<A, F>(
+    ablocks: BlockGrid<ArrayView<A, Ix2>>,
+    bblocks: BlockGrid<ArrayView<A, Ix2>>,
+    cblocks: BlockGrid<ArrayViewMut<A, Ix2>>,
+    resolution: F,
+) where
+    A: LinalgScalar + AddAssign + Send + Sync,
+    F: Fn(ArrayView<A, Ix2>, ArrayView<A, Ix2>, ArrayViewMut<A, Ix2>) + Sync + Send,
+{
+    mult_blocks_par_with(ablocks, bblocks, cblocks, resolution, &Global)
+}
+
+///
+/// mult_blocks with the blocks of the result distributed by `executor`.
+/// The blocks of the result are independent: each task owns some of them and
+/// loops sequentially over k for each one.
+///
+pub fn mult_blocks_par_with<A, F, E>(
+    ablocks: BlockGrid<ArrayView<A, Ix2>>,
+    bblocks: BlockGrid<ArrayView<A, Ix2>>,
+    cblocks: BlockGrid<ArrayViewMut<A, Ix2>>,
+    resolution: F,
+    executor: &E,
+) where
+    A: LinalgScalar + AddAssign + Send + Sync,
+    F: Fn(ArrayView<A, Ix2>, ArrayView<A, Ix2>, ArrayViewMut<A, Ix2>) + Sync + Send,
+    E: Executor,
+{
+    assert_eq!(ablocks.row_bounds(), cblocks.row_bounds());
+    assert_eq!(ablocks.col_bounds(), bblocks.row_bounds());
+    assert_eq!(bblocks.col_bounds(), cblocks.col_bounds());
+    let (rows, cols) = cblocks.shape();
+    let depth = ablocks.shape().1;
+    let outputs: Vec<_> = block_grid::positions(rows, cols, BlockOrder::RowMajor)
+        .into_iter()
+        .zip(cblocks.into_blocks())
+        .collect();
+    let outputs = split_at(
+        outputs,
+        |mut left, index| {
+            let right = left.split_off(index.min(left.len()));
+            (left, right)
+        },
+        |outputs| outputs.len(),
+    );
+    executor.for_each(outputs, |outputs| {
+        for ((i, j), mut c) in outputs {
+            for l in 0..depth {
+                resolution(ablocks[(i, l)].view(), bblocks[(l, j)].view(), c.view_mut());
+            }
+        }
+    });
+}
+
 #[test]
 fn test_mult() {
     let height = 500;
@@ -295,3 +340,82 @@ fn test_mult_blocks() {
         epsilon = 1e-1f32
     );
 }
+
+#[test]
+fn test_mult_blocks_rectangular() {
+    let (m, k, n) = (130, 77, 201);
+    let an = Array::from_shape_fn((m, k), |(i, j)| ((i * 3 + j) % 5) as f32);
+    let bn = Array::from_shape_fn((k, n), |(i, j)| ((i + 2 * j) % 7) as f32);
+    let mut verif = Array::zeros((m, n));
+    linalg::general_mat_mul(1.0, &an, &bn, 1.0, &mut verif);
+    for &(rows, cols, depth) in &[(32, 50, 20), (1000, 7, 1), (13, 1000, 1000)] {
+        let mut dest = Array::zeros((m, n));
+        let (ablocks, bblocks, cblocks) =
+            cut_in_blocks_with(an.view(), bn.view(), dest.view_mut(), rows, cols, depth);
+        assert_eq!(ablocks.shape().1, bblocks.shape().0);
+        let (i, j) = (ablocks.shape().0 - 1, bblocks.shape().1 - 1);
+        assert_eq!(
+            cblocks.block_shape((i, j)),
+            (ablocks.block_shape((i, 0)).0, bblocks.block_shape((0, j)).1)
+        );
+        mult_blocks(ablocks, bblocks, cblocks, mult_index_optimized);
+        // small integers: exact
+        assert_eq!(dest, verif, "blocks of {} x {} x {}", rows, cols, depth);
+    }
+}
+
+#[test]
+fn test_mult_blocks_par() {
+    use crate::executor::{ScopedThreads, Sequential};
+    let height = 1000;
+    let width = 1000;
+    let mut rng = rand::thread_rng();
+    let random = rng.gen_range(0.0, 1.0);
+    let an = Array::from_shape_fn((height, width), |(i, j)| {
+        (((j + i * width) % 3) as f32) - random
+    });
+    let bn = Array::from_shape_fn((width, height), |(i, j)| {
+        (((j + 7 + i * height) % 3) as f32) + random
+    });
+    let mut verif = Array::zeros((height, height));
+    linalg::general_mat_mul(1.0, &an, &bn, 1.0, &mut verif);
+    let mut dest = Array::zeros((height, height));
+    let (avec, bvec, rvec) = cut_in_blocks(an.view(), bn.view(), dest.view_mut(), 300, 300);
+    mult_blocks_par(avec, bvec, rvec, mult_index_optimized);
+    assert_abs_diff_eq!(
+        dest.as_slice().unwrap(),
+        verif.as_slice().unwrap(),
+        epsilon = 1e-1f32
+    );
+    let mut sequential = Array::zeros((height, height));
+    let (avec, bvec, rvec) = cut_in_blocks(an.view(), bn.view(), sequential.view_mut(), 300, 300);
+    mult_blocks_par_with(avec, bvec, rvec, mult_index_optimized, &Sequential);
+    let mut threads = Array::zeros((height, height));
+    let (avec, bvec, rvec) =
+        cut_in_blocks_with(an.view(), bn.view(), threads.view_mut(), 100, 300, 200);
+    mult_blocks_par_with(avec, bvec, rvec, mult_index_optimized, &ScopedThreads::new(4));
+    // same k order for each output block: same result whatever the schedule
+    assert_eq!(sequential, dest);
+    assert_abs_diff_eq!(
+        threads.as_slice().unwrap(),
+        verif.as_slice().unwrap(),
+        epsilon = 1e-1f32
+    );
+}
+
+#[test]
+fn test_mult_blocks_ordered() {
+    use crate::block_grid::BLOCK_ORDERS;
+    let (m, k, n) = (200, 150, 170);
+    let an = Array::from_shape_fn((m, k), |(i, j)| ((i + 3 * j) % 5) as f32);
+    let bn = Array::from_shape_fn((k, n), |(i, j)| ((2 * i + j) % 3) as f32);
+    let mut verif = Array::zeros((m, n));
+    linalg::general_mat_mul(1.0, &an, &bn, 1.0, &mut verif);
+    for &order in BLOCK_ORDERS.iter() {
+        let mut dest = Array::zeros((m, n));
+        let (avec, bvec, rvec) =
+            cut_in_blocks_with(an.view(), bn.view(), dest.view_mut(), 30, 20, 40);
+        mult_blocks_ordered(avec, bvec, rvec, order, mult_index_optimized);
+        assert_eq!(dest, verif, "{:?}", order);
+    }
+}
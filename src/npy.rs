@@ -0,0 +1,601 @@
+use ndarray::{Array, ArrayView, Ix2, ShapeBuilder};
+use num_traits::{cast, NumCast};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+const MAGIC: &[u8] = b"\x93NUMPY";
+/// numpy pads the header so that the data starts on a multiple of 64 bytes.
+const ALIGNMENT: usize = 64;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ByteOrder {
+    Little,
+    Big,
+}
+
+///
+/// Element types that can be written to a .npy file.
+/// Reading accepts any of them and converts the values to the requested type.
+///
+pub trait NpyElement: Copy {
+    /// numpy type code without the byte order ("f4", "i8", ...).
+    const DESCR: &'static str;
+    fn write_to<W: Write>(self, writer: &mut W, order: ByteOrder) -> io::Result<()>;
+}
+
+macro_rules! npy_element {
+    ($type:ty, $descr:expr) => {
+        impl NpyElement for $type {
+            const DESCR: &'static str = $descr;
+            fn write_to<W: Write>(self, writer: &mut W, order: ByteOrder) -> io::Result<()> {
+                match order {
+                    ByteOrder::Little => writer.write_all(&self.to_le_bytes()),
+                    ByteOrder::Big => writer.write_all(&self.to_be_bytes()),
+                }
+            }
+        }
+    };
+}
+
+npy_element!(f32, "f4");
+npy_element!(f64, "f8");
+npy_element!(i32, "i4");
+npy_element!(u32, "u4");
+npy_element!(i64, "i8");
+npy_element!(u64, "u8");
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+struct Header {
+    descr: String,
+    fortran_order: bool,
+    shape: Vec<usize>,
+}
+
+/// Value of `key` in the python dict literal of the header.
+fn dict_value<'h>(header: &'h str, key: &str) -> io::Result<&'h str> {
+    let missing = || invalid(format!("npy header without '{}'", key));
+    let start = header
+        .find(&format!("'{}'", key))
+        .or_else(|| header.find(&format!("\"{}\"", key)))
+        .ok_or_else(missing)?;
+    let rest = &header[start + key.len() + 2..];
+    let rest = rest.trim_start();
+    if !rest.starts_with(':') {
+        return Err(missing());
+    }
+    Ok(rest[1..].trim_start())
+}
+
+fn parse_header(header: &str) -> io::Result<Header> {
+    let descr = dict_value(header, "descr")?;
+    let quote = descr.chars().next().filter(|&c| c == '\'' || c == '"');
+    let descr = match quote {
+        Some(q) => descr[1..]
+            .split(q)
+            .next()
+            .ok_or_else(|| invalid("unterminated npy descr".to_owned()))?,
+        None => return Err(invalid("unsupported npy dtype (structured array)".to_owned())),
+    };
+    let fortran_order = dict_value(header, "fortran_order")?;
+    let fortran_order = if fortran_order.starts_with("True") {
+        true
+    } else if fortran_order.starts_with("False") {
+        false
+    } else {
+        return Err(invalid("invalid npy fortran_order".to_owned()));
+    };
+    let shape = dict_value(header, "shape")?;
+    if !shape.starts_with('(') {
+        return Err(invalid("invalid npy shape".to_owned()));
+    }
+    let end = shape
+        .find(')')
+        .ok_or_else(|| invalid("invalid npy shape".to_owned()))?;
+    let shape = shape[1..end]
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(|s| {
+            s.trim_end_matches('L')
+                .parse::<usize>()
+                .map_err(|_| invalid(format!("invalid npy dimension '{}'", s)))
+        })
+        .collect::<io::Result<Vec<usize>>>()?;
+    Ok(Header {
+        descr: descr.to_owned(),
+        fortran_order,
+        shape,
+    })
+}
+
+fn read_header<R: Read>(reader: &mut R) -> io::Result<Header> {
+    let mut preamble = [0u8; 8];
+    reader.read_exact(&mut preamble)?;
+    if &preamble[..6] != MAGIC {
+        return Err(invalid("not a npy file (bad magic string)".to_owned()));
+    }
+    let length = match preamble[6] {
+        1 => {
+            let mut length = [0u8; 2];
+            reader.read_exact(&mut length)?;
+            u16::from_le_bytes(length) as usize
+        }
+        2 | 3 => {
+            let mut length = [0u8; 4];
+            reader.read_exact(&mut length)?;
+            u32::from_le_bytes(length) as usize
+        }
+        version => return Err(invalid(format!("unsupported npy version {}", version))),
+    };
+    let mut header = vec![0u8; length];
+    reader.read_exact(&mut header)?;
+    let header =
+        String::from_utf8(header).map_err(|_| invalid("npy header is not text".to_owned()))?;
+    parse_header(&header)
+}
+
+fn decode<A: NumCast>(bytes: &[u8], descr: &str) -> io::Result<Vec<A>> {
+    let native = if cfg!(target_endian = "little") {
+        ByteOrder::Little
+    } else {
+        ByteOrder::Big
+    };
+    let (order, code) = match descr.chars().next() {
+        Some('<') => (ByteOrder::Little, &descr[1..]),
+        Some('>') => (ByteOrder::Big, &descr[1..]),
+        Some('=') | Some('|') => (native, &descr[1..]),
+        _ => (native, descr),
+    };
+    macro_rules! convert {
+        ($type:ty, $size:expr) => {
+            bytes
+                .chunks_exact($size)
+                .map(|chunk| {
+                    let mut raw = [0u8; $size];
+                    raw.copy_from_slice(chunk);
+                    let value = match order {
+                        ByteOrder::Little => <$type>::from_le_bytes(raw),
+                        ByteOrder::Big => <$type>::from_be_bytes(raw),
+                    };
+                    cast::<$type, A>(value).ok_or_else(|| {
+                        invalid(format!("npy value {} does not fit the requested type", value))
+                    })
+                })
+                .collect()
+        };
+    }
+    match code {
+        "f4" => convert!(f32, 4),
+        "f8" => convert!(f64, 8),
+        "i4" => convert!(i32, 4),
+        "u4" => convert!(u32, 4),
+        "i8" => convert!(i64, 8),
+        "u8" => convert!(u64, 8),
+        _ => Err(unsupported(descr)),
+    }
+}
+
+fn unsupported(descr: &str) -> io::Error {
+    invalid(format!(
+        "unsupported npy dtype '{}' (expected f4, f8, i4, u4, i8 or u8)",
+        descr
+    ))
+}
+
+/// Size in bytes of the values of a supported dtype.
+fn element_size(descr: &str) -> io::Result<usize> {
+    let code = descr.trim_start_matches(|c| "<>=|".contains(c));
+    match code {
+        "f4" | "i4" | "u4" => Ok(4),
+        "f8" | "i8" | "u8" => Ok(8),
+        _ => Err(unsupported(descr)),
+    }
+}
+
+///
+/// Read a 2 dimensional .npy array (C or Fortran order, any byte order),
+/// converting its values to A.
+///
+pub fn read<A, R>(mut reader: R) -> io::Result<Array<A, Ix2>>
+where
+    A: NumCast,
+    R: Read,
+{
+    let header = read_header(&mut reader)?;
+    let (rows, cols) = match header.shape[..] {
+        [rows, cols] => (rows, cols),
+        _ => {
+            return Err(invalid(format!(
+                "expected a 2 dimensional npy array, found shape {:?}",
+                header.shape
+            )))
+        }
+    };
+    let size = element_size(&header.descr)?;
+    let length = rows
+        .checked_mul(cols)
+        .and_then(|n| n.checked_mul(size))
+        .ok_or_else(|| invalid(format!("npy shape {:?} is too large", header.shape)))?;
+    // grows with the data actually read, whatever length the header announces
+    let mut bytes = Vec::new();
+    reader.take(length as u64).read_to_end(&mut bytes)?;
+    if bytes.len() != length {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "npy data shorter than its shape",
+        ));
+    }
+    let values = decode(&bytes, &header.descr)?;
+    let shape = (rows, cols).set_f(header.fortran_order);
+    Ok(Array::from_shape_vec(shape, values).expect("checked length"))
+}
+
+///
+/// Write a .npy file in C order and little endian.
+///
+pub fn write<A, W>(writer: W, matrix: ArrayView<A, Ix2>) -> io::Result<()>
+where
+    A: NpyElement,
+    W: Write,
+{
+    write_with(writer, matrix, ByteOrder::Little, false)
+}
+
+///
+/// Write a .npy file with the given byte order, in Fortran (column major) or C order.
+///
+pub fn write_with<A, W>(
+    mut writer: W,
+    matrix: ArrayView<A, Ix2>,
+    order: ByteOrder,
+    fortran_order: bool,
+) -> io::Result<()>
+where
+    A: NpyElement,
+    W: Write,
+{
+    let (rows, cols) = matrix.dim();
+    let mut header = format!(
+        "{{'descr': '{}{}', 'fortran_order': {}, 'shape': ({}, {}), }}",
+        if order == ByteOrder::Little { '<' } else { '>' },
+        A::DESCR,
+        if fortran_order { "True" } else { "False" },
+        rows,
+        cols
+    );
+    // magic, version and length take 10 bytes, the header ends with '\n'
+    let padding = ALIGNMENT - (MAGIC.len() + 4 + header.len() + 1) % ALIGNMENT;
+    header.extend(std::iter::repeat(' ').take(padding % ALIGNMENT));
+    header.push('\n');
+    writer.write_all(MAGIC)?;
+    writer.write_all(&[1, 0])?;
+    writer.write_all(&(header.len() as u16).to_le_bytes())?;
+    writer.write_all(header.as_bytes())?;
+    if fortran_order {
+        for column in matrix.gencolumns() {
+            for value in column {
+                value.write_to(&mut writer, order)?;
+            }
+        }
+    } else {
+        for value in matrix.iter() {
+            value.write_to(&mut writer, order)?;
+        }
+    }
+    writer.flush()
+}
+
+// .npz files are zip archives of .npy files. Only stored (numpy.savez) entries are
+// supported, not the deflated ones of numpy.savez_compressed.
+
+const LOCAL_HEADER: u32 = 0x0403_4b50;
+const CENTRAL_HEADER: u32 = 0x0201_4b50;
+const END_OF_CENTRAL_DIRECTORY: u32 = 0x0605_4b50;
+const ZIP64_EXTRA: u16 = 0x0001;
+
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in bytes {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+fn u16_at(bytes: &[u8], position: usize) -> u16 {
+    u16::from_le_bytes([bytes[position], bytes[position + 1]])
+}
+
+fn u32_at(bytes: &[u8], position: usize) -> u32 {
+    let mut raw = [0u8; 4];
+    raw.copy_from_slice(&bytes[position..position + 4]);
+    u32::from_le_bytes(raw)
+}
+
+fn u64_at(bytes: &[u8], position: usize) -> u64 {
+    let mut raw = [0u8; 8];
+    raw.copy_from_slice(&bytes[position..position + 8]);
+    u64::from_le_bytes(raw)
+}
+
+struct ZipEntry {
+    name: String,
+    method: u16,
+    crc: u32,
+    size: u64,
+    offset: u64,
+}
+
+fn central_directory<R: Read + Seek>(reader: &mut R) -> io::Result<Vec<ZipEntry>> {
+    let length = reader.seek(SeekFrom::End(0))?;
+    // end of central directory record: 22 bytes and a comment of at most 65535 bytes
+    let tail_length = length.min(22 + 65535);
+    reader.seek(SeekFrom::Start(length - tail_length))?;
+    let mut tail = vec![0u8; tail_length as usize];
+    reader.read_exact(&mut tail)?;
+    let end = (0..tail.len().saturating_sub(21))
+        .rev()
+        .find(|&p| u32_at(&tail, p) == END_OF_CENTRAL_DIRECTORY)
+        .ok_or_else(|| invalid("not a npz file (no zip directory)".to_owned()))?;
+    let count = u16_at(&tail, end + 10) as usize;
+    let directory_size = u32_at(&tail, end + 12) as usize;
+    let directory_offset = u32_at(&tail, end + 16) as u64;
+    let mut directory = vec![0u8; directory_size];
+    reader.seek(SeekFrom::Start(directory_offset))?;
+    reader.read_exact(&mut directory)?;
+
+    let mut entries = Vec::with_capacity(count);
+    let mut position = 0;
+    for _ in 0..count {
+        if position + 46 > directory.len() || u32_at(&directory, position) != CENTRAL_HEADER {
+            return Err(invalid("corrupted npz zip directory".to_owned()));
+        }
+        let method = u16_at(&directory, position + 10);
+        let crc = u32_at(&directory, position + 16);
+        let mut size = u32_at(&directory, position + 24) as u64;
+        let name_length = u16_at(&directory, position + 28) as usize;
+        let extra_length = u16_at(&directory, position + 30) as usize;
+        let comment_length = u16_at(&directory, position + 32) as usize;
+        let mut offset = u32_at(&directory, position + 42) as u64;
+        let name_start = position + 46;
+        let extra_start = name_start + name_length;
+        let next = extra_start + extra_length + comment_length;
+        if next > directory.len() {
+            return Err(invalid("corrupted npz zip directory".to_owned()));
+        }
+        let name = String::from_utf8_lossy(&directory[name_start..extra_start]).into_owned();
+        // zip64 sizes and offset (numpy forces zip64 entries)
+        let extra = &directory[extra_start..extra_start + extra_length];
+        let mut e = 0;
+        while e + 4 <= extra.len() {
+            let id = u16_at(extra, e);
+            let field_length = u16_at(extra, e + 2) as usize;
+            if id == ZIP64_EXTRA {
+                let mut field = e + 4;
+                if size == 0xFFFF_FFFF && field + 8 <= extra.len() {
+                    size = u64_at(extra, field);
+                    field += 8;
+                }
+                // compressed size: the same for stored entries
+                if u32_at(&directory, position + 20) == 0xFFFF_FFFF {
+                    field += 8;
+                }
+                if offset == 0xFFFF_FFFF && field + 8 <= extra.len() {
+                    offset = u64_at(extra, field);
+                }
+            }
+            e += 4 + field_length;
+        }
+        entries.push(ZipEntry {
+            name,
+            method,
+            crc,
+            size,
+            offset,
+        });
+        position = next;
+    }
+    Ok(entries)
+}
+
+///
+/// Read all the arrays of a .npz file, with their names (without the .npy extension).
+///
+pub fn read_npz<A, R>(mut reader: R) -> io::Result<Vec<(String, Array<A, Ix2>)>>
+where
+    A: NumCast,
+    R: Read + Seek,
+{
+    let entries = central_directory(&mut reader)?;
+    let archive_length = reader.seek(SeekFrom::End(0))?;
+    let mut arrays = Vec::with_capacity(entries.len());
+    for entry in entries {
+        if entry.method != 0 {
+            return Err(invalid(format!(
+                "npz entry '{}' is compressed, only numpy.savez (not savez_compressed) files are supported",
+                entry.name
+            )));
+        }
+        let mut local = [0u8; 30];
+        reader.seek(SeekFrom::Start(entry.offset))?;
+        reader.read_exact(&mut local)?;
+        if u32_at(&local, 0) != LOCAL_HEADER {
+            return Err(invalid(format!("corrupted npz entry '{}'", entry.name)));
+        }
+        let skip = u16_at(&local, 26) as i64 + u16_at(&local, 28) as i64;
+        let start = reader.seek(SeekFrom::Current(skip))?;
+        if entry.size > archive_length.saturating_sub(start) {
+            return Err(invalid(format!(
+                "npz entry '{}' is larger than the archive",
+                entry.name
+            )));
+        }
+        let mut data = vec![0u8; entry.size as usize];
+        reader.read_exact(&mut data)?;
+        if crc32(&data) != entry.crc {
+            return Err(invalid(format!("npz entry '{}': bad checksum", entry.name)));
+        }
+        let name = entry.name.trim_end_matches(".npy").to_owned();
+        arrays.push((name, read(&data[..])?));
+    }
+    Ok(arrays)
+}
+
+///
+/// Write arrays to a .npz file (stored zip, as numpy.savez).
+/// numpy.load gives them back under their names.
+///
+pub fn write_npz<A, W>(mut writer: W, arrays: &[(&str, ArrayView<A, Ix2>)]) -> io::Result<()>
+where
+    A: NpyElement,
+    W: Write,
+{
+    // 1980-01-01 00:00, the zip epoch
+    let (time, date) = (0u16, 0x21u16);
+    let mut offset = 0u32;
+    let mut directory = Vec::new();
+    for (name, matrix) in arrays {
+        let mut data = Vec::new();
+        write(&mut data, matrix.view())?;
+        let name = format!("{}.npy", name);
+        let crc = crc32(&data);
+        let size = data.len() as u32;
+        let mut local = Vec::with_capacity(30 + name.len());
+        local.extend_from_slice(&LOCAL_HEADER.to_le_bytes());
+        for field in &[20u16, 0, 0, time, date] {
+            local.extend_from_slice(&field.to_le_bytes());
+        }
+        for field in &[crc, size, size] {
+            local.extend_from_slice(&field.to_le_bytes());
+        }
+        local.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        local.extend_from_slice(&0u16.to_le_bytes());
+        local.extend_from_slice(name.as_bytes());
+
+        directory.extend_from_slice(&CENTRAL_HEADER.to_le_bytes());
+        for field in &[20u16, 20, 0, 0, time, date] {
+            directory.extend_from_slice(&field.to_le_bytes());
+        }
+        for field in &[crc, size, size] {
+            directory.extend_from_slice(&field.to_le_bytes());
+        }
+        for field in &[name.len() as u16, 0, 0, 0, 0] {
+            directory.extend_from_slice(&field.to_le_bytes());
+        }
+        for field in &[0u32, offset] {
+            directory.extend_from_slice(&field.to_le_bytes());
+        }
+        directory.extend_from_slice(name.as_bytes());
+
+        writer.write_all(&local)?;
+        writer.write_all(&data)?;
+        offset += (local.len() + data.len()) as u32;
+    }
+    writer.write_all(&directory)?;
+    writer.write_all(&END_OF_CENTRAL_DIRECTORY.to_le_bytes())?;
+    let count = arrays.len() as u16;
+    for field in &[0u16, 0, count, count] {
+        writer.write_all(&field.to_le_bytes())?;
+    }
+    writer.write_all(&(directory.len() as u32).to_le_bytes())?;
+    writer.write_all(&offset.to_le_bytes())?;
+    writer.write_all(&0u16.to_le_bytes())?;
+    writer.flush()
+}
+
+#[test]
+fn test_npy_round_trip() {
+    let matrix = Array::from_shape_fn((5, 3), |(i, j)| (i * 3 + j) as f64 - 4.5);
+    for &order in &[ByteOrder::Little, ByteOrder::Big] {
+        for &fortran_order in &[false, true] {
+            let mut buffer = Vec::new();
+            write_with(&mut buffer, matrix.view(), order, fortran_order).expect("write failed");
+            assert_eq!((buffer.len() - 15 * 8) % ALIGNMENT, 0);
+            let read_back: Array<f64, Ix2> = read(&buffer[..]).expect("read failed");
+            assert_eq!(read_back, matrix);
+        }
+    }
+    let integers = Array::from_shape_fn((2, 4), |(i, j)| (i * 4 + j) as u32);
+    let mut buffer = Vec::new();
+    write(&mut buffer, integers.view()).expect("write failed");
+    let as_f32: Array<f32, Ix2> = read(&buffer[..]).expect("read failed");
+    assert_eq!(as_f32, integers.mapv(|x| x as f32));
+    let negative = Array::from_shape_fn((1, 2), |(_, j)| j as i32 - 1);
+    let mut buffer = Vec::new();
+    write(&mut buffer, negative.view()).expect("write failed");
+    assert!(read::<u32, _>(&buffer[..]).is_err());
+}
+
+#[test]
+fn test_npy_numpy_header() {
+    // as written by numpy.save(f, np.array([[1, 2, 3], [4, 5, 6]], dtype='>i4', order='F'))
+    let header = "{'descr': '>i4', 'fortran_order': True, 'shape': (2, 3), }";
+    let mut file = file_with_header(&format!("{:width$}\n", header, width = 128 - 10 - 1));
+    for value in &[1i32, 4, 2, 5, 3, 6] {
+        file.extend_from_slice(&value.to_be_bytes());
+    }
+    let matrix: Array<i32, Ix2> = read(&file[..]).expect("read failed");
+    assert_eq!(matrix, ndarray::arr2(&[[1, 2, 3], [4, 5, 6]]));
+
+    let vector = file_with_header("{'descr': '<f8', 'fortran_order': False, 'shape': (3,), }");
+    assert!(read::<f64, _>(&vector[..]).is_err());
+}
+
+#[test]
+fn test_npy_bad_headers() {
+    let error = |header: &str| {
+        let mut file = file_with_header(header);
+        file.extend_from_slice(&[0u8; 64]);
+        read::<f64, _>(&file[..]).expect_err("read succeeded")
+    };
+    let complex = error("{'descr': '<c16', 'fortran_order': False, 'shape': (2, 2), }");
+    assert!(complex.to_string().contains("unsupported npy dtype"), "{}", complex);
+    let huge = format!(
+        "{{'descr': '<f8', 'fortran_order': False, 'shape': ({}, 3), }}",
+        usize::max_value()
+    );
+    assert_eq!(error(&huge).kind(), io::ErrorKind::InvalidData);
+    // not an overflow, far more than the file holds
+    let large = "{'descr': '<f8', 'fortran_order': False, 'shape': (1000000000, 1000), }";
+    assert_eq!(error(large).kind(), io::ErrorKind::UnexpectedEof);
+}
+
+#[cfg(test)]
+fn file_with_header(header: &str) -> Vec<u8> {
+    let mut file = Vec::new();
+    file.extend_from_slice(MAGIC);
+    file.extend_from_slice(&[1, 0]);
+    file.extend_from_slice(&(header.len() as u16).to_le_bytes());
+    file.extend_from_slice(header.as_bytes());
+    file
+}
+
+#[test]
+fn test_npz_round_trip() {
+    let a = Array::from_shape_fn((3, 2), |(i, j)| (i + 10 * j) as f32);
+    let b = Array::from_shape_fn((1, 4), |(_, j)| j as f32 * 0.25);
+    let mut buffer = Vec::new();
+    write_npz(&mut buffer, &[("a", a.view()), ("b", b.view())]).expect("write failed");
+    let arrays: Vec<(String, Array<f32, Ix2>)> =
+        read_npz(io::Cursor::new(&buffer)).expect("read failed");
+    assert_eq!(arrays.len(), 2);
+    assert_eq!(arrays[0], ("a".to_owned(), a));
+    assert_eq!(arrays[1], ("b".to_owned(), b));
+    assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    // an entry size beyond the end of the archive, in the central directory
+    let directory = (0..buffer.len() - 4)
+        .find(|&p| u32_at(&buffer, p) == CENTRAL_HEADER)
+        .expect("no central directory");
+    buffer[directory + 24..directory + 28].copy_from_slice(&0x7FFF_FFFFu32.to_le_bytes());
+    buffer[directory + 20..directory + 24].copy_from_slice(&0x7FFF_FFFFu32.to_le_bytes());
+    let error = read_npz::<f32, _>(io::Cursor::new(&buffer)).expect_err("read succeeded");
+    assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+}
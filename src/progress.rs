@@ -0,0 +1,307 @@
+use crate::executor::Executor;
+use rayon_adaptive::prelude::*;
+use std::error::Error;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+#[cfg(test)]
+use crate::executor::{Adaptive, Global, Sequential};
+#[cfg(test)]
+use crate::{matrix, my_ndarray};
+#[cfg(test)]
+use ndarray::{linalg, Array};
+#[cfg(test)]
+use rayon_adaptive::Policy;
+
+///
+/// Receives a report after each leaf task: the number of floating point
+/// operations it did (2 * m * n * k for a product).
+/// Called concurrently from every worker thread.
+///
+pub trait ProgressSink: Sync {
+    fn leaf_done(&self, flops: u64);
+}
+
+impl<F> ProgressSink for F
+where
+    F: Fn(u64) + Sync,
+{
+    fn leaf_done(&self, flops: u64) {
+        self(flops)
+    }
+}
+
+///
+/// Counters of the work done so far, to be polled from another thread.
+///
+#[derive(Default)]
+pub struct Progress {
+    flops: AtomicU64,
+    leaves: AtomicUsize,
+    total_flops: u64,
+}
+
+impl Progress {
+    /// Progress of a m x k by k x n multiplication.
+    pub fn new(m: usize, n: usize, k: usize) -> Self {
+        Progress {
+            flops: AtomicU64::new(0),
+            leaves: AtomicUsize::new(0),
+            total_flops: 2 * (m * n * k) as u64,
+        }
+    }
+
+    pub fn flops(&self) -> u64 {
+        self.flops.load(Ordering::Relaxed)
+    }
+
+    pub fn leaves(&self) -> usize {
+        self.leaves.load(Ordering::Relaxed)
+    }
+
+    /// Done fraction in [0, 1].
+    pub fn fraction(&self) -> f64 {
+        if self.total_flops == 0 {
+            1.0
+        } else {
+            self.flops() as f64 / self.total_flops as f64
+        }
+    }
+}
+
+impl ProgressSink for Progress {
+    fn leaf_done(&self, flops: u64) {
+        self.flops.fetch_add(flops, Ordering::Relaxed);
+        self.leaves.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+///
+/// Shared flag to stop a multiplication from another thread.
+/// Clones share the same flag.
+///
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed)
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+///
+/// Returned when a multiplication was stopped by its token.
+/// The leaves already started ran to completion, the others did not run:
+/// the output is partially computed and must be discarded.
+///
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cancelled;
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "matrix multiplication cancelled")
+    }
+}
+
+impl Error for Cancelled {}
+
+///
+/// Optional progress sink and cancellation token of a multiplication.
+///
+#[derive(Default)]
+pub struct Monitor<'s> {
+    progress: Option<&'s dyn ProgressSink>,
+    token: Option<CancellationToken>,
+    skipped: AtomicBool,
+}
+
+impl<'s> Monitor<'s> {
+    pub fn new() -> Self {
+        Monitor {
+            progress: None,
+            token: None,
+            skipped: AtomicBool::new(false),
+        }
+    }
+
+    pub fn with_progress(mut self, progress: &'s dyn ProgressSink) -> Self {
+        self.progress = Some(progress);
+        self
+    }
+
+    pub fn with_cancellation(mut self, token: CancellationToken) -> Self {
+        self.token = Some(token);
+        self
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.token.as_ref().map_or(false, |t| t.is_cancelled())
+    }
+
+    pub fn leaf_done(&self, flops: u64) {
+        if let Some(progress) = self.progress {
+            progress.leaf_done(flops);
+        }
+    }
+
+    /// Called by each monitored multiplication before its first leaf, so a monitor can be
+    /// reused after a cancelled run.
+    pub(crate) fn start(&self) {
+        self.skipped.store(false, Ordering::Relaxed);
+    }
+
+    /// Runs a leaf returning its number of flops and reports it,
+    /// or skips it if the token is cancelled.
+    pub fn leaf<F: FnOnce() -> u64>(&self, op: F) {
+        if self.is_cancelled() {
+            self.skipped.store(true, Ordering::Relaxed);
+        } else {
+            self.leaf_done(op());
+        }
+    }
+
+    /// Err(Cancelled) if a leaf was skipped: a token cancelled after the last leaf
+    /// started does not discard a complete result.
+    pub fn result(&self) -> Result<(), Cancelled> {
+        if self.skipped.load(Ordering::Relaxed) {
+            Err(Cancelled)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+///
+/// for_each driver for the rayon_adaptive matrices with progress and cancellation.
+/// `op` processes one leaf and returns its number of flops.
+/// The token is checked before each leaf: once cancelled the remaining leaves are skipped.
+///
+pub fn for_each_monitored<E, I, F>(
+    executor: &E,
+    iter: I,
+    monitor: &Monitor,
+    op: F,
+) -> Result<(), Cancelled>
+where
+    E: Executor,
+    I: ParallelIterator + Send,
+    F: Fn(I::Item) -> u64 + Sync + Send,
+{
+    monitor.start();
+    executor.for_each(iter, |item| monitor.leaf(|| op(item)));
+    monitor.result()
+}
+
+/// Number of floating point operations of a (m x k) * (k x n) product.
+pub fn flops(m: usize, n: usize, k: usize) -> u64 {
+    2 * (m * n * k) as u64
+}
+
+#[test]
+fn test_progress_counts_all_flops() {
+    let size = 300;
+    let an = Array::from_shape_fn((size, size), |(i, j)| ((i + j) % 3) as f32);
+    let bn = Array::from_shape_fn((size, size), |(i, j)| ((i * j) % 5) as f32);
+    let mut dest = Array::zeros((size, size));
+    let progress = Progress::new(size, size, size);
+    let monitor = Monitor::new().with_progress(&progress);
+    my_ndarray::mult_monitored(an.view(), bn.view(), dest.view_mut(), &Global, &monitor)
+        .expect("not cancelled");
+    assert_eq!(progress.flops(), flops(size, size, size));
+    assert_eq!(progress.fraction(), 1.0);
+    let mut verif = Array::zeros((size, size));
+    linalg::general_mat_mul(1.0, &an, &bn, 1.0, &mut verif);
+    assert_eq!(dest, verif);
+}
+
+#[test]
+fn test_cancellation() {
+    let size = 512;
+    let an = Array::from_shape_fn((size, size), |(i, j)| ((i + j) % 3) as f32);
+    let bn = Array::from_shape_fn((size, size), |(i, j)| ((i * j) % 5) as f32);
+    let token = CancellationToken::new();
+    let progress = Progress::new(size, size, size);
+    // cancel from the sink after a few leaves
+    let sink = |f: u64| {
+        progress.leaf_done(f);
+        if progress.leaves() >= 10 {
+            token.cancel();
+        }
+    };
+    let monitor = Monitor::new()
+        .with_progress(&sink)
+        .with_cancellation(token.clone());
+    let mut dest = Array::zeros((size, size));
+    let result =
+        my_ndarray::mult_monitored(an.view(), bn.view(), dest.view_mut(), &Global, &monitor);
+    assert_eq!(result.err(), Some(Cancelled));
+    assert!(progress.fraction() < 1.0);
+
+    // cancelled once every leaf is done: the result is complete
+    let token = CancellationToken::new();
+    let progress = Progress::new(size, size, size);
+    let sink = |f: u64| {
+        progress.leaf_done(f);
+        if progress.fraction() >= 1.0 {
+            token.cancel();
+        }
+    };
+    let monitor = Monitor::new()
+        .with_progress(&sink)
+        .with_cancellation(token.clone());
+    let mut dest = Array::zeros((size, size));
+    let result =
+        my_ndarray::mult_monitored(an.view(), bn.view(), dest.view_mut(), &Global, &monitor);
+    assert!(result.is_ok());
+    assert!(token.is_cancelled());
+
+    // a monitor reused with a new token after a cancelled run
+    let token = CancellationToken::new();
+    let leaves = AtomicUsize::new(0);
+    let sink = |_: u64| {
+        if leaves.fetch_add(1, Ordering::Relaxed) == 0 {
+            token.cancel();
+        }
+    };
+    let monitor = Monitor::new()
+        .with_progress(&sink)
+        .with_cancellation(token.clone());
+    let mut dest = Array::zeros((size, size));
+    let result =
+        my_ndarray::mult_monitored(an.view(), bn.view(), dest.view_mut(), &Sequential, &monitor);
+    assert_eq!(result.err(), Some(Cancelled));
+    let monitor = monitor.with_cancellation(CancellationToken::new());
+    let mut dest = Array::zeros((size, size));
+    let result =
+        my_ndarray::mult_monitored(an.view(), bn.view(), dest.view_mut(), &Sequential, &monitor);
+    assert!(result.is_ok());
+
+    // same with a rayon_adaptive driver
+    let token = CancellationToken::new();
+    token.cancel();
+    let monitor = Monitor::new().with_cancellation(token);
+    let mut dest = Array::zeros((size, size));
+    let mat = matrix::Matrix {
+        matrix: vec![([(an.view(), bn.view())].to_vec(), dest.view_mut())],
+    };
+    let result = for_each_monitored(&Adaptive(Policy::Join(1000)), mat.cut(), &monitor, |e| {
+        let mut done = 0;
+        for (vect, mut output) in e.matrix {
+            for (a, b) in vect {
+                linalg::general_mat_mul(1.0, &a, &b, 1.0, &mut output);
+                done += flops(a.rows(), b.cols(), a.cols());
+            }
+        }
+        done
+    });
+    assert_eq!(result, Err(Cancelled));
+    assert!(dest.iter().all(|x| *x == 0.0));
+}
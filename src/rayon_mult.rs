@@ -1,16 +1,7 @@
-const _USAGE: &str = "
-Usage: matmul bench [--size N]
-       matmul --help
-Parallel matrix multiplication.
-Commands:
-    bench           Run the benchmark in different modes and print the timings.
-Options:
-    --size N        Row-size of matrices (rounded up to power of 2) [default: 1024]
-    -h, --help      Show this message.
-";
-
-use rayon;
-use rayon::prelude::*;
+use crate::executor::{Executor, Global};
+use crate::my_ndarray::LEAF_SIZE;
+use crate::roofline;
+use std::mem::size_of;
 use std::time::Instant;
 
 // TODO: Investigate other cache patterns for row-major order that may be more
@@ -129,8 +120,15 @@ fn quarter_chunks_mut(v: &mut [f32]) -> (&mut [f32], &mut [f32], &mut [f32], &mu
     (a, b, c, d)
 }
 
-fn join4<F1, F2, F3, F4, R1, R2, R3, R4>(f1: F1, f2: F2, f3: F3, f4: F4) -> (R1, R2, R3, R4)
+fn join4<E, F1, F2, F3, F4, R1, R2, R3, R4>(
+    executor: &E,
+    f1: F1,
+    f2: F2,
+    f3: F3,
+    f4: F4,
+) -> (R1, R2, R3, R4)
 where
+    E: Executor,
     F1: FnOnce() -> R1 + Send,
     R1: Send,
     F2: FnOnce() -> R2 + Send,
@@ -140,11 +138,15 @@ where
     F4: FnOnce() -> R4 + Send,
     R4: Send,
 {
-    let ((r1, r2), (r3, r4)) = rayon::join(|| rayon::join(f1, f2), || rayon::join(f3, f4));
+    let ((r1, r2), (r3, r4)) = executor.join(
+        || executor.join(f1, f2),
+        || executor.join(f3, f4),
+    );
     (r1, r2, r3, r4)
 }
 
-fn join8<F1, F2, F3, F4, F5, F6, F7, F8, R1, R2, R3, R4, R5, R6, R7, R8>(
+fn join8<E, F1, F2, F3, F4, F5, F6, F7, F8, R1, R2, R3, R4, R5, R6, R7, R8>(
+    executor: &E,
     f1: F1,
     f2: F2,
     f3: F3,
@@ -155,6 +157,7 @@ fn join8<F1, F2, F3, F4, F5, F6, F7, F8, R1, R2, R3, R4, R5, R6, R7, R8>(
     f8: F8,
 ) -> (R1, R2, R3, R4, R5, R6, R7, R8)
 where
+    E: Executor,
     F1: FnOnce() -> R1 + Send,
     R1: Send,
     F2: FnOnce() -> R2 + Send,
@@ -172,15 +175,19 @@ where
     F8: FnOnce() -> R8 + Send,
     R8: Send,
 {
-    let (((r1, r2), (r3, r4)), ((r5, r6), (r7, r8))) = rayon::join(
-        || rayon::join(|| rayon::join(f1, f2), || rayon::join(f3, f4)),
-        || rayon::join(|| rayon::join(f5, f6), || rayon::join(f7, f8)),
+    let (((r1, r2), (r3, r4)), ((r5, r6), (r7, r8))) = executor.join(
+        || executor.join(|| executor.join(f1, f2), || executor.join(f3, f4)),
+        || executor.join(|| executor.join(f5, f6), || executor.join(f7, f8)),
     );
     (r1, r2, r3, r4, r5, r6, r7, r8)
 }
 
 // Multiply two square power of two matrices, given in Z-order.
 pub fn matmulz(a: &[f32], b: &[f32], dest: &mut [f32]) {
+    matmulz_with(a, b, dest, &Global)
+}
+
+pub fn matmulz_with<E: Executor>(a: &[f32], b: &[f32], dest: &mut [f32], executor: &E) {
     if a.len() <= MULT_CHUNK {
         seq_matmulz(a, b, dest);
         return;
@@ -196,22 +203,27 @@ pub fn matmulz(a: &[f32], b: &[f32], dest: &mut [f32]) {
         let (t1, t2, t3, t4) = quarter_chunks_mut(&mut tmp[..]);
         // Multiply 8 submatrices
         join8(
-            || matmulz(a1, b1, d1),
-            || matmulz(a1, b2, d2),
-            || matmulz(a3, b1, d3),
-            || matmulz(a3, b2, d4),
-            || matmulz(a2, b3, t1),
-            || matmulz(a2, b4, t2),
-            || matmulz(a4, b3, t3),
-            || matmulz(a4, b4, t4),
+            executor,
+            || matmulz_with(a1, b1, d1, executor),
+            || matmulz_with(a1, b2, d2, executor),
+            || matmulz_with(a3, b1, d3, executor),
+            || matmulz_with(a3, b2, d4, executor),
+            || matmulz_with(a2, b3, t1, executor),
+            || matmulz_with(a2, b4, t2, executor),
+            || matmulz_with(a4, b3, t3, executor),
+            || matmulz_with(a4, b4, t4, executor),
         );
     }
 
     // Sum each quarter
-    rmatsum(tmp.as_mut(), dest);
+    rmatsum(tmp.as_mut(), dest, executor);
 }
 
 pub fn matmul_strassen(a: &[f32], b: &[f32], dest: &mut [f32]) {
+    matmul_strassen_with(a, b, dest, &Global)
+}
+
+pub fn matmul_strassen_with<E: Executor>(a: &[f32], b: &[f32], dest: &mut [f32], executor: &E) {
     if a.len() <= MULT_CHUNK {
         seq_matmulz(a, b, dest);
         return;
@@ -223,23 +235,25 @@ pub fn matmul_strassen(a: &[f32], b: &[f32], dest: &mut [f32]) {
     // 7 submatrix multiplies.
     // Maybe the tree should be leaning the other way...
     let (m1, m2, m3, m4, m5, m6, m7, _) = join8(
-        || strassen_add2_mul(a11, a22, b11, b22),
-        || strassen_add_mul(a21, a22, b11),
-        || strassen_sub_mul(b12, b22, a11),
-        || strassen_sub_mul(b21, b11, a22),
-        || strassen_add_mul(a11, a12, b22),
-        || strassen_sub_add_mul(a21, a11, b11, b12),
-        || strassen_sub_add_mul(a12, a22, b21, b22),
+        executor,
+        || strassen_add2_mul(a11, a22, b11, b22, executor),
+        || strassen_add_mul(a21, a22, b11, executor),
+        || strassen_sub_mul(b12, b22, a11, executor),
+        || strassen_sub_mul(b21, b11, a22, executor),
+        || strassen_add_mul(a11, a12, b22, executor),
+        || strassen_sub_add_mul(a21, a11, b11, b12, executor),
+        || strassen_sub_add_mul(a12, a22, b21, b22, executor),
         || (),
     );
 
     // Sum results into dest.
     let (c11, c12, c21, c22) = quarter_chunks_mut(dest);
     join4(
-        || strassen_sum_sub(&m1[..], &m4[..], &m7[..], &m5[..], c11),
-        || strassen_sum(&m3[..], &m5[..], c12),
-        || strassen_sum(&m2[..], &m4[..], c21),
-        || strassen_sum_sub(&m1[..], &m3[..], &m6[..], &m2[..], c22),
+        executor,
+        || strassen_sum_sub(&m1[..], &m4[..], &m7[..], &m5[..], c11, executor),
+        || strassen_sum(&m3[..], &m5[..], c12, executor),
+        || strassen_sum(&m2[..], &m4[..], c21, executor),
+        || strassen_sum_sub(&m1[..], &m3[..], &m6[..], &m2[..], c22, executor),
     );
 }
 
@@ -251,74 +265,111 @@ fn raw_buffer(n: usize) -> Vec<f32> {
     tmp
 }
 
-fn strassen_add2_mul(a1: &[f32], a2: &[f32], b1: &[f32], b2: &[f32]) -> Vec<f32> {
+fn strassen_add2_mul<E: Executor>(
+    a1: &[f32],
+    a2: &[f32],
+    b1: &[f32],
+    b2: &[f32],
+    executor: &E,
+) -> Vec<f32> {
     let mut dest = raw_buffer(a1.len());
-    let (a, b) = rayon::join(|| rtmp_sum(a1, a2), || rtmp_sum(b1, b2));
-    matmul_strassen(&a[..], &b[..], &mut dest[..]);
+    let (a, b) = executor.join(
+        || rtmp_sum(a1, a2, executor),
+        || rtmp_sum(b1, b2, executor),
+    );
+    matmul_strassen_with(&a[..], &b[..], &mut dest[..], executor);
     dest
 }
 
-fn strassen_sub_add_mul(a1: &[f32], a2: &[f32], b1: &[f32], b2: &[f32]) -> Vec<f32> {
+fn strassen_sub_add_mul<E: Executor>(
+    a1: &[f32],
+    a2: &[f32],
+    b1: &[f32],
+    b2: &[f32],
+    executor: &E,
+) -> Vec<f32> {
     let mut dest = raw_buffer(a1.len());
-    let (a, b) = rayon::join(|| rtmp_sub(a1, a2), || rtmp_sum(b1, b2));
-    matmul_strassen(&a[..], &b[..], &mut dest[..]);
+    let (a, b) = executor.join(
+        || rtmp_sub(a1, a2, executor),
+        || rtmp_sum(b1, b2, executor),
+    );
+    matmul_strassen_with(&a[..], &b[..], &mut dest[..], executor);
     dest
 }
 
-fn strassen_add_mul(a1: &[f32], a2: &[f32], b: &[f32]) -> Vec<f32> {
+fn strassen_add_mul<E: Executor>(a1: &[f32], a2: &[f32], b: &[f32], executor: &E) -> Vec<f32> {
     let mut dest = raw_buffer(a1.len());
-    let a = rtmp_sum(a1, a2);
-    matmul_strassen(&a[..], b, &mut dest[..]);
+    let a = rtmp_sum(a1, a2, executor);
+    matmul_strassen_with(&a[..], b, &mut dest[..], executor);
     dest
 }
 
-fn strassen_sub_mul(b1: &[f32], b2: &[f32], a: &[f32]) -> Vec<f32> {
+fn strassen_sub_mul<E: Executor>(b1: &[f32], b2: &[f32], a: &[f32], executor: &E) -> Vec<f32> {
     let mut dest = raw_buffer(a.len());
-    let b = rtmp_sub(b1, b2);
-    matmul_strassen(a, &b[..], &mut dest[..]);
+    let b = rtmp_sub(b1, b2, executor);
+    matmul_strassen_with(a, &b[..], &mut dest[..], executor);
     dest
 }
 
-fn strassen_sum_sub(a: &[f32], b: &[f32], c: &[f32], s: &[f32], dest: &mut [f32]) {
-    rcopy(a, dest);
-    rmatsum(b, dest);
-    rmatsum(c, dest);
-    rmatsub(s, dest);
+fn strassen_sum_sub<E: Executor>(
+    a: &[f32],
+    b: &[f32],
+    c: &[f32],
+    s: &[f32],
+    dest: &mut [f32],
+    executor: &E,
+) {
+    rcopy(a, dest, executor);
+    rmatsum(b, dest, executor);
+    rmatsum(c, dest, executor);
+    rmatsub(s, dest, executor);
 }
 
-fn strassen_sum(a: &[f32], b: &[f32], dest: &mut [f32]) {
-    rcopy(a, dest);
-    rmatsum(b, dest);
+fn strassen_sum<E: Executor>(a: &[f32], b: &[f32], dest: &mut [f32], executor: &E) {
+    rcopy(a, dest, executor);
+    rmatsum(b, dest, executor);
 }
 
-fn rtmp_sum(a: &[f32], b: &[f32]) -> Vec<f32> {
+fn rtmp_sum<E: Executor>(a: &[f32], b: &[f32], executor: &E) -> Vec<f32> {
     let mut tmp = raw_buffer(a.len());
-    rcopy(a, &mut tmp[..]);
-    rmatsum(b, &mut tmp[..]);
+    rcopy(a, &mut tmp[..], executor);
+    rmatsum(b, &mut tmp[..], executor);
     tmp
 }
 
-fn rtmp_sub(a: &[f32], b: &[f32]) -> Vec<f32> {
+fn rtmp_sub<E: Executor>(a: &[f32], b: &[f32], executor: &E) -> Vec<f32> {
     let mut tmp = raw_buffer(a.len());
-    rcopy(a, &mut tmp[..]);
-    rmatsub(b, &mut tmp[..]);
+    rcopy(a, &mut tmp[..], executor);
+    rmatsub(b, &mut tmp[..], executor);
     tmp
 }
 
 // Any layout works, we're just adding by element.
-fn rmatsum(src: &[f32], dest: &mut [f32]) {
-    dest.par_iter_mut()
-        .zip(src.par_iter())
-        .for_each(|(d, s)| *d += *s);
+fn rmatsum<E: Executor>(src: &[f32], dest: &mut [f32], executor: &E) {
+    if dest.len() <= LINEAR_CHUNK {
+        dest.iter_mut().zip(src.iter()).for_each(|(d, s)| *d += *s);
+        return;
+    }
+
+    let mid = dest.len() / 2;
+    let (s1, s2) = src.split_at(mid);
+    let (d1, d2) = dest.split_at_mut(mid);
+    executor.join(|| rmatsum(s1, d1, executor), || rmatsum(s2, d2, executor));
 }
 
-fn rmatsub(src: &[f32], dest: &mut [f32]) {
-    dest.par_iter_mut()
-        .zip(src.par_iter())
-        .for_each(|(d, s)| *d -= *s);
+fn rmatsub<E: Executor>(src: &[f32], dest: &mut [f32], executor: &E) {
+    if dest.len() <= LINEAR_CHUNK {
+        dest.iter_mut().zip(src.iter()).for_each(|(d, s)| *d -= *s);
+        return;
+    }
+
+    let mid = dest.len() / 2;
+    let (s1, s2) = src.split_at(mid);
+    let (d1, d2) = dest.split_at_mut(mid);
+    executor.join(|| rmatsub(s1, d1, executor), || rmatsub(s2, d2, executor));
 }
 
-fn rcopy(src: &[f32], dest: &mut [f32]) {
+fn rcopy<E: Executor>(src: &[f32], dest: &mut [f32], executor: &E) {
     if dest.len() <= LINEAR_CHUNK {
         dest.copy_from_slice(src);
         return;
@@ -327,7 +378,7 @@ fn rcopy(src: &[f32], dest: &mut [f32]) {
     let mid = dest.len() / 2;
     let (s1, s2) = src.split_at(mid);
     let (d1, d2) = dest.split_at_mut(mid);
-    rayon::join(|| rcopy(s1, d1), || rcopy(s2, d2));
+    executor.join(|| rcopy(s1, d1, executor), || rcopy(s2, d2, executor));
 }
 
 #[test]
@@ -380,30 +431,7 @@ pub fn timed_matmul<F: FnOnce(&[f32], &[f32], &mut [f32])>(size: usize, f: F, na
     f(&a[..], &b[..], &mut dest[..]);
     let dur = Instant::now() - start;
     let nanos = u64::from(dur.subsec_nanos()) + dur.as_secs() * 1_000_000_000u64;
-    println!(
-        "{}:\t{}x{} matrix: {} s",
-        name,
-        size,
-        size,
-        nanos as f32 / 1e9f32
-    );
+    // leaves of MULT_CHUNK elements: LEAF_SIZE x LEAF_SIZE blocks
+    roofline::print_timing(name, size, LEAF_SIZE, size_of::<f32>(), nanos);
     nanos
 }
-
-pub fn main() {
-    let row_size = 256;
-    let n = row_size;
-    if n <= 1024 {
-        // Crappy algorithm takes several minutes on larger inputs.
-        timed_matmul(n, seq_matmul, "seq row-major");
-    }
-    let seq = if n <= 2048 {
-        timed_matmul(n, seq_matmulz, "seq z-order")
-    } else {
-        0
-    };
-    let par = timed_matmul(n, matmulz, "par z-order");
-    timed_matmul(n, matmul_strassen, "par strassen");
-    let speedup = seq as f64 / par as f64;
-    println!("speedup: {:.2}x", speedup);
-}
@@ -0,0 +1,129 @@
+use crate::report::BenchResult;
+use crate::stats::{welch_t_test, TTest};
+use std::io::{self, Write};
+
+///
+/// A configuration measured again against its previous result.
+///
+#[derive(Clone, Debug, PartialEq)]
+pub struct Comparison {
+    pub baseline: BenchResult,
+    pub current: BenchResult,
+    /// Relative change of the mean time: 0.1 is 10% slower, -0.1 10% faster.
+    pub change: f64,
+    /// None when a side has not enough samples (or no variance) for the test.
+    pub test: Option<TTest>,
+}
+
+impl Comparison {
+    pub fn new(baseline: BenchResult, current: BenchResult) -> Self {
+        let change = current.times.mean / baseline.times.mean - 1.0;
+        let test = welch_t_test(&baseline.times, &current.times);
+        Comparison {
+            baseline,
+            current,
+            change,
+            test,
+        }
+    }
+
+    ///
+    /// Slower by more than `threshold` (relative), and significantly so at level `alpha`.
+    /// Without a test (single samples) the threshold alone decides.
+    ///
+    pub fn is_regression(&self, threshold: f64, alpha: f64) -> bool {
+        self.change > threshold && self.test.map_or(true, |test| test.p_value < alpha)
+    }
+}
+
+/// Same kernel, policy, shape and number of threads.
+pub fn same_configuration(a: &BenchResult, b: &BenchResult) -> bool {
+    a.kernel == b.kernel && a.policy == b.policy && a.shape == b.shape && a.threads == b.threads
+}
+
+///
+/// Pair each baseline result with the current result of the same configuration.
+/// Configurations missing on either side are left out.
+///
+pub fn compare(baseline: &[BenchResult], current: &[BenchResult]) -> Vec<Comparison> {
+    baseline
+        .iter()
+        .filter_map(|b| {
+            current
+                .iter()
+                .find(|c| same_configuration(b, c))
+                .map(|c| Comparison::new(b.clone(), c.clone()))
+        })
+        .collect()
+}
+
+/// One line per comparison, regressions marked.
+pub fn write_table<W: Write>(
+    mut writer: W,
+    comparisons: &[Comparison],
+    threshold: f64,
+    alpha: f64,
+) -> io::Result<()> {
+    writeln!(
+        writer,
+        "{:<12} {:<12} {:>16} {:>7} {:>12} {:>12} {:>8} {:>8}",
+        "kernel", "policy", "shape", "threads", "before (s)", "after (s)", "change", "p-value"
+    )?;
+    for c in comparisons {
+        let (m, n, k) = c.current.shape;
+        let p_value = c
+            .test
+            .map(|test| format!("{:.4}", test.p_value))
+            .unwrap_or_else(|| "-".to_owned());
+        writeln!(
+            writer,
+            "{:<12} {:<12} {:>16} {:>7} {:>12.6} {:>12.6} {:>+7.1}% {:>8}{}",
+            c.current.kernel,
+            c.current.policy,
+            format!("{}x{}x{}", m, n, k),
+            c.current.threads,
+            c.baseline.times.mean / 1e9,
+            c.current.times.mean / 1e9,
+            c.change * 100.0,
+            p_value,
+            if c.is_regression(threshold, alpha) {
+                "  REGRESSION"
+            } else {
+                ""
+            }
+        )?;
+    }
+    writer.flush()
+}
+
+#[test]
+fn test_compare() {
+    let baseline = vec![
+        BenchResult::new("blas", "join", (64, 64, 64), 4, &[100.0, 101.0, 99.0, 100.0]),
+        BenchResult::new("blas", "rayon", (64, 64, 64), 4, &[100.0, 140.0, 60.0, 100.0]),
+        BenchResult::new("blas", "adaptive", (64, 64, 64), 4, &[100.0]),
+    ];
+    let current = vec![
+        BenchResult::new("blas", "adaptive", (64, 64, 64), 4, &[120.0]),
+        BenchResult::new("blas", "rayon", (64, 64, 64), 4, &[110.0, 150.0, 70.0, 110.0]),
+        BenchResult::new("blas", "join", (64, 64, 64), 4, &[120.0, 121.0, 119.0, 120.0]),
+        BenchResult::new("blas", "join", (64, 64, 64), 2, &[500.0]),
+    ];
+    let comparisons = compare(&baseline, &current);
+    assert_eq!(comparisons.len(), 3);
+    // clearly slower
+    assert!((comparisons[0].change - 0.2).abs() < 1e-12);
+    assert!(comparisons[0].is_regression(0.05, 0.05));
+    assert!(!comparisons[0].is_regression(0.25, 0.05));
+    // slower but within the noise
+    assert!(comparisons[1].change > 0.05);
+    assert!(!comparisons[1].is_regression(0.05, 0.05));
+    // a single sample: no test
+    assert_eq!(comparisons[2].test, None);
+    assert!(comparisons[2].is_regression(0.05, 0.05));
+    let mut table = Vec::new();
+    write_table(&mut table, &comparisons, 0.05, 0.05).expect("table failed");
+    let table = String::from_utf8(table).unwrap();
+    assert_eq!(table.lines().count(), 4);
+    assert_eq!(table.matches("REGRESSION").count(), 2);
+}
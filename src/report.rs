@@ -0,0 +1,395 @@
+use crate::stats::Summary;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::Path;
+
+///
+/// Timings of one benchmark configuration, in nanoseconds.
+/// The shape is the one of the product: (m, n, k) for (m x k) * (k x n).
+///
+#[derive(Clone, Debug, PartialEq)]
+pub struct BenchResult {
+    pub kernel: String,
+    pub policy: String,
+    pub shape: (usize, usize, usize),
+    pub threads: usize,
+    pub times: Summary,
+}
+
+const CSV_HEADER: &str = "kernel,policy,m,n,k,threads,samples,mean_ns,median_ns,q1_ns,q3_ns,min_ns,max_ns,stddev_ns,gflops";
+
+impl BenchResult {
+    pub fn new(
+        kernel: &str,
+        policy: &str,
+        shape: (usize, usize, usize),
+        threads: usize,
+        times_ns: &[f64],
+    ) -> Self {
+        BenchResult {
+            kernel: kernel.to_owned(),
+            policy: policy.to_owned(),
+            shape,
+            threads,
+            times: Summary::new(times_ns),
+        }
+    }
+
+    /// Floating point operations per second of the median run, in GFLOP/s.
+    pub fn gflops(&self) -> f64 {
+        let (m, n, k) = self.shape;
+        // flops per nanosecond are GFLOP/s
+        2.0 * (m * n * k) as f64 / self.times.median
+    }
+
+    fn csv_row(&self) -> String {
+        let (m, n, k) = self.shape;
+        let t = &self.times;
+        format!(
+            "{},{},{},{},{},{},{},{},{},{},{},{},{},{},{}",
+            csv_field(&self.kernel),
+            csv_field(&self.policy),
+            m,
+            n,
+            k,
+            self.threads,
+            t.samples,
+            t.mean,
+            t.median,
+            t.q1,
+            t.q3,
+            t.min,
+            t.max,
+            t.stddev,
+            self.gflops()
+        )
+    }
+
+    fn json_object(&self) -> String {
+        let (m, n, k) = self.shape;
+        let t = &self.times;
+        format!(
+            "{{\"kernel\": {}, \"policy\": {}, \"m\": {}, \"n\": {}, \"k\": {}, \"threads\": {}, \
+             \"samples\": {}, \"mean_ns\": {}, \"median_ns\": {}, \"q1_ns\": {}, \"q3_ns\": {}, \
+             \"min_ns\": {}, \"max_ns\": {}, \"stddev_ns\": {}, \"gflops\": {}}}",
+            json_string(&self.kernel),
+            json_string(&self.policy),
+            m,
+            n,
+            k,
+            self.threads,
+            t.samples,
+            json_number(t.mean),
+            json_number(t.median),
+            json_number(t.q1),
+            json_number(t.q3),
+            json_number(t.min),
+            json_number(t.max),
+            json_number(t.stddev),
+            json_number(self.gflops())
+        )
+    }
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// A result from the columns of a CSV row or the members of a JSON object.
+fn from_record(record: &HashMap<String, String>) -> io::Result<BenchResult> {
+    let text = |key: &str| {
+        record
+            .get(key)
+            .ok_or_else(|| invalid(format!("missing '{}' in benchmark result", key)))
+    };
+    let number = |key: &str| -> io::Result<f64> {
+        let value = text(key)?;
+        if value == "null" {
+            return Ok(f64::NAN);
+        }
+        value
+            .parse::<f64>()
+            .map_err(|_| invalid(format!("invalid {} '{}'", key, value)))
+    };
+    let count = |key: &str| number(key).map(|n| n as usize);
+    Ok(BenchResult {
+        kernel: text("kernel")?.clone(),
+        policy: text("policy")?.clone(),
+        shape: (count("m")?, count("n")?, count("k")?),
+        threads: count("threads")?,
+        times: Summary {
+            samples: count("samples")?,
+            mean: number("mean_ns")?,
+            stddev: number("stddev_ns")?,
+            min: number("min_ns")?,
+            q1: number("q1_ns")?,
+            median: number("median_ns")?,
+            q3: number("q3_ns")?,
+            max: number("max_ns")?,
+        },
+    })
+}
+
+fn csv_field(field: &str) -> String {
+    if field.contains(|c| c == ',' || c == '"' || c == '\n') {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_owned()
+    }
+}
+
+/// JSON has no NaN nor infinities: they are written as null.
+fn json_number(x: f64) -> String {
+    if x.is_finite() {
+        x.to_string()
+    } else {
+        "null".to_owned()
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len() + 2);
+    escaped.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+/// Fields of a CSV line, with double quoted fields.
+fn split_csv(line: &str) -> Vec<String> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => fields.push(std::mem::replace(&mut field, String::new())),
+            c => field.push(c),
+        }
+    }
+    fields.push(field);
+    fields
+}
+
+/// Read the results written by write_csv.
+pub fn read_csv<R: BufRead>(reader: R) -> io::Result<Vec<BenchResult>> {
+    let mut lines = reader.lines();
+    let header = match lines.next() {
+        Some(line) => split_csv(&line?),
+        None => return Ok(Vec::new()),
+    };
+    let mut results = Vec::new();
+    for line in lines {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record = header.iter().cloned().zip(split_csv(&line)).collect();
+        results.push(from_record(&record)?);
+    }
+    Ok(results)
+}
+
+/// The 4 hexadecimal digits of a \u escape.
+fn json_code_unit<I: Iterator<Item = char>>(chars: &mut I) -> io::Result<u32> {
+    let code: String = chars.by_ref().take(4).collect();
+    if code.len() != 4 || !code.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(invalid(format!("invalid JSON escape '\\u{}'", code)));
+    }
+    Ok(u32::from_str_radix(&code, 16).expect("hexadecimal digits"))
+}
+
+/// A JSON string starting at `chars` (after the opening quote).
+fn json_string_value<I: Iterator<Item = char>>(chars: &mut I) -> io::Result<String> {
+    let mut value = String::new();
+    loop {
+        match chars.next() {
+            Some('"') => return Ok(value),
+            Some('\\') => match chars.next() {
+                Some(c @ '"') | Some(c @ '\\') | Some(c @ '/') => value.push(c),
+                Some('b') => value.push('\u{8}'),
+                Some('f') => value.push('\u{c}'),
+                Some('n') => value.push('\n'),
+                Some('r') => value.push('\r'),
+                Some('t') => value.push('\t'),
+                Some('u') => {
+                    let high = json_code_unit(chars)?;
+                    // characters outside the BMP are a surrogate pair: \uD8xx\uDCxx
+                    let code = if (0xD800..0xDC00).contains(&high) {
+                        if chars.next() != Some('\\') || chars.next() != Some('u') {
+                            return Err(invalid("unpaired JSON surrogate".to_owned()));
+                        }
+                        let low = json_code_unit(chars)?;
+                        if !(0xDC00..0xE000).contains(&low) {
+                            return Err(invalid("unpaired JSON surrogate".to_owned()));
+                        }
+                        0x10000 + ((high - 0xD800) << 10) + (low - 0xDC00)
+                    } else {
+                        high
+                    };
+                    let c = std::char::from_u32(code).ok_or_else(|| {
+                        invalid(format!("invalid JSON escape '\\u{:04x}'", code))
+                    })?;
+                    value.push(c);
+                }
+                Some(c) => return Err(invalid(format!("invalid JSON escape '\\{}'", c))),
+                None => break,
+            },
+            Some(c) => value.push(c),
+            None => break,
+        }
+    }
+    Err(invalid("unterminated JSON string".to_owned()))
+}
+
+///
+/// Read the results written by write_json: an array of flat objects
+/// with string or number members.
+///
+pub fn read_json<R: Read>(mut reader: R) -> io::Result<Vec<BenchResult>> {
+    let mut text = String::new();
+    reader.read_to_string(&mut text)?;
+    let mut chars = text.chars().peekable();
+    let mut results = Vec::new();
+    let mut record = HashMap::new();
+    let mut key: Option<String> = None;
+    let mut depth = 0;
+    while let Some(c) = chars.next() {
+        match c {
+            '[' if depth == 0 => depth = 1,
+            '{' if depth == 1 => depth = 2,
+            '}' if depth == 2 => {
+                results.push(from_record(&record)?);
+                record.clear();
+                depth = 1;
+            }
+            ']' if depth == 1 => depth = 0,
+            '"' if depth == 2 => {
+                let s = json_string_value(&mut chars)?;
+                match key.take() {
+                    None => key = Some(s),
+                    Some(k) => {
+                        record.insert(k, s);
+                    }
+                }
+            }
+            ':' | ',' => (),
+            c if c.is_whitespace() => (),
+            c if depth == 2 && key.is_some() => {
+                let mut number = c.to_string();
+                while let Some(&next) = chars.peek() {
+                    if next == ',' || next == '}' || next.is_whitespace() {
+                        break;
+                    }
+                    number.push(next);
+                    chars.next();
+                }
+                record.insert(key.take().unwrap(), number);
+            }
+            c => return Err(invalid(format!("unexpected '{}' in JSON results", c))),
+        }
+    }
+    Ok(results)
+}
+
+/// Read a result file, JSON if its name ends in .json, CSV otherwise.
+pub fn load(path: &Path) -> io::Result<Vec<BenchResult>> {
+    let file = BufReader::new(File::open(path)?);
+    if path.extension().map_or(false, |e| e == "json") {
+        read_json(file)
+    } else {
+        read_csv(file)
+    }
+}
+
+/// One header line, then one line per result.
+pub fn write_csv<W: Write>(mut writer: W, results: &[BenchResult]) -> io::Result<()> {
+    writeln!(writer, "{}", CSV_HEADER)?;
+    for result in results {
+        writeln!(writer, "{}", result.csv_row())?;
+    }
+    writer.flush()
+}
+
+/// A JSON array of objects with the CSV columns as keys.
+pub fn write_json<W: Write>(mut writer: W, results: &[BenchResult]) -> io::Result<()> {
+    writeln!(writer, "[")?;
+    for (index, result) in results.iter().enumerate() {
+        let separator = if index + 1 < results.len() { "," } else { "" };
+        writeln!(writer, "  {}{}", result.json_object(), separator)?;
+    }
+    writeln!(writer, "]")?;
+    writer.flush()
+}
+
+#[test]
+fn test_report_formats() {
+    let results = vec![
+        BenchResult::new("blas", "adaptive", (100, 100, 100), 4, &[1e6, 2e6, 3e6]),
+        BenchResult::new("z-order", "say \"hi\", twice", (8, 4, 2), 1, &[10.0]),
+    ];
+    assert_eq!(results[0].gflops(), 1.0);
+    let mut csv = Vec::new();
+    write_csv(&mut csv, &results).expect("csv failed");
+    let csv = String::from_utf8(csv).unwrap();
+    let lines: Vec<&str> = csv.lines().collect();
+    assert_eq!(lines.len(), 3);
+    assert_eq!(lines[0].split(',').count(), 15);
+    assert!(lines[1].starts_with("blas,adaptive,100,100,100,4,3,2000000,2000000,1500000,"));
+    assert!(lines[2].starts_with("z-order,\"say \"\"hi\"\", twice\",8,4,2,1,1,10,"));
+    let mut json = Vec::new();
+    write_json(&mut json, &results).expect("json failed");
+    let json = String::from_utf8(json).unwrap();
+    assert!(json.contains("\"policy\": \"say \\\"hi\\\", twice\""));
+    assert!(json.contains("\"gflops\": 1}"));
+    assert_eq!(json.matches('{').count(), 2);
+    // an empty product in no time: 0 / 0 GFLOP/s
+    let empty = [BenchResult::new("blas", "join", (0, 4, 4), 1, &[0.0])];
+    let mut json = Vec::new();
+    write_json(&mut json, &empty).expect("json failed");
+    assert!(String::from_utf8(json).unwrap().contains("\"gflops\": null}"));
+}
+
+#[test]
+fn test_report_read_back() {
+    let results = vec![
+        BenchResult::new("blas", "adaptive", (100, 100, 100), 4, &[1e6, 2e6, 3e6]),
+        BenchResult::new("z-order", "say \"hi\", twice", (8, 4, 2), 1, &[10.5]),
+    ];
+    let mut csv = Vec::new();
+    write_csv(&mut csv, &results).expect("csv failed");
+    assert_eq!(read_csv(&csv[..]).expect("csv read failed"), results);
+    let mut json = Vec::new();
+    write_json(&mut json, &results).expect("json failed");
+    assert_eq!(read_json(&json[..]).expect("json read failed"), results);
+    assert!(read_csv(&b"kernel,policy\nblas,join\n"[..]).is_err());
+}
+
+#[test]
+fn test_json_escapes() {
+    let read = |text: &str| json_string_value(&mut text.chars());
+    let escaped = r#"a\"\\\/\b\f\n\r\t\u00e9\ud83d\ude00 z""#;
+    assert_eq!(
+        read(escaped).expect("valid escapes"),
+        "a\"\\/\u{8}\u{c}\n\r\t\u{e9}\u{1f600} z"
+    );
+    for bad in &[r#"\x""#, r#"\u12""#, r#"\u+123""#, r#"\ud83d x""#, r#"\ude00""#] {
+        assert!(read(bad).is_err(), "{}", bad);
+    }
+    let text = "tab\t, cr\r, bell\u{7}, quote \"";
+    assert_eq!(read(&json_string(text)[1..]).expect("written by json_string"), text);
+}
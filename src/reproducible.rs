@@ -0,0 +1,105 @@
+use crate::matrix_adaptive;
+use crate::simd::{self, SimdScalar};
+use ndarray::{ArrayView, ArrayViewMut, Ix2};
+use rayon_adaptive::Policy;
+#[cfg(test)]
+use ndarray::Array;
+#[cfg(test)]
+use rand::rngs::StdRng;
+#[cfg(test)]
+use rand::{Rng, SeedableRng};
+
+///
+/// result += a * b, adding each product a(i,k) * b(k,j) directly into result(i,j)
+/// in increasing k order.
+/// Vectorised along the rows of b and result (simd::axpy), which rounds each element as
+/// the scalar loop: the value of an output element does not depend on the block it
+/// belongs to.
+///
+pub fn mult_ordered<'a, 'b, 'd, A>(
+    a: ArrayView<'a, A, Ix2>,
+    b: ArrayView<'b, A, Ix2>,
+    mut result: ArrayViewMut<'d, A, Ix2>,
+) where
+    A: SimdScalar,
+{
+    assert_eq!(a.cols(), b.rows());
+    assert_eq!(a.rows(), result.rows());
+    assert_eq!(b.cols(), result.cols());
+    for (arow, mut drow) in a.genrows().into_iter().zip(result.genrows_mut()) {
+        for (aik, brow) in arow.iter().zip(b.genrows()) {
+            match (brow.as_slice(), drow.as_slice_mut()) {
+                (Some(bs), Some(ds)) => simd::axpy(*aik, bs, ds),
+                _ => {
+                    for (dij, bkj) in drow.iter_mut().zip(brow.iter()) {
+                        *dij = dij.add_product(*aik, *bkj);
+                    }
+                }
+            }
+        }
+    }
+}
+
+///
+/// result += a * b with a result that is bitwise identical for any policy and thread count
+/// (matrix_adaptive::Matrix::mult_deterministic).
+///
+pub fn mult_reproducible<'a, 'b, 'd, A>(
+    a: ArrayView<'a, A, Ix2>,
+    b: ArrayView<'b, A, Ix2>,
+    result: ArrayViewMut<'d, A, Ix2>,
+    policy: Policy,
+) where
+    A: SimdScalar,
+{
+    let m = matrix_adaptive::Matrix {
+        asize: a.dim(),
+        bsize: b.dim(),
+        dsize: result.dim(),
+        a,
+        b,
+        d: result,
+    };
+    m.mult_deterministic(policy);
+}
+
+#[test]
+fn test_reproducible() {
+    let height = 301;
+    let depth = 517;
+    let width = 263;
+    let mut rng = StdRng::seed_from_u64(0);
+    let an = Array::from_shape_fn((height, depth), |_| rng.gen_range(-1.0f32, 1.0));
+    let bn = Array::from_shape_fn((depth, width), |_| rng.gen_range(-1.0f32, 1.0));
+    // scalar loop in increasing k order: the vectorised leaf rounds the same way
+    let reference = Array::from_shape_fn((height, width), |(i, j)| {
+        (0..depth).fold(0.0f32, |sum, k| sum + an[(i, k)] * bn[(k, j)])
+    });
+    let mut ordered = Array::zeros((height, width));
+    mult_ordered(an.view(), bn.view(), ordered.view_mut());
+    assert!(ordered == reference);
+    let policies = vec![
+        Policy::Sequential,
+        Policy::Join(1000),
+        Policy::Join(7),
+        Policy::Rayon(1),
+        Policy::Adaptive(100, 5000),
+        Policy::Adaptive(1, 50),
+    ];
+    for threads in &[1, 2, 3, 8] {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(*threads)
+            .build()
+            .expect("Pool creation failed");
+        for policy in &policies {
+            let mut dest = Array::zeros((height, width));
+            pool.install(|| mult_reproducible(an.view(), bn.view(), dest.view_mut(), *policy));
+            assert!(
+                dest == reference,
+                "{:?} with {} threads is not bitwise identical",
+                policy,
+                threads
+            );
+        }
+    }
+}
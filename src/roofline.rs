@@ -0,0 +1,267 @@
+use crate::progress;
+use packed_simd::f32x8;
+use rayon::prelude::*;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+
+/// f64 elements in each array of the bandwidth probe: 3 x 32 MiB, beyond the caches.
+const STREAM_LEN: usize = 1 << 22;
+/// Iterations of each thread of the peak FLOPs probe.
+const PEAK_ITERATIONS: usize = 1 << 22;
+/// Independent accumulators, enough to hide the latency of the FMA units.
+const ACCUMULATORS: usize = 8;
+const PROBE_RUNS: usize = 5;
+
+fn elapsed_ns(start: Instant) -> u64 {
+    let dur = Instant::now() - start;
+    u64::from(dur.subsec_nanos()) + dur.as_secs() * 1_000_000_000u64
+}
+
+///
+/// STREAM triad (a[i] = b[i] + s * c[i]) on all threads, in GB/s.
+/// The best of several runs, counting 24 bytes per element as STREAM does.
+///
+pub fn stream_bandwidth(len: usize) -> f64 {
+    let mut a = vec![0f64; len];
+    let b = vec![1f64; len];
+    let c = vec![2f64; len];
+    let scalar = 3.0;
+    let best = (0..PROBE_RUNS)
+        .map(|_| {
+            let start = Instant::now();
+            a.par_iter_mut()
+                .zip(b.par_iter())
+                .zip(c.par_iter())
+                .for_each(|((a, b), c)| *a = b + scalar * c);
+            elapsed_ns(start)
+        })
+        .min()
+        .unwrap();
+    assert_eq!(a[len / 2], 7.0);
+    // bytes per nanosecond are GB/s
+    (3 * 8 * len) as f64 / best.max(1) as f64
+}
+
+/// Dependent chains of fused multiply-adds, returns a value depending on all of them.
+fn fma_chains(iterations: usize) -> f32 {
+    let x = f32x8::splat(0.999_999);
+    let y = f32x8::splat(1e-7);
+    let mut accumulators = [f32x8::splat(1.0); ACCUMULATORS];
+    for _ in 0..iterations {
+        for acc in accumulators.iter_mut() {
+            *acc = acc.mul_adde(x, y);
+        }
+    }
+    accumulators.iter().map(|acc| acc.sum()).sum()
+}
+
+///
+/// Single precision fused multiply-adds on all threads, in GFLOP/s (best of several runs).
+/// Each thread runs `iterations` steps of independent 8 wide FMA chains.
+///
+pub fn peak_gflops(iterations: usize) -> f64 {
+    let threads = rayon::current_num_threads();
+    let flops = (threads * iterations * ACCUMULATORS * f32x8::lanes() * 2) as f64;
+    let best = (0..PROBE_RUNS)
+        .map(|_| {
+            let start = Instant::now();
+            let total: f32 = (0..threads)
+                .into_par_iter()
+                .map(|_| fma_chains(iterations))
+                .sum();
+            assert!(total.is_finite());
+            elapsed_ns(start)
+        })
+        .min()
+        .unwrap();
+    flops / best.max(1) as f64
+}
+
+///
+/// The roofline model of a machine: a kernel of arithmetic intensity I
+/// (flop per byte of memory traffic) cannot exceed min(peak, bandwidth * I).
+///
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Roofline {
+    /// Memory bandwidth in GB/s.
+    pub bandwidth: f64,
+    /// Peak GFLOP/s.
+    pub peak_gflops: f64,
+}
+
+static MACHINE_BANDWIDTH: AtomicU64 = AtomicU64::new(0);
+static MACHINE_PEAK: AtomicU64 = AtomicU64::new(0);
+
+impl Roofline {
+    /// Run the bandwidth and peak FLOPs probes (about a second).
+    pub fn measure() -> Self {
+        Roofline {
+            bandwidth: stream_bandwidth(STREAM_LEN),
+            peak_gflops: peak_gflops(PEAK_ITERATIONS),
+        }
+    }
+
+    /// The roofline of this machine, measured at the first call.
+    pub fn machine() -> Self {
+        let bandwidth = MACHINE_BANDWIDTH.load(Ordering::Relaxed);
+        let peak = MACHINE_PEAK.load(Ordering::Relaxed);
+        if bandwidth != 0 && peak != 0 {
+            return Roofline {
+                bandwidth: f64::from_bits(bandwidth),
+                peak_gflops: f64::from_bits(peak),
+            };
+        }
+        let roofline = Roofline::measure();
+        MACHINE_BANDWIDTH.store(roofline.bandwidth.to_bits(), Ordering::Relaxed);
+        MACHINE_PEAK.store(roofline.peak_gflops.to_bits(), Ordering::Relaxed);
+        roofline
+    }
+
+    /// Best GFLOP/s reachable at this arithmetic intensity.
+    pub fn attainable(&self, intensity: f64) -> f64 {
+        self.peak_gflops.min(self.bandwidth * intensity)
+    }
+
+    /// Intensity above which kernels are compute bound.
+    pub fn ridge_point(&self) -> f64 {
+        self.peak_gflops / self.bandwidth
+    }
+
+    /// Where a run sits under the roof.
+    pub fn describe(&self, run: &Measurement) -> String {
+        let intensity = run.intensity();
+        let bound = if intensity < self.ridge_point() {
+            "memory"
+        } else {
+            "compute"
+        };
+        format!(
+            "{:.1}% of the {:.2} GFLOP/s roof ({} bound)",
+            100.0 * run.gflops() / self.attainable(intensity),
+            self.attainable(intensity),
+            bound
+        )
+    }
+}
+
+///
+/// Estimated memory traffic in bytes of a (m x k) * (k x n) product computed by leaves
+/// of `block` = (bm, bn, bk): each leaf reads its blocks of A and B and reads and writes
+/// its block of C, nothing being reused from one leaf to the next.
+/// Never less than reading A and B and reading and writing C once.
+///
+pub fn bytes_moved(
+    (m, n, k): (usize, usize, usize),
+    (bm, bn, bk): (usize, usize, usize),
+    element_size: usize,
+) -> u64 {
+    let (bm, bn, bk) = (bm.min(m).max(1), bn.min(n).max(1), bk.min(k).max(1));
+    let leaves = ((m + bm - 1) / bm) * ((n + bn - 1) / bn) * ((k + bk - 1) / bk);
+    let per_leaf = bm * bk + bk * bn + 2 * bm * bn;
+    let compulsory = m * k + k * n + 2 * m * n;
+    (element_size * compulsory.max(leaves * per_leaf)) as u64
+}
+
+///
+/// One timed multiplication.
+///
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Measurement {
+    pub flops: u64,
+    pub bytes: u64,
+    pub nanos: u64,
+}
+
+impl Measurement {
+    pub fn new(
+        shape: (usize, usize, usize),
+        block: (usize, usize, usize),
+        element_size: usize,
+        nanos: u64,
+    ) -> Self {
+        let (m, n, k) = shape;
+        Measurement {
+            flops: progress::flops(m, n, k),
+            bytes: bytes_moved(shape, block, element_size),
+            nanos,
+        }
+    }
+
+    pub fn gflops(&self) -> f64 {
+        self.flops as f64 / self.nanos.max(1) as f64
+    }
+
+    /// Flop per byte of memory traffic.
+    pub fn intensity(&self) -> f64 {
+        self.flops as f64 / self.bytes as f64
+    }
+}
+
+impl fmt::Display for Measurement {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} s, {:.2} GFLOP/s, {:.1} MB moved, {:.2} flop/byte",
+            self.nanos as f32 / 1e9f32,
+            self.gflops(),
+            self.bytes as f64 / 1e6,
+            self.intensity()
+        )
+    }
+}
+
+///
+/// Print the timing of a square multiplication, its GFLOP/s and its position under
+/// the roofline of the machine (probed at the first call).
+///
+pub fn print_timing(name: &str, size: usize, block: usize, element_size: usize, nanos: u64) {
+    let run = Measurement::new(
+        (size, size, size),
+        (block, block, block),
+        element_size,
+        nanos,
+    );
+    println!(
+        "{}:\t{}x{} matrix: {}, {}",
+        name,
+        size,
+        size,
+        run,
+        Roofline::machine().describe(&run)
+    );
+}
+
+#[test]
+fn test_bytes_moved() {
+    // one leaf: compulsory traffic
+    assert_eq!(bytes_moved((4, 4, 4), (4, 4, 4), 4), 4 * (16 + 16 + 32));
+    assert_eq!(bytes_moved((4, 4, 4), (64, 64, 64), 4), 4 * (16 + 16 + 32));
+    // 8 leaves of 2x2x2 read 4 + 4 and write 2 * 4 elements each
+    assert_eq!(bytes_moved((4, 4, 4), (2, 2, 2), 8), 8 * 8 * 16);
+    // larger blocks move less
+    let shape = (1024, 1024, 1024);
+    assert!(bytes_moved(shape, (32, 32, 32), 4) < bytes_moved(shape, (8, 8, 8), 4));
+    let run = Measurement::new(shape, (32, 32, 32), 4, 1_000_000_000);
+    assert!((run.gflops() - 2.147_483_648).abs() < 1e-9);
+    assert!((run.intensity() - 4.0).abs() < 1e-9);
+}
+
+#[test]
+fn test_roofline() {
+    let roofline = Roofline {
+        bandwidth: 10.0,
+        peak_gflops: 100.0,
+    };
+    assert_eq!(roofline.ridge_point(), 10.0);
+    assert_eq!(roofline.attainable(2.0), 20.0);
+    assert_eq!(roofline.attainable(50.0), 100.0);
+    let run = Measurement {
+        flops: 10_000,
+        bytes: 5_000,
+        nanos: 1_000,
+    };
+    assert_eq!(roofline.describe(&run), "50.0% of the 20.00 GFLOP/s roof (memory bound)");
+    assert!(stream_bandwidth(1 << 12) > 0.0);
+    assert!(peak_gflops(1 << 8) > 0.0);
+}
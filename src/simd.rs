@@ -0,0 +1,122 @@
+use ndarray::LinalgScalar;
+use packed_simd::{f32x8, f64x4, i32x8, i64x4, u32x8, u64x4};
+use std::ops::{Add, Mul};
+
+///
+/// Scalar types and their packed_simd vector type, so one generic implementation of the
+/// packed_simd kernels (dot, axpy, reproducible::mult_ordered) covers f32, f64, i32, u32,
+/// i64 and u64. faster_vec::FasterScalar adds their faster vector type.
+/// Integer operations wrap on overflow.
+///
+pub trait SimdScalar: LinalgScalar + Send + Sync {
+    type Vector: Copy + Add<Output = Self::Vector> + Mul<Output = Self::Vector>;
+    const LANES: usize;
+    fn splat(x: Self) -> Self::Vector;
+    /// The first LANES values of the slice.
+    fn load(values: &[Self]) -> Self::Vector;
+    /// Write the lanes to the first LANES values of the slice.
+    fn store(vector: Self::Vector, values: &mut [Self]);
+    /// Sum of the lanes.
+    fn reduce_sum(vector: Self::Vector) -> Self;
+    /// self + x * y, the scalar counterpart of the vector operations.
+    fn add_product(self, x: Self, y: Self) -> Self;
+}
+
+macro_rules! simd_scalar {
+    ($scalar:ty, $vector:ident, $sum:ident, $add:ident, $mul:ident) => {
+        impl SimdScalar for $scalar {
+            type Vector = $vector;
+            const LANES: usize = $vector::lanes();
+            #[inline(always)]
+            fn splat(x: Self) -> $vector {
+                $vector::splat(x)
+            }
+            #[inline(always)]
+            fn load(values: &[Self]) -> $vector {
+                $vector::from_slice_unaligned(values)
+            }
+            #[inline(always)]
+            fn store(vector: $vector, values: &mut [Self]) {
+                vector.write_to_slice_unaligned(values)
+            }
+            #[inline(always)]
+            fn reduce_sum(vector: $vector) -> Self {
+                vector.$sum()
+            }
+            #[inline(always)]
+            fn add_product(self, x: Self, y: Self) -> Self {
+                self.$add(x.$mul(y))
+            }
+        }
+    };
+}
+
+simd_scalar!(f32, f32x8, sum, add, mul);
+simd_scalar!(f64, f64x4, sum, add, mul);
+simd_scalar!(i32, i32x8, wrapping_sum, wrapping_add, wrapping_mul);
+simd_scalar!(u32, u32x8, wrapping_sum, wrapping_add, wrapping_mul);
+simd_scalar!(i64, i64x4, wrapping_sum, wrapping_add, wrapping_mul);
+simd_scalar!(u64, u64x4, wrapping_sum, wrapping_add, wrapping_mul);
+
+///
+/// Scalar product of two slices of the same length, LANES products at a time.
+///
+#[inline(always)]
+pub fn dot<A: SimdScalar>(a: &[A], b: &[A]) -> A {
+    assert_eq!(a.len(), b.len());
+    let mut acc = A::splat(A::zero());
+    let mut chunks_a = a.chunks_exact(A::LANES);
+    let mut chunks_b = b.chunks_exact(A::LANES);
+    for (x, y) in (&mut chunks_a).zip(&mut chunks_b) {
+        acc = acc + A::load(x) * A::load(y);
+    }
+    chunks_a
+        .remainder()
+        .iter()
+        .zip(chunks_b.remainder())
+        .fold(A::reduce_sum(acc), |sum, (x, y)| sum.add_product(*x, *y))
+}
+
+///
+/// y += alpha * x, LANES elements at a time.
+/// A multiplication then an addition (no fused multiply-add), so every element is rounded
+/// exactly as by the scalar `y + alpha * x`.
+///
+#[inline(always)]
+pub fn axpy<A: SimdScalar>(alpha: A, x: &[A], y: &mut [A]) {
+    assert_eq!(x.len(), y.len());
+    let factor = A::splat(alpha);
+    let mut chunks_x = x.chunks_exact(A::LANES);
+    let mut chunks_y = y.chunks_exact_mut(A::LANES);
+    for (cx, cy) in (&mut chunks_x).zip(&mut chunks_y) {
+        A::store(A::load(cy) + factor * A::load(cx), cy);
+    }
+    for (vx, vy) in chunks_x.remainder().iter().zip(chunks_y.into_remainder()) {
+        *vy = vy.add_product(alpha, *vx);
+    }
+}
+
+#[test]
+fn test_dot() {
+    let a: Vec<f32> = (0..21).map(|i| i as f32).collect();
+    let b: Vec<f32> = (0..21).map(|i| (i % 3) as f32).collect();
+    let expected: f32 = a.iter().zip(&b).map(|(x, y)| x * y).sum();
+    assert_eq!(dot(&a, &b), expected);
+    let a: Vec<u32> = (0..13).collect();
+    assert_eq!(dot(&a, &a), (0..13).map(|i| i * i).sum());
+    let a: Vec<i64> = (-5..4).collect();
+    assert_eq!(dot(&a, &a), (-5i64..4).map(|i| i * i).sum());
+    assert_eq!(dot::<f64>(&[], &[]), 0.0);
+    // integers wrap, in the remainder too
+    let a = [u32::MAX; 3];
+    assert_eq!(dot(&a, &[2, 2, 2]), u32::MAX.wrapping_mul(6));
+}
+
+#[test]
+fn test_axpy() {
+    let x: Vec<f32> = (0..19).map(|i| i as f32 * 0.1).collect();
+    let mut y: Vec<f32> = (0..19).map(|i| 1.0 / (i + 1) as f32).collect();
+    let expected: Vec<f32> = y.iter().zip(&x).map(|(y, x)| *y + 0.3 * *x).collect();
+    axpy(0.3, &x, &mut y);
+    assert_eq!(y, expected);
+}
@@ -1,76 +1,406 @@
 use rayon_adaptive::prelude::*;
 use rayon_adaptive::BasicPower;
-use std::iter::{once, Once};
+use std::option;
+use std::vec;
+#[cfg(test)]
+use rayon_adaptive::Policy;
+#[cfg(test)]
+use std::ops::Range;
+
+///
+/// Any data processed adaptively: `splitter(data, index)` cuts it in two parts, the first
+/// one of length `index` as measured by `length` (approximately if the data cannot be
+/// cut exactly there, but not empty when index > 0).
+/// `divide_at` splits at the index given by the policy and `extract_iter` takes a prefix
+/// of `size`, so it works with `cut()` and with every `Policy` including `Adaptive`.
 ///
 pub struct Split<D, S, L>
 where
     D: Send,
-    S: Fn(D) -> (D, D) + Sync + Send + Clone,
+    S: Fn(D, usize) -> (D, D) + Sync + Send + Clone,
     L: Fn(&D) -> usize + Clone + Send,
 {
-    pub data: D,
+    /// None once extract_iter took everything.
+    pub data: Option<D>,
     pub splitter: S,
     pub length: L,
 }
 
+impl<D, S, L> Split<D, S, L>
+where
+    D: Send,
+    S: Fn(D, usize) -> (D, D) + Sync + Send + Clone,
+    L: Fn(&D) -> usize + Clone + Send,
+{
+    fn with_data(&self, data: Option<D>) -> Self {
+        Split {
+            data,
+            splitter: self.splitter.clone(),
+            length: self.length.clone(),
+        }
+    }
+}
+
 impl<D, S, L> Divisible for Split<D, S, L>
 where
     D: Send,
-    S: Fn(D) -> (D, D) + Sync + Send + Clone,
+    S: Fn(D, usize) -> (D, D) + Sync + Send + Clone,
     L: Fn(&D) -> usize + Clone + Send,
 {
     type Power = BasicPower;
     fn base_length(&self) -> Option<usize> {
-        Some((self.length)(&self.data))
+        Some(self.data.as_ref().map_or(0, &self.length))
     }
 
-    #[allow(unused_variables)]
-    fn divide_at(self, index: usize) -> (Self, Self) {
-        let (d1, d2) = (self.splitter)(self.data);
-        (
-            Split {
-                data: d1,
-                splitter: self.splitter.clone(),
-                length: self.length.clone(),
-            },
-            Split {
-                data: d2,
-                splitter: self.splitter,
-                length: self.length,
-            },
-        )
+    fn divide_at(mut self, index: usize) -> (Self, Self) {
+        match self.data.take() {
+            Some(data) => {
+                let (d1, d2) = (self.splitter)(data, index);
+                (self.with_data(Some(d1)), self.with_data(Some(d2)))
+            }
+            None => (self.with_data(None), self),
+        }
     }
 }
 
 impl<D, S, L> ParallelIterator for Split<D, S, L>
 where
     D: Send,
-    S: Fn(D) -> (D, D) + Sync + Send + Clone,
+    S: Fn(D, usize) -> (D, D) + Sync + Send + Clone,
     L: Fn(&D) -> usize + Clone + Send,
 {
     type Item = D;
 
-    type SequentialIterator = Once<D>;
+    type SequentialIterator = option::IntoIter<D>;
 
     fn to_sequential(self) -> Self::SequentialIterator {
-        once(self.data)
+        self.data.into_iter()
     }
 
-    #[allow(unused_variables)]
     fn extract_iter(&mut self, size: usize) -> Self::SequentialIterator {
-        panic!("extract_iter");
+        match self.data.take() {
+            Some(data) if (self.length)(&data) > size.max(1) => {
+                let (prefix, rest) = (self.splitter)(data, size.max(1));
+                self.data = Some(rest);
+                Some(prefix).into_iter()
+            }
+            data => data.into_iter(),
+        }
     }
 }
 
-pub fn split<D, S, L>(data: D, splitter: S, length: L) -> Split<D, S, L>
+///
+/// Split with a splitter cutting in halves, whatever the index.
+/// extract_iter then takes the first half instead of a prefix of the requested size.
+///
+pub fn split<D, S, L>(
+    data: D,
+    splitter: S,
+    length: L,
+) -> Split<D, impl Fn(D, usize) -> (D, D) + Sync + Send + Clone, L>
 where
     D: Send,
     S: Fn(D) -> (D, D) + Sync + Send + Clone,
     L: Fn(&D) -> usize + Clone + Send,
+{
+    split_at(data, move |data, _index| splitter(data), length)
+}
+
+///
+/// Split with a splitter cutting at the given index.
+///
+pub fn split_at<D, S, L>(data: D, splitter: S, length: L) -> Split<D, S, L>
+where
+    D: Send,
+    S: Fn(D, usize) -> (D, D) + Sync + Send + Clone,
+    L: Fn(&D) -> usize + Clone + Send,
 {
     Split {
-        data: data,
-        splitter: splitter,
-        length: length,
+        data: Some(data),
+        splitter,
+        length,
+    }
+}
+
+///
+/// A part of the data of a WeightedSplit, with its cost.
+/// Atomic once the splitter returned it alone.
+///
+struct Piece<D> {
+    data: D,
+    weight: usize,
+    atomic: bool,
+}
+
+///
+/// Data cut in any number of pieces of different costs: `splitter(data)` returns k pieces
+/// (the data alone if it cannot be cut, never nothing) and `cost` estimates the work of
+/// a piece.
+/// The length seen by the policies is the total cost, `divide_at` cuts the pieces where
+/// their cumulated cost reaches the index, splitting a single piece first.
+///
+pub struct WeightedSplit<D, S, C>
+where
+    D: Send,
+    S: Fn(D) -> Vec<D> + Sync + Send + Clone,
+    C: Fn(&D) -> usize + Clone + Send,
+{
+    pieces: Vec<Piece<D>>,
+    pub splitter: S,
+    pub cost: C,
+}
+
+impl<D, S, C> WeightedSplit<D, S, C>
+where
+    D: Send,
+    S: Fn(D) -> Vec<D> + Sync + Send + Clone,
+    C: Fn(&D) -> usize + Clone + Send,
+{
+    /// The remaining pieces, in order.
+    pub fn into_data(self) -> Vec<D> {
+        self.pieces.into_iter().map(|p| p.data).collect()
+    }
+
+    fn with_pieces(&self, pieces: Vec<Piece<D>>) -> Self {
+        WeightedSplit {
+            pieces,
+            splitter: self.splitter.clone(),
+            cost: self.cost.clone(),
+        }
+    }
+
+    /// Replace the piece at `index` by the pieces the splitter cuts it in.
+    /// They are atomic if the splitter did not really cut it: at most one piece with a
+    /// cost, or a piece as costly as the whole.
+    fn refine(&mut self, index: usize) {
+        if self.pieces[index].atomic {
+            return;
+        }
+        let Piece { data, weight, .. } = self.pieces.remove(index);
+        let mut parts: Vec<Piece<D>> = (self.splitter)(data)
+            .into_iter()
+            .map(|data| Piece {
+                weight: (self.cost)(&data),
+                data,
+                atomic: false,
+            })
+            .collect();
+        assert!(
+            !parts.is_empty(),
+            "split_weighted: the splitter must return the data it cannot cut"
+        );
+        let costly = parts.iter().filter(|p| p.weight > 0).count();
+        if costly <= 1 || parts.iter().any(|p| p.weight >= weight.max(1)) {
+            for part in &mut parts {
+                part.atomic = true;
+            }
+        }
+        self.pieces.splice(index..index, parts);
+    }
+}
+
+impl<D, S, C> Divisible for WeightedSplit<D, S, C>
+where
+    D: Send,
+    S: Fn(D) -> Vec<D> + Sync + Send + Clone,
+    C: Fn(&D) -> usize + Clone + Send,
+{
+    type Power = BasicPower;
+    fn base_length(&self) -> Option<usize> {
+        match self.pieces.as_slice() {
+            // cannot be divided: seen as a single unit of work
+            [piece] if piece.atomic => Some(1),
+            pieces => Some(pieces.iter().map(|p| p.weight).sum()),
+        }
+    }
+
+    fn divide_at(mut self, index: usize) -> (Self, Self) {
+        if self.pieces.len() == 1 {
+            self.refine(0);
+        }
+        let len = self.pieces.len();
+        if len < 2 {
+            let empty = self.with_pieces(Vec::new());
+            return (self, empty);
+        }
+        // the left part gets the pieces whose middle is before the index, at least one
+        let mut cut = 1;
+        let mut prefix = self.pieces[0].weight;
+        while cut < len - 1 && prefix + self.pieces[cut].weight / 2 < index {
+            prefix += self.pieces[cut].weight;
+            cut += 1;
+        }
+        let right = self.pieces.split_off(cut);
+        let right = self.with_pieces(right);
+        (self, right)
+    }
+}
+
+impl<D, S, C> ParallelIterator for WeightedSplit<D, S, C>
+where
+    D: Send,
+    S: Fn(D) -> Vec<D> + Sync + Send + Clone,
+    C: Fn(&D) -> usize + Clone + Send,
+{
+    type Item = D;
+
+    type SequentialIterator = vec::IntoIter<D>;
+
+    fn to_sequential(self) -> Self::SequentialIterator {
+        self.into_data().into_iter()
+    }
+
+    fn extract_iter(&mut self, size: usize) -> Self::SequentialIterator {
+        let size = size.max(1);
+        while self
+            .pieces
+            .first()
+            .map_or(false, |p| p.weight > size && !p.atomic)
+        {
+            self.refine(0);
+        }
+        let mut taken = 0;
+        let mut count = 0;
+        for piece in &self.pieces {
+            if count > 0 && taken + piece.weight > size {
+                break;
+            }
+            taken += piece.weight;
+            count += 1;
+        }
+        let prefix: Vec<D> = self.pieces.drain(..count).map(|p| p.data).collect();
+        prefix.into_iter()
+    }
+}
+
+///
+/// WeightedSplit of the data: the policies balance the estimated cost of the pieces.
+///
+pub fn split_weighted<D, S, C>(data: D, splitter: S, cost: C) -> WeightedSplit<D, S, C>
+where
+    D: Send,
+    S: Fn(D) -> Vec<D> + Sync + Send + Clone,
+    C: Fn(&D) -> usize + Clone + Send,
+{
+    let weight = cost(&data);
+    WeightedSplit {
+        pieces: vec![Piece {
+            data,
+            weight,
+            atomic: false,
+        }],
+        splitter,
+        cost,
+    }
+}
+
+#[cfg(test)]
+fn ranges(data: Range<usize>, policy: Policy) -> Vec<Range<usize>> {
+    split_at(
+        data,
+        |r: Range<usize>, index| {
+            let middle = (r.start + index).min(r.end);
+            (r.start..middle, middle..r.end)
+        },
+        |r| r.len(),
+    )
+    .with_policy(policy)
+    .collect()
+}
+
+#[test]
+fn test_split_at() {
+    for &policy in &[
+        Policy::Sequential,
+        Policy::Join(10),
+        Policy::Rayon(1),
+        Policy::Adaptive(7, 100),
+    ] {
+        let pieces = ranges(0..1000, policy);
+        // in order, without gaps or overlaps
+        let mut next = 0;
+        for piece in &pieces {
+            assert_eq!(piece.start, next, "{:?}", policy);
+            next = piece.end;
+        }
+        assert_eq!(next, 1000);
+    }
+    assert!(ranges(0..1000, Policy::Join(10)).iter().all(|r| r.len() <= 10));
+    // prefix extraction, down to nothing left
+    let mut s = split_at(
+        0..10,
+        |r: Range<usize>, i| (r.start..r.start + i, r.start + i..r.end),
+        |r| r.len(),
+    );
+    assert_eq!(s.extract_iter(4).collect::<Vec<_>>(), vec![0..4]);
+    assert_eq!(s.base_length(), Some(6));
+    assert_eq!(s.extract_iter(0).collect::<Vec<_>>(), vec![4..5]);
+    assert_eq!(s.extract_iter(100).collect::<Vec<_>>(), vec![5..10]);
+    assert_eq!(s.base_length(), Some(0));
+    assert_eq!(s.extract_iter(1).count(), 0);
+    assert!(s.data.is_none());
+}
+
+#[test]
+fn test_split_weighted() {
+    // blocks of different k: the cost of a block of rows is the sum of its k
+    let ks: Vec<usize> = vec![1, 1, 1, 1, 1, 1, 100, 1, 300, 2, 2, 50];
+    let rows = |policy| -> Vec<Vec<usize>> {
+        split_weighted(
+            ks.clone(),
+            |rows: Vec<usize>| {
+                // three-way split
+                let third = (rows.len() + 2) / 3;
+                rows.chunks(third.max(1)).map(|c| c.to_vec()).collect()
+            },
+            |rows| rows.iter().sum(),
+        )
+        .with_policy(policy)
+        .collect()
+    };
+    for &policy in &[
+        Policy::Sequential,
+        Policy::Join(60),
+        Policy::Rayon(1),
+        Policy::Adaptive(10, 100),
+    ] {
+        let pieces = rows(policy);
+        let flat: Vec<usize> = pieces.iter().flat_map(|p| p.iter().cloned()).collect();
+        assert_eq!(flat, ks, "{:?}", policy);
+    }
+    // each piece holds at most 60 of cost, or a single row
+    for piece in rows(Policy::Join(60)) {
+        assert!(piece.len() == 1 || piece.iter().sum::<usize>() <= 60, "{:?}", piece);
+    }
+    // divide_at balances the cost, not the number of rows
+    let costly = split_weighted(
+        vec![1usize, 1, 1, 9],
+        |v: Vec<usize>| v.into_iter().map(|x| vec![x]).collect(),
+        |v| v.iter().sum(),
+    );
+    assert_eq!(costly.base_length(), Some(12));
+    let (left, right) = costly.divide_at(6);
+    assert_eq!((left.into_data().len(), right.into_data().len()), (3, 1));
+    // single rows cannot be cut
+    let (left, right) = split_weighted(vec![5], |v: Vec<usize>| vec![v], |v| v[0]).divide();
+    assert_eq!((left.base_length(), right.base_length()), (Some(1), Some(0)));
+    // splitters which do not reduce the cost: the pieces are kept, atomic
+    for policy in &[Policy::Join(1), Policy::Adaptive(1, 4)] {
+        let whole_and_empty = split_weighted(
+            vec![3usize, 4],
+            |v: Vec<usize>| vec![v, Vec::new()],
+            |v| v.iter().sum(),
+        );
+        let flat: Vec<usize> = whole_and_empty
+            .with_policy(*policy)
+            .collect::<Vec<Vec<usize>>>()
+            .concat();
+        assert_eq!(flat, vec![3, 4]);
     }
+    let mut unbalanced = split_weighted(
+        vec![3usize, 4],
+        |v: Vec<usize>| v.into_iter().map(|x| vec![x, x]).collect(),
+        |v| v.iter().sum(),
+    );
+    assert_eq!(unbalanced.extract_iter(1).collect::<Vec<_>>(), vec![vec![3, 3]]);
 }
@@ -0,0 +1,189 @@
+///
+/// Summary statistics of benchmark samples.
+/// Quartiles are linearly interpolated between the closest ranks (as numpy's default),
+/// the standard deviation is the sample one (n - 1).
+///
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Summary {
+    pub samples: usize,
+    pub mean: f64,
+    pub stddev: f64,
+    pub min: f64,
+    pub q1: f64,
+    pub median: f64,
+    pub q3: f64,
+    pub max: f64,
+}
+
+impl Summary {
+    /// Panics without samples.
+    pub fn new(values: &[f64]) -> Self {
+        assert!(!values.is_empty(), "no samples to summarize");
+        let mut sorted = values.to_vec();
+        sorted.sort_by(|a, b| a.partial_cmp(b).expect("NaN sample"));
+        let n = sorted.len();
+        let mean = sorted.iter().sum::<f64>() / n as f64;
+        let variance = if n > 1 {
+            sorted.iter().map(|x| (x - mean) * (x - mean)).sum::<f64>() / (n - 1) as f64
+        } else {
+            0.0
+        };
+        Summary {
+            samples: n,
+            mean,
+            stddev: variance.sqrt(),
+            min: sorted[0],
+            q1: quantile(&sorted, 0.25),
+            median: quantile(&sorted, 0.5),
+            q3: quantile(&sorted, 0.75),
+            max: sorted[n - 1],
+        }
+    }
+}
+
+///
+/// q-quantile (q in [0, 1]) of sorted values.
+///
+pub fn quantile(sorted: &[f64], q: f64) -> f64 {
+    let position = q * (sorted.len() - 1) as f64;
+    let below = position.floor() as usize;
+    let above = position.ceil() as usize;
+    sorted[below] + (sorted[above] - sorted[below]) * (position - below as f64)
+}
+
+///
+/// Welch's unequal variances t-test between two sets of samples.
+///
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TTest {
+    pub t: f64,
+    pub degrees_of_freedom: f64,
+    /// Two-sided: probability of a difference of means at least this large if they are equal.
+    pub p_value: f64,
+}
+
+///
+/// None when a side has less than 2 samples or both have no variance.
+///
+pub fn welch_t_test(a: &Summary, b: &Summary) -> Option<TTest> {
+    if a.samples < 2 || b.samples < 2 {
+        return None;
+    }
+    let va = a.stddev * a.stddev / a.samples as f64;
+    let vb = b.stddev * b.stddev / b.samples as f64;
+    if va + vb == 0.0 {
+        return None;
+    }
+    let t = (a.mean - b.mean) / (va + vb).sqrt();
+    let degrees_of_freedom = (va + vb) * (va + vb)
+        / (va * va / (a.samples - 1) as f64 + vb * vb / (b.samples - 1) as f64);
+    let x = degrees_of_freedom / (degrees_of_freedom + t * t);
+    Some(TTest {
+        t,
+        degrees_of_freedom,
+        p_value: incomplete_beta(degrees_of_freedom / 2.0, 0.5, x),
+    })
+}
+
+/// ln(Gamma(x)) for x > 0 (Lanczos approximation).
+fn ln_gamma(x: f64) -> f64 {
+    const COEFFICIENTS: [f64; 6] = [
+        76.180_091_729_471_46,
+        -86.505_320_329_416_77,
+        24.014_098_240_830_91,
+        -1.231_739_572_450_155,
+        0.120_865_097_386_617_9e-2,
+        -0.539_523_938_495_3e-5,
+    ];
+    let tmp = x + 5.5;
+    let tmp = tmp - (x + 0.5) * tmp.ln();
+    let mut series = 1.000_000_000_190_015;
+    for (i, c) in COEFFICIENTS.iter().enumerate() {
+        series += c / (x + 1.0 + i as f64);
+    }
+    -tmp + (2.506_628_274_631_000_5 * series / x).ln()
+}
+
+/// Regularized incomplete beta function I_x(a, b).
+fn incomplete_beta(a: f64, b: f64, x: f64) -> f64 {
+    if x <= 0.0 {
+        return 0.0;
+    }
+    if x >= 1.0 {
+        return 1.0;
+    }
+    let front = (ln_gamma(a + b) - ln_gamma(a) - ln_gamma(b) + a * x.ln() + b * (1.0 - x).ln())
+        .exp();
+    // the continued fraction converges quickly for x < (a + 1) / (a + b + 2)
+    if x < (a + 1.0) / (a + b + 2.0) {
+        front * beta_fraction(a, b, x) / a
+    } else {
+        1.0 - front * beta_fraction(b, a, 1.0 - x) / b
+    }
+}
+
+/// Continued fraction of the incomplete beta function (modified Lentz's method).
+fn beta_fraction(a: f64, b: f64, x: f64) -> f64 {
+    const TINY: f64 = 1e-300;
+    let mut c = 1.0;
+    let mut d = 1.0 - (a + b) * x / (a + 1.0);
+    if d.abs() < TINY {
+        d = TINY;
+    }
+    d = 1.0 / d;
+    let mut h = d;
+    for m in 1..300 {
+        let m = m as f64;
+        // even step
+        let numerator = m * (b - m) * x / ((a + 2.0 * m - 1.0) * (a + 2.0 * m));
+        d = 1.0 + numerator * d;
+        d = if d.abs() < TINY { 1.0 / TINY } else { 1.0 / d };
+        c = 1.0 + numerator / c;
+        if c.abs() < TINY {
+            c = TINY;
+        }
+        h *= d * c;
+        // odd step
+        let numerator = -(a + m) * (a + b + m) * x / ((a + 2.0 * m) * (a + 2.0 * m + 1.0));
+        d = 1.0 + numerator * d;
+        d = if d.abs() < TINY { 1.0 / TINY } else { 1.0 / d };
+        c = 1.0 + numerator / c;
+        if c.abs() < TINY {
+            c = TINY;
+        }
+        let delta = d * c;
+        h *= delta;
+        if (delta - 1.0).abs() < 1e-14 {
+            break;
+        }
+    }
+    h
+}
+
+#[test]
+fn test_summary() {
+    let s = Summary::new(&[4.0, 1.0, 3.0, 2.0, 5.0]);
+    assert_eq!(s.samples, 5);
+    assert_eq!(s.mean, 3.0);
+    assert_eq!(s.median, 3.0);
+    assert_eq!((s.q1, s.q3), (2.0, 4.0));
+    assert_eq!((s.min, s.max), (1.0, 5.0));
+    assert!((s.stddev - 2.5f64.sqrt()).abs() < 1e-12);
+    let s = Summary::new(&[1.0, 2.0]);
+    assert_eq!((s.q1, s.median, s.q3), (1.25, 1.5, 1.75));
+    assert_eq!(Summary::new(&[7.0]).stddev, 0.0);
+}
+
+#[test]
+fn test_welch_t_test() {
+    // reference p-value: numerical integration of the Student t density
+    let a = Summary::new(&[19.8, 20.4, 19.6, 17.8, 18.5, 18.9, 18.3, 18.9, 19.5, 22.0]);
+    let b = Summary::new(&[28.2, 26.6, 20.1, 23.3, 25.2, 22.1, 17.7, 27.6, 20.6, 13.7]);
+    let test = welch_t_test(&a, &b).expect("enough samples");
+    assert!((test.t - -2.074_015).abs() < 1e-6);
+    assert!((test.degrees_of_freedom - 10.209_185).abs() < 1e-6);
+    assert!((test.p_value - 0.064_280).abs() < 1e-6);
+    let same = welch_t_test(&a, &a).expect("enough samples");
+    assert!((same.p_value - 1.0).abs() < 1e-12);
+    assert_eq!(welch_t_test(&Summary::new(&[1.0]), &a), None);
+}
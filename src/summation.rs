@@ -0,0 +1,199 @@
+use ndarray::LinalgScalar;
+use ndarray::{Array, ArrayView, Axis, Ix1};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+#[cfg(test)]
+use crate::{faster_vec, naive_sequential};
+
+/// Below this length pairwise summation falls back to a plain loop.
+const PAIRWISE_CHUNK: usize = 32;
+
+///
+/// How the partial products are accumulated along the k dimension.
+/// Naive: one running sum, the error grows linearly with k.
+/// Kahan: compensated summation, the error does not depend on k.
+/// Pairwise: recursive halving, the error grows with log2(k).
+/// For integer types every mode gives the same result.
+///
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Summation {
+    Naive,
+    Kahan,
+    Pairwise,
+}
+
+impl Default for Summation {
+    fn default() -> Self {
+        Summation::Naive
+    }
+}
+
+///
+/// Sum of a slice with the given summation mode.
+///
+pub fn sum<A>(values: &[A], mode: Summation) -> A
+where
+    A: LinalgScalar,
+{
+    match mode {
+        Summation::Naive => values.iter().fold(A::zero(), |acc, x| acc + *x),
+        Summation::Kahan => kahan(values.iter().cloned()),
+        Summation::Pairwise => pairwise(values),
+    }
+}
+
+///
+/// Scalar product of two vectors with the given summation mode.
+/// Works on strided views (rows and columns of a matrix).
+///
+pub fn dot<'a, 'b, A>(a: ArrayView<'a, A, Ix1>, b: ArrayView<'b, A, Ix1>, mode: Summation) -> A
+where
+    A: LinalgScalar,
+{
+    match mode {
+        Summation::Naive => a
+            .iter()
+            .zip(b.iter())
+            .fold(A::zero(), |acc, (x, y)| acc + *x * *y),
+        Summation::Kahan => kahan(a.iter().zip(b.iter()).map(|(x, y)| *x * *y)),
+        Summation::Pairwise => pairwise_dot(a, b),
+    }
+}
+
+fn kahan<A, I>(values: I) -> A
+where
+    A: LinalgScalar,
+    I: Iterator<Item = A>,
+{
+    let mut sum = A::zero();
+    let mut compensation = A::zero();
+    for x in values {
+        let y = x - compensation;
+        let t = sum + y;
+        compensation = (t - sum) - y;
+        sum = t;
+    }
+    sum
+}
+
+fn pairwise<A>(values: &[A]) -> A
+where
+    A: LinalgScalar,
+{
+    if values.len() <= PAIRWISE_CHUNK {
+        return values.iter().fold(A::zero(), |acc, x| acc + *x);
+    }
+    let (left, right) = values.split_at(values.len() / 2);
+    pairwise(left) + pairwise(right)
+}
+
+fn pairwise_dot<'a, 'b, A>(a: ArrayView<'a, A, Ix1>, b: ArrayView<'b, A, Ix1>) -> A
+where
+    A: LinalgScalar,
+{
+    let len = a.len();
+    if len <= PAIRWISE_CHUNK {
+        return a
+            .iter()
+            .zip(b.iter())
+            .fold(A::zero(), |acc, (x, y)| acc + *x * *y);
+    }
+    let (a1, a2) = a.split_at(Axis(0), len / 2);
+    let (b1, b2) = b.split_at(Axis(0), len / 2);
+    pairwise_dot(a1, b1) + pairwise_dot(a2, b2)
+}
+
+///
+/// Largest relative error of `values` against a f64 reference.
+///
+pub fn relative_error(values: &[f32], reference: &[f64]) -> f64 {
+    assert_eq!(values.len(), reference.len());
+    values
+        .iter()
+        .zip(reference.iter())
+        .map(|(v, r)| {
+            let scale = if *r == 0.0 { 1.0 } else { r.abs() };
+            (*v as f64 - *r).abs() / scale
+        })
+        .fold(0.0, f64::max)
+}
+
+///
+/// Relative error of a f32 scalar product of length k for each mode,
+/// measured against the same product computed in f64.
+/// The inputs are uniform in [0, 1) so the sum grows with k (the bad case for Naive).
+///
+pub fn dot_error(k: usize, seed: u64) -> Vec<(Summation, f64)> {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let a = Array::from_shape_fn(k, |_| rng.gen_range(0.0f32, 1.0));
+    let b = Array::from_shape_fn(k, |_| rng.gen_range(0.0f32, 1.0));
+    let reference: f64 = a
+        .iter()
+        .zip(b.iter())
+        .map(|(x, y)| *x as f64 * *y as f64)
+        .sum();
+    [Summation::Naive, Summation::Kahan, Summation::Pairwise]
+        .iter()
+        .map(|mode| {
+            let value = dot(a.view(), b.view(), *mode);
+            (*mode, relative_error(&[value], &[reference]))
+        })
+        .collect()
+}
+
+#[test]
+fn test_dot_error_large_k() {
+    let errors = dot_error(1 << 20, 42);
+    let naive = errors[0].1;
+    let kahan = errors[1].1;
+    let pairwise = errors[2].1;
+    assert!(kahan < naive);
+    assert!(pairwise < naive);
+    assert!(kahan < 1e-6);
+    assert!(pairwise < 1e-5);
+}
+
+#[test]
+fn test_integer_modes_agree() {
+    let values: Vec<i64> = (0..1000).map(|i| i * i - 300).collect();
+    let expected: i64 = values.iter().sum();
+    assert_eq!(sum(&values, Summation::Naive), expected);
+    assert_eq!(sum(&values, Summation::Kahan), expected);
+    assert_eq!(sum(&values, Summation::Pairwise), expected);
+}
+
+#[test]
+fn test_mult_with_summation() {
+    let height = 50;
+    let depth = 20_000;
+    let mut rng = StdRng::seed_from_u64(7);
+    let an = Array::from_shape_fn((height, depth), |_| rng.gen_range(0.0f32, 1.0));
+    let bn = Array::from_shape_fn((depth, height), |_| rng.gen_range(0.0f32, 1.0));
+    let mut verif = Array::<f64, _>::zeros((height, height));
+    for i in 0..height {
+        for j in 0..height {
+            verif[[i, j]] = an
+                .row(i)
+                .iter()
+                .zip(bn.column(j).iter())
+                .map(|(x, y)| *x as f64 * *y as f64)
+                .sum();
+        }
+    }
+    for mode in [Summation::Kahan, Summation::Pairwise].iter() {
+        let mut dest = Array::zeros((height, height));
+        naive_sequential::mult_with_summation(an.view(), bn.view(), dest.view_mut(), *mode);
+        let error = relative_error(dest.as_slice().unwrap(), verif.as_slice().unwrap());
+        assert!(error < 1e-5, "{:?}: relative error {}", mode, error);
+
+        let mut dest = Array::zeros((height, height));
+        faster_vec::mult_faster_from_ndarray_with_summation(
+            an.view(),
+            bn.view(),
+            &mut dest.view_mut(),
+            *mode,
+        );
+        let error = relative_error(dest.as_slice().unwrap(), verif.as_slice().unwrap());
+        assert!(error < 1e-5, "faster {:?}: relative error {}", mode, error);
+    }
+}
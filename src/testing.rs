@@ -0,0 +1,566 @@
+//! Differential testing of the multiplication kernels.
+//! Every kernel runs on randomized cases (awkward shapes: 0, 1, primes, non powers of two;
+//! row-major, padded, transposed and strided operands and outputs) and its result is
+//! compared to a f64 reference. Each case comes from a seed, printed on failure:
+//! MATRIX_MULT_SEED=<seed> replays that case alone.
+
+use crate::{
+    cache_oblivious, dispatch, faster_vec, matrix, matrix_adaptive, my_ndarray, naive_sequential,
+    rayon_mult, reproducible, vectorisation, vectorisation_packed_simd,
+};
+use crate::faster_vec::FasterScalar;
+use ndarray::{linalg, s, Array, ArrayView, ArrayViewMut, Ix2};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use rayon_adaptive::prelude::*;
+use rayon_adaptive::Policy;
+use std::fmt;
+use std::ops::AddAssign;
+use std::panic::{self, AssertUnwindSafe};
+
+/// Sizes that break kernels assuming powers of two, vector widths or non-empty matrices.
+pub const AWKWARD_SIZES: [usize; 15] = [0, 1, 2, 3, 5, 7, 13, 17, 31, 32, 33, 64, 97, 128, 129];
+/// Largest dimension of the other random shapes.
+const MAX_DIMENSION: usize = 100;
+
+/// How an operand is stored.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Layout {
+    /// Contiguous rows.
+    RowMajor,
+    /// Rows inside a wider matrix (row stride larger than the number of columns).
+    Padded,
+    /// Column-major: the transposed view of a row-major matrix.
+    Transposed,
+    /// Every other row and column of a larger matrix.
+    Strided,
+}
+
+const LAYOUTS: [Layout; 4] = [
+    Layout::RowMajor,
+    Layout::Padded,
+    Layout::Transposed,
+    Layout::Strided,
+];
+
+impl Layout {
+    /// Each row is contiguous in memory.
+    pub fn unit_column_stride(self) -> bool {
+        self == Layout::RowMajor || self == Layout::Padded
+    }
+}
+
+///
+/// One multiplication d += a * b with a (m x k), b (k x n) and d (m x n).
+///
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Case {
+    pub seed: u64,
+    pub m: usize,
+    pub n: usize,
+    pub k: usize,
+    pub a: Layout,
+    pub b: Layout,
+    pub d: Layout,
+}
+
+impl Case {
+    /// The case of a seed: each dimension is awkward half of the time.
+    pub fn random(seed: u64) -> Self {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let dimension = |rng: &mut StdRng| {
+            if rng.gen() {
+                AWKWARD_SIZES[rng.gen_range(0, AWKWARD_SIZES.len())]
+            } else {
+                rng.gen_range(0, MAX_DIMENSION + 1)
+            }
+        };
+        let (m, n, k) = (dimension(&mut rng), dimension(&mut rng), dimension(&mut rng));
+        let layout = |rng: &mut StdRng| LAYOUTS[rng.gen_range(0, LAYOUTS.len())];
+        Case {
+            seed,
+            m,
+            n,
+            k,
+            a: layout(&mut rng),
+            b: layout(&mut rng),
+            d: layout(&mut rng),
+        }
+    }
+
+    /// a, b and d all have contiguous rows.
+    pub fn unit_column_strides(&self) -> bool {
+        self.a.unit_column_stride() && self.b.unit_column_stride() && self.d.unit_column_stride()
+    }
+}
+
+///
+/// Element types of the differential tests: random values small enough for
+/// integer products to stay exact.
+///
+pub trait Element: FasterScalar + AddAssign + fmt::Debug {
+    /// Floating point types are compared with a tolerance, integers exactly.
+    const EPSILON: f64;
+    fn random(rng: &mut StdRng) -> Self;
+    fn to_f64(self) -> f64;
+}
+
+impl Element for f32 {
+    const EPSILON: f64 = std::f32::EPSILON as f64;
+    fn random(rng: &mut StdRng) -> Self {
+        rng.gen_range(-1.0, 1.0)
+    }
+    fn to_f64(self) -> f64 {
+        f64::from(self)
+    }
+}
+
+impl Element for f64 {
+    const EPSILON: f64 = std::f64::EPSILON;
+    fn random(rng: &mut StdRng) -> Self {
+        rng.gen_range(-1.0, 1.0)
+    }
+    fn to_f64(self) -> f64 {
+        self
+    }
+}
+
+impl Element for i32 {
+    const EPSILON: f64 = 0.0;
+    fn random(rng: &mut StdRng) -> Self {
+        rng.gen_range(-8, 9)
+    }
+    fn to_f64(self) -> f64 {
+        f64::from(self)
+    }
+}
+
+impl Element for u32 {
+    const EPSILON: f64 = 0.0;
+    fn random(rng: &mut StdRng) -> Self {
+        rng.gen_range(0, 9)
+    }
+    fn to_f64(self) -> f64 {
+        f64::from(self)
+    }
+}
+
+/// Storage of an operand of the given layout and its (rows x cols) view.
+struct Operand<A> {
+    storage: Array<A, Ix2>,
+    layout: Layout,
+    cols: usize,
+}
+
+impl<A: Element> Operand<A> {
+    fn new<F: FnMut() -> A>(rows: usize, cols: usize, layout: Layout, mut value: F) -> Self {
+        let shape = match layout {
+            Layout::RowMajor => (rows, cols),
+            Layout::Padded => (rows, cols + 3),
+            Layout::Transposed => (cols, rows),
+            Layout::Strided => (2 * rows, 2 * cols),
+        };
+        Operand {
+            storage: Array::from_shape_fn(shape, |_| value()),
+            layout,
+            cols,
+        }
+    }
+
+    fn view(&self) -> ArrayView<'_, A, Ix2> {
+        match self.layout {
+            Layout::RowMajor => self.storage.view(),
+            Layout::Padded => self.storage.slice(s![.., ..self.cols]),
+            Layout::Transposed => self.storage.t(),
+            Layout::Strided => self.storage.slice(s![..;2, ..;2]),
+        }
+    }
+
+    fn view_mut(&mut self) -> ArrayViewMut<'_, A, Ix2> {
+        match self.layout {
+            Layout::RowMajor => self.storage.view_mut(),
+            Layout::Padded => {
+                let cols = self.cols;
+                self.storage.slice_mut(s![.., ..cols])
+            }
+            Layout::Transposed => self.storage.view_mut().reversed_axes(),
+            Layout::Strided => self.storage.slice_mut(s![..;2, ..;2]),
+        }
+    }
+}
+
+///
+/// A kernel under test, computing d += a * b.
+///
+pub struct Kernel<A> {
+    pub name: &'static str,
+    /// False for the cases (layouts, shapes) the kernel is not meant to handle.
+    pub supports: fn(&Case) -> bool,
+    pub run: fn(ArrayView<A, Ix2>, ArrayView<A, Ix2>, ArrayViewMut<A, Ix2>),
+}
+
+fn any_case(_: &Case) -> bool {
+    true
+}
+
+fn row_major(case: &Case) -> bool {
+    case.unit_column_strides()
+}
+
+fn adaptive<A: Element>(
+    a: ArrayView<A, Ix2>,
+    b: ArrayView<A, Ix2>,
+    mut d: ArrayViewMut<A, Ix2>,
+    policy: Policy,
+) {
+    let dsize = d.dim();
+    let m = matrix_adaptive::Matrix {
+        a,
+        b,
+        d: d.view_mut(),
+        asize: a.dim(),
+        bsize: b.dim(),
+        dsize,
+    };
+    m.with_policy(policy).for_each(|mut e| {
+        linalg::general_mat_mul(A::one(), &e.a, &e.b, A::one(), &mut e.d);
+    });
+}
+
+fn add_products<A: Element>(e: matrix::Matrix<'_, '_, '_, A>) {
+    for (vect, mut output) in e.matrix {
+        for (a, b) in vect {
+            linalg::general_mat_mul(A::one(), &a, &b, A::one(), &mut output);
+        }
+    }
+}
+
+fn cut<A: Element>(
+    a: ArrayView<A, Ix2>,
+    b: ArrayView<A, Ix2>,
+    d: ArrayViewMut<A, Ix2>,
+    policy: Policy,
+) {
+    let m = matrix::Matrix {
+        matrix: vec![([(a, b)].to_vec(), d)],
+    };
+    m.cut().with_policy(policy).for_each(add_products);
+}
+
+fn leaves<A: Element>(
+    a: ArrayView<A, Ix2>,
+    b: ArrayView<A, Ix2>,
+    d: ArrayViewMut<A, Ix2>,
+    policy: Policy,
+) {
+    let m = matrix::Matrix {
+        matrix: vec![([(a, b)].to_vec(), d)],
+    };
+    m.with_policy(policy).for_each(add_products);
+}
+
+/// The kernels generic over the element type.
+pub fn generic_kernels<A: Element>() -> Vec<Kernel<A>> {
+    vec![
+        Kernel {
+            name: "ndarray::general_mat_mul",
+            supports: any_case,
+            run: |a, b, mut d| linalg::general_mat_mul(A::one(), &a, &b, A::one(), &mut d),
+        },
+        Kernel {
+            name: "naive_sequential::mult",
+            supports: any_case,
+            run: naive_sequential::mult,
+        },
+        Kernel {
+            name: "naive_sequential::mult_jik",
+            supports: any_case,
+            run: naive_sequential::mult_jik,
+        },
+        Kernel {
+            name: "naive_sequential::mult_index_optimized",
+            supports: any_case,
+            run: naive_sequential::mult_index_optimized,
+        },
+        Kernel {
+            name: "naive_sequential::mult_blocks",
+            supports: any_case,
+            run: |a, b, d| {
+                let (a, b, d) = naive_sequential::cut_in_blocks_with(a, b, d, 16, 24, 8);
+                naive_sequential::mult_blocks(a, b, d, naive_sequential::mult_index_optimized)
+            },
+        },
+        Kernel {
+            name: "naive_sequential::mult_blocks_par",
+            supports: any_case,
+            run: |a, b, d| {
+                let (a, b, d) = naive_sequential::cut_in_blocks_with(a, b, d, 8, 16, 24);
+                naive_sequential::mult_blocks_par(a, b, d, naive_sequential::mult_index_optimized)
+            },
+        },
+        Kernel {
+            name: "cache_oblivious::mult",
+            supports: any_case,
+            run: |a, b, d| {
+                cache_oblivious::mult(a, b, d);
+            },
+        },
+        Kernel {
+            name: "my_ndarray::mult",
+            supports: any_case,
+            run: |a, b, d| {
+                my_ndarray::mult(a, b, d);
+            },
+        },
+        Kernel {
+            name: "reproducible::mult_reproducible",
+            supports: any_case,
+            run: |a, b, d| reproducible::mult_reproducible(a, b, d, Policy::Join(64)),
+        },
+        Kernel {
+            name: "matrix_adaptive sequential",
+            supports: any_case,
+            run: |a, b, d| adaptive(a, b, d, Policy::Sequential),
+        },
+        Kernel {
+            name: "matrix_adaptive join",
+            supports: any_case,
+            run: |a, b, d| adaptive(a, b, d, Policy::Join(64)),
+        },
+        Kernel {
+            name: "matrix_adaptive rayon",
+            supports: any_case,
+            run: |a, b, d| adaptive(a, b, d, Policy::Rayon(1)),
+        },
+        Kernel {
+            name: "matrix_adaptive adaptive",
+            supports: any_case,
+            run: |a, b, d| adaptive(a, b, d, Policy::Adaptive(8, 256)),
+        },
+        Kernel {
+            name: "matrix cut join",
+            supports: any_case,
+            run: |a, b, d| cut(a, b, d, Policy::Join(64)),
+        },
+        Kernel {
+            name: "matrix adaptive",
+            supports: any_case,
+            run: |a, b, d| leaves(a, b, d, Policy::Adaptive(8, 256)),
+        },
+        Kernel {
+            name: "faster_vec::mult_faster_from_ndarray",
+            supports: row_major,
+            run: |a, b, mut d| faster_vec::mult_faster_from_ndarray(a, b, &mut d),
+        },
+        Kernel {
+            name: "my_ndarray::mult_nd_faster",
+            supports: row_major,
+            run: |a, b, d| {
+                my_ndarray::mult_nd_faster(a, b, d);
+            },
+        },
+    ]
+}
+
+/// The generic kernels and the f32 only ones (packed_simd, dispatch, slices).
+pub fn f32_kernels() -> Vec<Kernel<f32>> {
+    let mut kernels = generic_kernels();
+    kernels.extend(vec![
+        Kernel {
+            name: "vectorisation::mult_faster_from_ndarray",
+            supports: row_major,
+            run: |a, b, mut d| vectorisation::mult_faster_from_ndarray(a, b, &mut d),
+        },
+        Kernel {
+            name: "vectorisation_packed_simd::mult_faster_from_ndarray",
+            supports: row_major,
+            run: |a, b, mut d| vectorisation_packed_simd::mult_faster_from_ndarray(a, b, &mut d),
+        },
+        Kernel {
+            name: "dispatch::mult_packed_simd",
+            supports: row_major,
+            run: |a, b, mut d| dispatch::mult_packed_simd(a, b, &mut d),
+        },
+        Kernel {
+            name: "dispatch::mult_faster",
+            supports: row_major,
+            run: |a, b, mut d| dispatch::mult_faster(a, b, &mut d),
+        },
+        Kernel {
+            name: "rayon_mult::seq_matmul",
+            // square powers of two, contiguous
+            supports: |case| {
+                case.m == case.n
+                    && case.n == case.k
+                    && case.n.is_power_of_two()
+                    && (case.a, case.b, case.d)
+                        == (Layout::RowMajor, Layout::RowMajor, Layout::RowMajor)
+            },
+            run: |a, b, mut d| {
+                let mut product = vec![0f32; d.len()];
+                rayon_mult::seq_matmul(
+                    a.as_slice().unwrap(),
+                    b.as_slice().unwrap(),
+                    &mut product,
+                );
+                for (x, p) in d.iter_mut().zip(product) {
+                    *x += p;
+                }
+            },
+        },
+    ]);
+    kernels
+}
+
+///
+/// A kernel giving a wrong result (or panicking) on a case.
+///
+#[derive(Clone, Debug)]
+pub struct Failure {
+    pub kernel: &'static str,
+    pub case: Case,
+    pub message: String,
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} on {:?}: {} (replay with MATRIX_MULT_SEED={})",
+            self.kernel, self.case, self.message, self.case.seed
+        )
+    }
+}
+
+///
+/// Run a kernel on a case, d starting with random values, and compare d to
+/// the f64 reference: exactly for integers, for floating point types within
+/// a bound of the accumulated rounding errors (k * epsilon * sum |a_ik * b_kj|).
+///
+pub fn check<A: Element>(kernel: &Kernel<A>, case: &Case) -> Result<(), Failure> {
+    let mut rng = StdRng::seed_from_u64(case.seed);
+    let (m, n, k) = (case.m, case.n, case.k);
+    let a = Operand::new(m, k, case.a, || A::random(&mut rng));
+    let b = Operand::new(k, n, case.b, || A::random(&mut rng));
+    let mut d = Operand::new(m, n, case.d, || A::random(&mut rng));
+    let initial = d.view().to_owned();
+    let fail = |message: String| Failure {
+        kernel: kernel.name,
+        case: *case,
+        message,
+    };
+    let run = kernel.run;
+    panic::catch_unwind(AssertUnwindSafe(|| run(a.view(), b.view(), d.view_mut())))
+        .map_err(|cause| {
+            let message = cause
+                .downcast_ref::<String>()
+                .cloned()
+                .or_else(|| cause.downcast_ref::<&str>().map(|s| s.to_string()))
+                .unwrap_or_default();
+            fail(format!("panicked: {}", message))
+        })?;
+    let (a, b, d) = (a.view(), b.view(), d.view());
+    for i in 0..m {
+        for j in 0..n {
+            let mut exact = initial[[i, j]].to_f64();
+            let mut magnitude = exact.abs();
+            for p in 0..k {
+                let product = a[[i, p]].to_f64() * b[[p, j]].to_f64();
+                exact += product;
+                magnitude += product.abs();
+            }
+            let error = (d[[i, j]].to_f64() - exact).abs();
+            if error > 2.0 * (k + 1) as f64 * A::EPSILON * magnitude {
+                return Err(fail(format!(
+                    "d[{}, {}] = {:?} instead of {}",
+                    i,
+                    j,
+                    d[[i, j]],
+                    exact
+                )));
+            }
+        }
+    }
+    Ok(())
+}
+
+///
+/// The seeds to test: `count` seeds from `first`, or only MATRIX_MULT_SEED if it is set.
+///
+pub fn seeds(first: u64, count: usize) -> Vec<u64> {
+    match std::env::var("MATRIX_MULT_SEED").ok().and_then(|s| s.parse().ok()) {
+        Some(seed) => vec![seed],
+        None => (first..first + count as u64).collect(),
+    }
+}
+
+/// Every supported (kernel, case) pair that fails.
+pub fn check_all<A: Element>(kernels: &[Kernel<A>], seeds: &[u64]) -> Vec<Failure> {
+    let mut failures = Vec::new();
+    for &seed in seeds {
+        let case = Case::random(seed);
+        for kernel in kernels.iter().filter(|kernel| (kernel.supports)(&case)) {
+            if let Err(failure) = check(kernel, &case) {
+                failures.push(failure);
+            }
+        }
+    }
+    failures
+}
+
+/// Panic listing the failures, if any.
+pub fn assert_no_failures(failures: &[Failure]) {
+    if !failures.is_empty() {
+        let lines: Vec<String> = failures.iter().map(|f| f.to_string()).collect();
+        panic!("{} failures:\n{}", failures.len(), lines.join("\n"));
+    }
+}
+
+#[test]
+fn test_differential_f32() {
+    // f32_kernels include the dispatched ones
+    let _lock = dispatch::test_lock();
+    assert_no_failures(&check_all(&f32_kernels(), &seeds(0, 200)));
+}
+
+#[test]
+fn test_differential_f64() {
+    assert_no_failures(&check_all(&generic_kernels::<f64>(), &seeds(1000, 100)));
+}
+
+#[test]
+fn test_differential_integers() {
+    assert_no_failures(&check_all(&generic_kernels::<i32>(), &seeds(2000, 100)));
+    assert_no_failures(&check_all(&generic_kernels::<u32>(), &seeds(3000, 100)));
+}
+
+#[test]
+fn test_check_reports_wrong_kernels() {
+    let wrong = Kernel::<f32> {
+        name: "wrong",
+        supports: any_case,
+        run: |a, b, mut d| {
+            linalg::general_mat_mul(1.0, &a, &b, 1.0, &mut d);
+            if let Some(x) = d.iter_mut().next() {
+                *x += 0.5;
+            }
+        },
+    };
+    let case = Case {
+        seed: 5,
+        m: 3,
+        n: 4,
+        k: 5,
+        a: Layout::Strided,
+        b: Layout::Transposed,
+        d: Layout::Padded,
+    };
+    let failure = check(&wrong, &case).unwrap_err();
+    assert!(failure.to_string().contains("MATRIX_MULT_SEED=5"));
+    let panicking = Kernel::<f32> {
+        name: "panicking",
+        supports: any_case,
+        run: |_, _, _| panic!("boom"),
+    };
+    assert!(check(&panicking, &case).unwrap_err().message.contains("boom"));
+}
@@ -0,0 +1,274 @@
+//! Built-in task tracer: records when each divide and leaf task of the parallel
+//! multiplications starts and ends, on which thread.
+//! Off by default, a disabled span costs one atomic load.
+//! During `record` every span of every thread is recorded (one trace at a time) in a buffer
+//! of its thread, the buffers are merged when the recording stops.
+//! The trace exports to the Chrome trace event format (chrome://tracing, Perfetto).
+
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Instant;
+#[cfg(test)]
+use crate::executor::ScopedThreads;
+#[cfg(test)]
+use crate::{matrix, matrix_adaptive, my_ndarray};
+#[cfg(test)]
+use std::collections::HashSet;
+#[cfg(test)]
+use ndarray::{linalg, Array};
+#[cfg(test)]
+use rayon_adaptive::prelude::*;
+#[cfg(test)]
+use rayon_adaptive::Policy;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+/// The buffers of all the threads which recorded a span, until they exit.
+static BUFFERS: Mutex<Vec<Arc<Mutex<Vec<Event>>>>> = Mutex::new(Vec::new());
+static NEXT_THREAD: AtomicUsize = AtomicUsize::new(1);
+/// Held by `record`: one trace at a time.
+static RECORDING: Mutex<()> = Mutex::new(());
+
+/// The events of one thread: only this thread pushes, only `start` and `stop` read.
+struct Local {
+    thread: usize,
+    buffer: Arc<Mutex<Vec<Event>>>,
+}
+
+thread_local! {
+    static LOCAL: Local = {
+        let buffer = Arc::new(Mutex::new(Vec::new()));
+        lock(&BUFFERS).push(buffer.clone());
+        Local {
+            thread: NEXT_THREAD.fetch_add(1, Ordering::Relaxed),
+            buffer,
+        }
+    };
+}
+
+/// One task: `category` is the module, `name` the kind of task ("divide", "leaf").
+#[derive(Clone, Debug)]
+pub struct Event {
+    pub category: &'static str,
+    pub name: &'static str,
+    /// Number of the OS thread, from 1 in the order of their first span.
+    pub thread: usize,
+    pub start: Instant,
+    pub end: Instant,
+}
+
+/// The events recorded by `record`, sorted by start time.
+pub struct Trace {
+    pub origin: Instant,
+    pub events: Vec<Event>,
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Start recording, dropping the events of a previous trace.
+fn start() {
+    for buffer in lock(&BUFFERS).iter() {
+        lock(buffer).clear();
+    }
+    ENABLED.store(true, Ordering::SeqCst);
+}
+
+/// Stop recording and return the events of all threads since `start`.
+fn stop(origin: Instant) -> Trace {
+    ENABLED.store(false, Ordering::SeqCst);
+    let mut buffers = lock(&BUFFERS);
+    let mut events = Vec::new();
+    for buffer in buffers.iter() {
+        events.append(&mut lock(buffer));
+    }
+    // the threads which exited hold no reference anymore
+    buffers.retain(|buffer| Arc::strong_count(buffer) > 1);
+    events.sort_by_key(|e| e.start);
+    Trace { origin, events }
+}
+
+/// Run `op` while recording the spans of all threads.
+/// Concurrent calls wait for each other, `op` must not call record.
+pub fn record<R, F: FnOnce() -> R>(op: F) -> (R, Trace) {
+    let _recording = lock(&RECORDING);
+    let origin = Instant::now();
+    start();
+    let result = op();
+    (result, stop(origin))
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+///
+/// Records the time from its creation to its drop, if tracing was enabled at creation.
+///
+pub struct Span {
+    started: Option<(&'static str, &'static str, Instant)>,
+}
+
+pub fn span(category: &'static str, name: &'static str) -> Span {
+    Span {
+        started: if is_enabled() {
+            Some((category, name, Instant::now()))
+        } else {
+            None
+        },
+    }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        if let Some((category, name, start)) = self.started.take() {
+            let end = Instant::now();
+            // nothing to record into once the thread locals are destroyed
+            let _ = LOCAL.try_with(|local| {
+                lock(&local.buffer).push(Event {
+                    category,
+                    name,
+                    thread: local.thread,
+                    start,
+                    end,
+                })
+            });
+        }
+    }
+}
+
+///
+/// An iterator traced as one span, from its creation until it is dropped.
+/// Used as the sequential iterator of the rayon_adaptive matrices,
+/// so the span covers the processing of the leaf.
+///
+pub struct Traced<I> {
+    iter: I,
+    _span: Span,
+}
+
+impl<I> Traced<I> {
+    pub fn new(iter: I, category: &'static str, name: &'static str) -> Self {
+        Traced {
+            iter,
+            _span: span(category, name),
+        }
+    }
+}
+
+impl<I: Iterator> Iterator for Traced<I> {
+    type Item = I::Item;
+
+    fn next(&mut self) -> Option<I::Item> {
+        self.iter.next()
+    }
+}
+
+fn micros(origin: Instant, instant: Instant) -> f64 {
+    let dur = instant.saturating_duration_since(origin);
+    dur.as_secs() as f64 * 1e6 + f64::from(dur.subsec_nanos()) / 1e3
+}
+
+impl Trace {
+    /// Number of events of the given kind.
+    pub fn count(&self, category: &str, name: &str) -> usize {
+        self.events
+            .iter()
+            .filter(|e| e.category == category && e.name == name)
+            .count()
+    }
+
+    /// Chrome trace event JSON: one complete ("X") event per task, times in microseconds.
+    pub fn write_chrome_json<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        writeln!(writer, "{{\"traceEvents\": [")?;
+        for (index, e) in self.events.iter().enumerate() {
+            let separator = if index + 1 < self.events.len() { "," } else { "" };
+            let start = micros(self.origin, e.start);
+            writeln!(
+                writer,
+                "  {{\"name\": \"{}\", \"cat\": \"{}\", \"ph\": \"X\", \"ts\": {:.3}, \"dur\": {:.3}, \"pid\": 1, \"tid\": {}}}{}",
+                e.name,
+                e.category,
+                start,
+                micros(self.origin, e.end) - start,
+                e.thread,
+                separator
+            )?;
+        }
+        writeln!(writer, "], \"displayTimeUnit\": \"ns\"}}")?;
+        writer.flush()
+    }
+}
+
+#[test]
+fn test_trace() {
+    let size = 256;
+    let an = Array::from_shape_fn((size, size), |(i, j)| ((i + j) % 3) as f32);
+    let bn = Array::from_shape_fn((size, size), |(i, j)| ((i * j) % 5) as f32);
+    let mut verif = Array::zeros((size, size));
+    linalg::general_mat_mul(1.0, &an, &bn, 1.0, &mut verif);
+    let mut d1 = Array::zeros((size, size));
+    let mut d2 = Array::zeros((size, size));
+    let mut d3 = Array::zeros((size, size));
+    let ((), trace) = record(|| {
+        my_ndarray::mult(an.view(), bn.view(), d1.view_mut());
+        let mat = matrix_adaptive::Matrix {
+            a: an.view(),
+            b: bn.view(),
+            d: d2.view_mut(),
+            asize: an.dim(),
+            bsize: bn.dim(),
+            dsize: (size, size),
+        };
+        mat.with_policy(Policy::Join(size * size / 16)).for_each(|mut e| {
+            linalg::general_mat_mul(1.0, &e.a, &e.b, 1.0, &mut e.d);
+        });
+        let mat = matrix::Matrix {
+            matrix: vec![([(an.view(), bn.view())].to_vec(), d3.view_mut())],
+        };
+        mat.with_policy(Policy::Join(size * size / 16)).for_each(|e| {
+            for (vect, mut output) in e.matrix {
+                for (a, b) in vect {
+                    linalg::general_mat_mul(1.0, &a, &b, 1.0, &mut output);
+                }
+            }
+        });
+    });
+    assert_eq!((d1 == verif, d2 == verif, d3 == verif), (true, true, true));
+    // 256 x 256 down to 32 x 32 leaves: 8 * 8 * 8 leaves and 1 + 8 + 64 divisions
+    assert!(trace.count("my_ndarray", "leaf") >= 512);
+    assert!(trace.count("my_ndarray", "divide") >= 73);
+    assert!(trace.count("matrix_adaptive", "leaf") >= 16);
+    assert!(trace.count("matrix_adaptive", "divide") >= 15);
+    assert!(trace.count("matrix", "leaf") >= 16);
+    assert!(trace.count("matrix", "divide") >= 1);
+    assert!(trace.events.iter().all(|e| e.start <= e.end));
+    let mut json = Vec::new();
+    trace.write_chrome_json(&mut json).expect("export failed");
+    let json = String::from_utf8(json).unwrap();
+    assert!(json.starts_with("{\"traceEvents\": ["));
+    assert_eq!(json.matches("\"ph\": \"X\"").count(), trace.events.len());
+    // disabled: nothing recorded
+    let _ = span("test", "ignored");
+    assert!(!is_enabled());
+}
+
+#[test]
+fn test_trace_threads() {
+    let size = 128;
+    let an = Array::from_shape_fn((size, size), |(i, j)| ((i + j) % 3) as f32);
+    let mut d = Array::zeros((size, size));
+    let ((), trace) = record(|| {
+        my_ndarray::mult_with(an.view(), an.view(), d.view_mut(), &ScopedThreads::new(4));
+    });
+    // the threads spawned by the executor get their own tracks
+    let threads: HashSet<usize> = trace
+        .events
+        .iter()
+        .filter(|e| e.category == "my_ndarray")
+        .map(|e| e.thread)
+        .collect();
+    assert!(threads.len() > 1, "{:?}", threads);
+    assert!(!threads.contains(&0));
+}
@@ -9,6 +9,7 @@ use ndarray::{linalg,Array};
 #[cfg(test)]
 use rand::Rng;
 
+#[inline(always)]
 pub fn multiply_add<F>(
     into: &mut [f32],
     a: &[f32],
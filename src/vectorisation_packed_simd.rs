@@ -10,6 +10,7 @@ use ndarray::{linalg,Array};
 #[cfg(test)]
 use rand::Rng;
 
+#[inline(always)]
 fn multiply_add_packed_sim(  mut into: &mut [f32],
     a: f32,
     b: &[f32],
@@ -38,6 +39,7 @@ fn multiply_add_packed_sim(  mut into: &mut [f32],
     });
 }
 
+#[inline(always)]
 pub fn mult_faster_from_ndarray(a: ArrayView<f32,Ix2> ,b: ArrayView<f32,Ix2>,output: &mut ArrayViewMut<f32,Ix2>) {
     let (raw_ptr_a, len_a) = my_ndarray::view_ptr(a);
     let stridesa = a.strides();